@@ -0,0 +1,222 @@
+//! Hashes of fixed size blocks of the sources, used by incremental backups
+//!
+//! Blocks are aligned to offsets in the source rather than in the fragment,
+//! so the hashes of different fragments and generations can be compared
+//! block by block. The first and last block of a fragment may be partial.
+
+use std::io::{Result as IoResult, Write};
+
+use anyhow::{ensure, Result};
+use sha3::Digest;
+
+use crate::copy::encode_hash;
+use crate::index::{BlockHashes, Fragment, Offset, Slice};
+use crate::util::parse_size;
+
+/// Block size used when neither the command line nor earlier generations name one
+pub const DEFAULT_BLOCK_SIZE: Offset = 16 << 20;
+
+/// Smallest block size accepted; smaller ones make the list of hashes larger than the data
+pub const MIN_BLOCK_SIZE: Offset = 4 << 10;
+
+/// Largest block size accepted; incremental backups hold an entire block in memory
+pub const MAX_BLOCK_SIZE: Offset = 1 << 30;
+
+/// Parse a block size from the command line
+pub fn parse_block_size(s: &str) -> Result<Offset> {
+    let size = parse_size(s)?;
+    ensure!(
+        (MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&size),
+        "The block size must be between {MIN_BLOCK_SIZE} and {MAX_BLOCK_SIZE} bytes."
+    );
+    Ok(size)
+}
+
+pub fn hash_block(data: &[u8]) -> String {
+    encode_hash(sha3::Sha3_256::digest(data))
+}
+
+/// Passes data through to `inner` and records the hashes of the blocks written
+pub struct BlockWriter<W> {
+    inner: W,
+    size: Offset,
+    /// Offset in the source of the next byte
+    pos: Offset,
+    hasher: sha3::Sha3_256,
+    /// Whether the hasher holds data of an unfinished block
+    pending: bool,
+    hashes: Vec<String>,
+}
+
+impl<W: Write> BlockWriter<W> {
+    /// `start` is the offset in the source of the first byte written
+    pub fn new(inner: W, size: Offset, start: Offset) -> Self {
+        Self {
+            inner,
+            size,
+            pos: start,
+            hasher: Default::default(),
+            pending: false,
+            hashes: vec![],
+        }
+    }
+
+    fn feed(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let room = self.size - self.pos % self.size;
+            let (now, rest) = data.split_at(data.len().min(room as usize));
+            self.hasher.update(now);
+            self.pending = true;
+            self.pos += now.len() as Offset;
+            if self.pos.is_multiple_of(self.size) {
+                self.hashes
+                    .push(encode_hash(std::mem::take(&mut self.hasher).finalize()));
+                self.pending = false;
+            }
+            data = rest;
+        }
+    }
+
+    pub fn finish(mut self) -> BlockHashes {
+        if self.pending {
+            self.hashes.push(encode_hash(self.hasher.finalize()));
+        }
+        BlockHashes {
+            size: self.size,
+            sha3_256: self.hashes,
+        }
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        let written = self.inner.write(buf)?;
+        self.feed(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.inner.flush()
+    }
+}
+
+/// Block size recorded by the fragments, if they agree on a usable one
+pub fn recorded_size(frags: &[&Fragment]) -> Option<Offset> {
+    let mut sizes = frags
+        .iter()
+        .filter_map(|f| f.blocks.as_ref().map(|b| b.size));
+    let size = sizes.next()?;
+    ((1..=MAX_BLOCK_SIZE).contains(&size) && sizes.all(|s| s == size)).then_some(size)
+}
+
+/// Hash of `block` as recorded by the newest generation among `frags`
+///
+/// `None` if that generation does not hold the entire block in a single
+/// fragment with block hashes of the given size.
+pub fn recorded_hash<'a>(frags: &[&'a Fragment], block: Slice, size: Offset) -> Option<&'a str> {
+    let overlaps = |s: &Slice| s.start < block.end && block.start < s.end;
+    let newest = frags
+        .iter()
+        .filter(|f| overlaps(&f.geometry))
+        .max_by_key(|f| f.generation)?;
+
+    let geo = newest.geometry;
+    if !(geo.start <= block.start && block.end <= geo.end) || newest.holes.iter().any(overlaps) {
+        return None;
+    }
+    let blocks = newest.blocks.as_ref().filter(|b| b.size == size)?;
+    let no = block.start / size - geo.start / size;
+    blocks.sha3_256.get(no as usize).map(String::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::Index;
+
+    #[test]
+    fn block_sizes() {
+        assert_eq!(parse_block_size("4K").unwrap(), MIN_BLOCK_SIZE);
+        assert_eq!(parse_block_size("1G").unwrap(), MAX_BLOCK_SIZE);
+        assert!(parse_block_size("1K").is_err());
+        assert!(parse_block_size("2G").is_err());
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_the_source() {
+        let data = (0..11u8).collect::<Vec<_>>();
+        let mut out = vec![];
+        let mut writer = BlockWriter::new(&mut out, 4, 2);
+        for chunk in data.chunks(3) {
+            writer.write_all(chunk).unwrap();
+        }
+        let blocks = writer.finish();
+        assert_eq!(out, data);
+
+        // The source offsets 2..13 make for blocks 2..4, 4..8, 8..12 and 12..13
+        assert_eq!(blocks.size, 4);
+        assert_eq!(
+            blocks.sha3_256,
+            [&data[..2], &data[2..6], &data[6..10], &data[10..]].map(hash_block)
+        );
+
+        let mut writer = BlockWriter::new(vec![], 4, 0);
+        writer.write_all(&data[..8]).unwrap();
+        assert_eq!(writer.finish().sha3_256.len(), 2);
+    }
+
+    #[test]
+    fn hashes_of_the_newest_generation() {
+        let mut idx: Index = toml::from_str(
+            r#"
+            [[fragments]]
+            name = ["base"]
+            type = "File"
+            path = "/media/base"
+            groups = ["backup"]
+            start = 0
+            end = 12
+            generation = 1
+            blocks = { size = 4, sha3_256 = ["b0", "b1", "b2"] }
+
+            [[fragments]]
+            name = ["delta"]
+            type = "File"
+            path = "/media/delta"
+            groups = ["backup"]
+            start = 4
+            end = 8
+            generation = 2
+            blocks = { size = 4, sha3_256 = ["d1"] }
+
+            [[fragments]]
+            name = ["holey"]
+            type = "File"
+            path = "/media/holey"
+            groups = ["backup"]
+            start = 12
+            end = 20
+            generation = 1
+            holes = [{ start = 16, end = 18 }]
+            blocks = { size = 4, sha3_256 = ["h3", "h4"] }
+            "#,
+        )
+        .unwrap();
+        idx.normalize();
+        let frags = idx.fragments.iter().collect::<Vec<_>>();
+        let hash = |start, end| recorded_hash(&frags, Slice { start, end }, 4);
+
+        assert_eq!(recorded_size(&frags), Some(4));
+        assert_eq!(hash(0, 4), Some("b0"));
+        assert_eq!(hash(4, 8), Some("d1"));
+        assert_eq!(hash(8, 12), Some("b2"));
+        assert_eq!(hash(12, 16), Some("h3"));
+        assert_eq!(hash(16, 20), None);
+        assert_eq!(hash(20, 24), None);
+        assert_eq!(recorded_hash(&frags, Slice { start: 0, end: 8 }, 8), None);
+
+        idx.fragments[1].blocks.as_mut().unwrap().size = 8;
+        let frags = idx.fragments.iter().collect::<Vec<_>>();
+        assert_eq!(recorded_size(&frags), None);
+    }
+}
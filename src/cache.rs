@@ -0,0 +1,486 @@
+//! Moving data without filling the page cache
+//!
+//! Copying large images through the page cache evicts everything else and
+//! leaves gigabytes of dirty data for the final `sync_data`. [CacheMode::Direct]
+//! bypasses the cache with `O_DIRECT`, which needs buffers, offsets and
+//! lengths aligned to the sector size of the device; the unaligned head and
+//! tail of a copy are written through the page cache instead.
+//! [CacheMode::DropCache] keeps using the cache but writes data back as it
+//! goes and tells the kernel to drop whatever was read or written back.
+
+use std::fs::{self, File};
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+
+use nix::fcntl::{posix_fadvise, PosixFadviseAdvice};
+
+use crate::util::ReadSeek;
+
+/// Alignment of buffers, offsets and lengths for `O_DIRECT`; a multiple of all common sector sizes
+pub const ALIGN: u64 = 4096;
+
+/// Size of the buffers used for `O_DIRECT`
+const BUF_SIZE: usize = 1 << 20;
+
+/// Amount of data after which written data is written back and read data dropped
+const DROP_STEP: u64 = 32 << 20;
+
+/// How file data is moved through the page cache
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    #[default]
+    Buffered,
+    /// Bypass the page cache with `O_DIRECT`
+    Direct,
+    /// Go through the page cache but drop the data once it was read or written back
+    DropCache,
+}
+
+impl CacheMode {
+    pub fn from_flags(direct: bool, drop_cache: bool) -> Self {
+        match (direct, drop_cache) {
+            (true, _) => Self::Direct,
+            (false, true) => Self::DropCache,
+            (false, false) => Self::Buffered,
+        }
+    }
+}
+
+/// A buffer of `len` bytes whose start is aligned to [ALIGN]
+struct AlignedBuf {
+    data: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuf {
+    fn new(len: usize) -> Self {
+        let data = vec![0; len + ALIGN as usize];
+        let offset = data.as_ptr().align_offset(ALIGN as usize);
+        Self { data, offset, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        &self.data[self.offset..self.offset + self.len]
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.data[self.offset..self.offset + self.len]
+    }
+}
+
+fn align_down(offset: u64) -> u64 {
+    offset - offset % ALIGN
+}
+
+/// Open `path` with `O_DIRECT`; `None` if its filesystem does not support it
+fn open_direct(path: &Path, options: &mut fs::OpenOptions) -> IoResult<Option<File>> {
+    match options.custom_flags(libc::O_DIRECT).open(path) {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => {
+            log::warn!(
+                "`{}` cannot be accessed with O_DIRECT, going through the page cache.",
+                path.display()
+            );
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Ask the kernel to drop the range from the page cache; dirty pages are skipped
+fn drop_range(file: &File, start: u64, end: u64) {
+    if end > start {
+        let advice = PosixFadviseAdvice::POSIX_FADV_DONTNEED;
+        let res = posix_fadvise(file.as_raw_fd(), start as i64, (end - start) as i64, advice);
+        if let Err(e) = res {
+            log::debug!("Could not drop data from the page cache: {e}");
+        }
+    }
+}
+
+/// Start writing back the range; with `wait`, also wait for it to be written
+fn sync_range(file: &File, start: u64, end: u64, wait: bool) -> IoResult<()> {
+    if end <= start {
+        return Ok(());
+    }
+    let flags = match wait {
+        true => {
+            libc::SYNC_FILE_RANGE_WAIT_BEFORE
+                | libc::SYNC_FILE_RANGE_WRITE
+                | libc::SYNC_FILE_RANGE_WAIT_AFTER
+        }
+        false => libc::SYNC_FILE_RANGE_WRITE,
+    };
+    // SAFETY: Only plain integers and a file descriptor kept open by `file` are passed
+    let res = unsafe {
+        libc::sync_file_range(file.as_raw_fd(), start as i64, (end - start) as i64, flags)
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(IoError::last_os_error()),
+    }
+}
+
+/// Open `path` for reading in the given mode
+pub fn open_read<P: AsRef<Path>>(path: P, mode: CacheMode) -> IoResult<Box<dyn ReadSeek>> {
+    let path = path.as_ref();
+    if mode == CacheMode::Direct {
+        if let Some(file) = open_direct(path, fs::OpenOptions::new().read(true))? {
+            return Ok(Box::new(DirectReader::new(file)));
+        }
+    }
+    let file = File::open(path)?;
+    Ok(match mode {
+        CacheMode::DropCache => Box::new(DropCacheReader::new(file)),
+        _ => Box::new(file),
+    })
+}
+
+/// Reads a file opened with `O_DIRECT` through an aligned buffer
+pub struct DirectReader {
+    file: File,
+    buf: AlignedBuf,
+    /// Offset of the buffer in the file and the number of bytes it holds
+    buf_start: u64,
+    buf_len: usize,
+    pos: u64,
+}
+
+impl DirectReader {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            buf: AlignedBuf::new(BUF_SIZE),
+            buf_start: 0,
+            buf_len: 0,
+            pos: 0,
+        }
+    }
+}
+
+impl Read for DirectReader {
+    fn read(&mut self, out: &mut [u8]) -> IoResult<usize> {
+        if out.is_empty() {
+            return Ok(0);
+        }
+        if !(self.buf_start..self.buf_start + self.buf_len as u64).contains(&self.pos) {
+            self.buf_start = align_down(self.pos);
+            self.buf_len = 0;
+            self.buf_len = self.file.read_at(self.buf.as_mut_slice(), self.buf_start)?;
+            // Reads only come up short at the end of the file
+            if self.pos >= self.buf_start + self.buf_len as u64 {
+                return Ok(0);
+            }
+        }
+        let at = (self.pos - self.buf_start) as usize;
+        let n = out.len().min(self.buf_len - at);
+        out[..n].copy_from_slice(&self.buf.as_slice()[at..at + n]);
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for DirectReader {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new = match pos {
+            SeekFrom::Start(dst) => Some(dst),
+            SeekFrom::Current(dst) => self.pos.checked_add_signed(dst),
+            SeekFrom::End(dst) => self.file.metadata()?.len().checked_add_signed(dst),
+        };
+        self.pos = new.ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// Reads a file and drops what was read from the page cache every [DROP_STEP] bytes
+pub struct DropCacheReader {
+    file: File,
+    pos: u64,
+    /// Start of the data read but not dropped yet
+    dropped: u64,
+}
+
+impl DropCacheReader {
+    pub fn new(file: File) -> Self {
+        Self {
+            file,
+            pos: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl Read for DropCacheReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let n = self.file.read_at(buf, self.pos)?;
+        self.pos += n as u64;
+        if n == 0 || self.pos - self.dropped >= DROP_STEP {
+            drop_range(&self.file, self.dropped, self.pos);
+            self.dropped = self.pos;
+        }
+        Ok(n)
+    }
+}
+
+impl Seek for DropCacheReader {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new = match pos {
+            SeekFrom::Start(dst) => Some(dst),
+            SeekFrom::Current(dst) => self.pos.checked_add_signed(dst),
+            SeekFrom::End(dst) => self.file.metadata()?.len().checked_add_signed(dst),
+        };
+        let new = new.ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+        if new != self.pos {
+            drop_range(&self.file, self.dropped, self.pos);
+            (self.pos, self.dropped) = (new, new);
+        }
+        Ok(self.pos)
+    }
+}
+
+impl Drop for DropCacheReader {
+    fn drop(&mut self) {
+        drop_range(&self.file, self.dropped, self.pos);
+    }
+}
+
+/// Writes to a file at `O_DIRECT` through an aligned buffer
+///
+/// Data is collected until the buffer is full, so bytes accepted by
+/// [Write::write] are not necessarily in the file yet; [DirectWriter::finish]
+/// writes the rest and tells how much made it. Data before the first
+/// aligned offset and after the last one goes through the page cache.
+pub struct DirectWriter {
+    direct: File,
+    buffered: File,
+    buf: AlignedBuf,
+    filled: usize,
+    /// File offset where the buffer or, before the first aligned offset, the next write goes
+    pos: u64,
+    /// Bytes written to the file
+    written: u64,
+}
+
+impl DirectWriter {
+    /// Write at `offset` into `direct`, opened with `O_DIRECT`, and `buffered`, opened without
+    pub fn new(direct: File, buffered: File, offset: u64) -> Self {
+        Self {
+            direct,
+            buffered,
+            buf: AlignedBuf::new(BUF_SIZE),
+            filled: 0,
+            pos: offset,
+            written: 0,
+        }
+    }
+
+    /// Write the first `len` bytes of the buffer, which must be a multiple of [ALIGN]
+    fn write_buf(&mut self, len: usize) -> IoResult<()> {
+        let mut done = 0;
+        while done < len {
+            match self
+                .direct
+                .write_at(&self.buf.as_slice()[done..len], self.pos)
+            {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    done += n;
+                    self.pos += n as u64;
+                    self.written += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.buf.as_mut_slice().copy_within(done..self.filled, 0);
+                    self.filled -= done;
+                    return Err(e);
+                }
+            }
+        }
+        self.buf.as_mut_slice().copy_within(len..self.filled, 0);
+        self.filled -= len;
+        Ok(())
+    }
+
+    /// Write out everything still buffered; returns the number of bytes written to the file
+    pub fn finish(mut self) -> (u64, IoResult<()>) {
+        let aligned = self.filled - self.filled % ALIGN as usize;
+        let mut res = self.write_buf(aligned);
+        // The tail cannot be written with O_DIRECT
+        while res.is_ok() && self.filled > 0 {
+            match self
+                .buffered
+                .write_at(&self.buf.as_slice()[..self.filled], self.pos)
+            {
+                Ok(0) => res = Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.buf.as_mut_slice().copy_within(n..self.filled, 0);
+                    self.filled -= n;
+                    self.pos += n as u64;
+                    self.written += n as u64;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => res = Err(e),
+            }
+        }
+        (self.written, res)
+    }
+}
+
+impl Write for DirectWriter {
+    fn write(&mut self, data: &[u8]) -> IoResult<usize> {
+        // Up to the first aligned offset, nothing is buffered
+        let head = (ALIGN - self.pos % ALIGN) % ALIGN;
+        if head > 0 {
+            let len = data.len().min(head as usize);
+            let n = self.buffered.write_at(&data[..len], self.pos)?;
+            self.pos += n as u64;
+            self.written += n as u64;
+            return Ok(n);
+        }
+        if self.filled == BUF_SIZE {
+            self.write_buf(BUF_SIZE)?;
+        }
+        let n = data.len().min(BUF_SIZE - self.filled);
+        self.buf.as_mut_slice()[self.filled..self.filled + n].copy_from_slice(&data[..n]);
+        self.filled += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Writes to a file, writing data back every [DROP_STEP] bytes and dropping it from the page cache
+pub struct DropCacheWriter {
+    file: File,
+    start: u64,
+    pos: u64,
+    /// Writeback was started for the data before this offset
+    started: u64,
+    /// The data before this offset was written back and dropped
+    dropped: u64,
+}
+
+impl DropCacheWriter {
+    pub fn new(file: File, offset: u64) -> Self {
+        Self {
+            file,
+            start: offset,
+            pos: offset,
+            started: offset,
+            dropped: offset,
+        }
+    }
+
+    /// Write back all data; returns the number of bytes written
+    pub fn finish(self) -> (u64, IoResult<()>) {
+        let res = sync_range(&self.file, self.dropped, self.pos, true);
+        drop_range(&self.file, self.dropped, self.pos);
+        (self.pos - self.start, res)
+    }
+}
+
+impl Write for DropCacheWriter {
+    fn write(&mut self, data: &[u8]) -> IoResult<usize> {
+        let n = self.file.write_at(data, self.pos)?;
+        self.pos += n as u64;
+        if self.pos - self.started >= DROP_STEP {
+            // Wait for the previous step while this one is being written back
+            sync_range(&self.file, self.started, self.pos, false)?;
+            sync_range(&self.file, self.dropped, self.started, true)?;
+            drop_range(&self.file, self.dropped, self.started);
+            (self.dropped, self.started) = (self.started, self.pos);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
+
+/// Writes to a file at an offset in the given mode
+pub enum CacheWriter {
+    Buffered {
+        file: File,
+        start: u64,
+        pos: u64,
+    },
+    Direct(DirectWriter),
+    DropCache(DropCacheWriter),
+    /// Through the page cache with several writes in flight
+    #[cfg(feature = "io-uring")]
+    Uring(Box<crate::uring::UringWriter>),
+}
+
+impl CacheWriter {
+    /// Write to `file`, which is found at `path`, starting at `offset`
+    pub fn new<P: AsRef<Path>>(
+        file: &File,
+        path: P,
+        offset: u64,
+        mode: CacheMode,
+    ) -> IoResult<Self> {
+        let file = file.try_clone()?;
+        if mode == CacheMode::Direct {
+            if let Some(direct) = open_direct(path.as_ref(), fs::OpenOptions::new().write(true))? {
+                return Ok(Self::Direct(DirectWriter::new(direct, file, offset)));
+            }
+        }
+        Ok(match mode {
+            CacheMode::DropCache => Self::DropCache(DropCacheWriter::new(file, offset)),
+            _ => Self::Buffered {
+                file,
+                start: offset,
+                pos: offset,
+            },
+        })
+    }
+
+    /// Write out anything held back; returns the number of bytes that made it into the file
+    ///
+    /// May be less than what [Write::write] accepted if writing failed.
+    pub fn finish(self) -> (u64, IoResult<()>) {
+        match self {
+            Self::Buffered { start, pos, .. } => (pos - start, Ok(())),
+            Self::Direct(dst) => dst.finish(),
+            Self::DropCache(dst) => dst.finish(),
+            #[cfg(feature = "io-uring")]
+            Self::Uring(dst) => dst.finish(),
+        }
+    }
+}
+
+impl Write for CacheWriter {
+    fn write(&mut self, data: &[u8]) -> IoResult<usize> {
+        match self {
+            Self::Buffered { file, pos, .. } => {
+                let n = file.write_at(data, *pos)?;
+                *pos += n as u64;
+                Ok(n)
+            }
+            Self::Direct(dst) => dst.write(data),
+            Self::DropCache(dst) => dst.write(data),
+            #[cfg(feature = "io-uring")]
+            Self::Uring(dst) => dst.write(data),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
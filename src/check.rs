@@ -0,0 +1,309 @@
+//! Consistency checks of an index and of the files it references
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::Result;
+use base64::Engine;
+
+use crate::chunks;
+use crate::copy::hash_data;
+use crate::diff::{describe_location, fragment_key};
+use crate::index::{Fragment, HashIdentifier, Index, LocationData, Offset};
+use crate::reader::IndexReader;
+
+/// Violations of the invariants every index must satisfy
+pub fn check_index(idx: &Index) -> Vec<String> {
+    let mut problems = vec![];
+
+    let mut names: HashMap<&str, usize> = HashMap::new();
+    for frag in idx.fragments.iter() {
+        for name in frag.meta.name.iter() {
+            *names.entry(name).or_default() += 1;
+        }
+    }
+    let mut duplicates = names
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .collect::<Vec<_>>();
+    duplicates.sort();
+    for (name, count) in duplicates {
+        problems.push(format!("The name `{name}` is used by {count} fragments."));
+    }
+
+    let sources = idx.sources().collect::<Vec<_>>();
+    if sources.is_empty() {
+        problems.push("There is no fragment named `main`.".to_owned());
+    }
+    for source in sources.iter() {
+        if source.geometry.start != 0 {
+            let label = match source.is_named("main") {
+                true => "The main fragment".to_owned(),
+                false => format!("Source {}", fragment_key(source)),
+            };
+            problems.push(format!(
+                "{label} starts at {} instead of 0.",
+                source.geometry.start
+            ));
+        }
+    }
+
+    for (no, frag) in idx.fragments.iter().enumerate() {
+        let label = match frag.meta.name.is_empty() {
+            true => format!("Fragment #{no}"),
+            false => format!("Fragment {}", fragment_key(frag)),
+        };
+        let geo = frag.geometry;
+
+        if frag.meta.name.is_empty() {
+            problems.push(format!("{label} has no name."));
+        }
+        if geo.start > geo.end {
+            problems.push(format!(
+                "{label} starts at {} after its end {}.",
+                geo.start, geo.end
+            ));
+            continue;
+        }
+        if !frag.in_group("main") {
+            match idx.source_of(frag) {
+                Ok(source) => {
+                    let source = source.get(idx);
+                    let len = source.geometry.end;
+                    if !source.in_group("main") {
+                        problems.push(format!(
+                            "{label} refers to `{}`, which is not a source.",
+                            frag.source_name()
+                        ));
+                    } else if geo.end > len {
+                        problems.push(format!(
+                            "{label} ends at {} beyond the end of {} ({len}).",
+                            geo.end,
+                            match source.is_named("main") {
+                                true => "main".to_owned(),
+                                false => format!("`{}`", frag.source_name()),
+                            }
+                        ));
+                    }
+                }
+                // Reported above as a missing main or a duplicate name
+                Err(_) if sources.is_empty() => {}
+                Err(_) if idx.fragments.iter().any(|f| f.is_named(frag.source_name())) => {}
+                Err(_) => problems.push(format!(
+                    "{label} refers to the unknown source `{}`.",
+                    frag.source_name()
+                )),
+            }
+        }
+        for hole in frag.holes.iter() {
+            if hole.start > hole.end || hole.start < geo.start || hole.end > geo.end {
+                problems.push(format!(
+                    "{label} has a hole {}..{} outside of its geometry {}..{}.",
+                    hole.start, hole.end, geo.start, geo.end
+                ));
+            }
+        }
+        if let Some(slice) = frag.location.slice {
+            if slice.len() != geo.len() {
+                problems.push(format!(
+                    "{label} stores {} bytes in its file but covers {} bytes of main.",
+                    slice.len(),
+                    geo.len()
+                ));
+            }
+        }
+        if let Some(blocks) = &frag.blocks {
+            let expected = match (blocks.size, geo.is_empty()) {
+                (0, _) => None,
+                (_, true) => Some(0),
+                (size, false) => Some((geo.end - 1) / size - geo.start / size + 1),
+            };
+            match expected {
+                None => problems.push(format!("{label} records blocks of size 0.")),
+                Some(n) if n != blocks.sha3_256.len() as u64 => problems.push(format!(
+                    "{label} records {} block hashes instead of {n}.",
+                    blocks.sha3_256.len()
+                )),
+                Some(_) => {}
+            }
+        }
+        for (id, hash) in frag.hashes.iter() {
+            let expected_len = match id {
+                HashIdentifier::Sha3_256 => 32,
+            };
+            match base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(hash) {
+                Ok(digest) if digest.len() == expected_len => {}
+                _ => problems.push(format!("{label} has a malformed {id:?} hash `{hash}`.")),
+            }
+        }
+        if frag.groups.is_empty() {
+            problems.push(format!("{label} is not part of any group."));
+        }
+    }
+
+    let sources = idx.sources().map(fragment_key).collect::<Vec<_>>();
+    let mut numbers = HashSet::new();
+    for gen in idx.generations.iter() {
+        if !numbers.insert(gen.no) {
+            problems.push(format!("Generation {} is recorded more than once.", gen.no));
+        }
+        if gen.groups.is_empty() {
+            problems.push(format!("Generation {} is not part of any group.", gen.no));
+        }
+        for key in gen.sources.keys().filter(|key| !sources.contains(key)) {
+            problems.push(format!(
+                "Generation {} records the unknown source `{key}`.",
+                gen.no
+            ));
+        }
+    }
+
+    problems
+}
+
+/// Result of checking the file behind a single fragment
+pub struct FileStatus {
+    pub fragment: String,
+    pub path: String,
+    pub expected_len: Offset,
+    pub actual_len: Option<Offset>,
+    /// `None` if the hash was not checked
+    pub hash_ok: Option<bool>,
+    pub problem: Option<String>,
+    /// Why the file was not checked, if it was skipped without that being a problem
+    pub skipped: Option<String>,
+}
+
+fn file_len(path: &str) -> std::io::Result<Offset> {
+    // Block devices report a size of zero through metadata, seeking works for both
+    fs::File::open(path)?.seek(SeekFrom::End(0))
+}
+
+fn check_hash(frag: &Fragment) -> Result<Option<bool>> {
+    let Some(ref_hash) = frag.hashes.get(&HashIdentifier::Sha3_256) else {
+        return Ok(None);
+    };
+    let mut reader = IndexReader::from_fragments([frag]).with_verify(false);
+    reader.seek(SeekFrom::Start(frag.geometry.start))?;
+    let hash = hash_data(reader.take(frag.geometry.len()))?;
+    Ok(Some(hash == *ref_hash))
+}
+
+/// Check that the files of all fragments exist and have the expected size, optionally hashing them
+pub fn check_files(idx: &Index, with_hash: bool) -> Vec<FileStatus> {
+    let mut statuses = vec![];
+
+    for frag in idx.fragments.iter() {
+        let mut status = FileStatus {
+            fragment: fragment_key(frag),
+            path: String::new(),
+            expected_len: frag.data_offset() + frag.geometry.len(),
+            actual_len: None,
+            hash_ok: None,
+            problem: None,
+            skipped: None,
+        };
+
+        let path = match &frag.location.data {
+            // Recorded by rebuild-index and import-split when main itself is not at hand
+            LocationData::File(file) if file.device.is_none() && file.path.is_empty() => {
+                status.path = "-".to_owned();
+                status.skipped = Some("location unknown".to_owned());
+                statuses.push(status);
+                continue;
+            }
+            LocationData::File(file) if file.device.is_none() => file.path.clone(),
+            LocationData::ChunkStore(_) => describe_location(&frag.location.data),
+            data => {
+                status.path = format!("{data:?}");
+                status.problem = Some("cannot check this kind of location".to_owned());
+                statuses.push(status);
+                continue;
+            }
+        };
+        status.path = path.clone();
+
+        // Chunk stores must hold every chunk of the manifest in full
+        let len = match &frag.location.data {
+            LocationData::ChunkStore(chunks) => chunks::Store::open(&chunks.store)
+                .and_then(|store| store.stored_len(&chunks.manifest))
+                .map_err(|e| format!("{e:#}")),
+            _ => file_len(&path).map_err(|e| format!("cannot open: {e}")),
+        };
+        match len {
+            Err(problem) => status.problem = Some(problem),
+            Ok(len) => {
+                status.actual_len = Some(len);
+                // Files may carry trailing data after the fragment, but must not be short
+                if len < status.expected_len {
+                    status.problem = Some("file is too short".to_owned());
+                } else if with_hash && !frag.holes.is_empty() {
+                    // The recorded hash covers data that the holes no longer hold
+                    status.skipped = Some("has holes, hash not checked".to_owned());
+                } else if with_hash {
+                    log::info!("Hashing `{path}`…");
+                    match check_hash(frag) {
+                        Ok(ok) => status.hash_ok = ok,
+                        Err(e) => status.problem = Some(format!("cannot read: {e}")),
+                    }
+                    if status.hash_ok == Some(false) {
+                        status.problem = Some("hash mismatch".to_owned());
+                    }
+                }
+            }
+        }
+
+        statuses.push(status);
+    }
+
+    statuses
+}
+
+/// Render the outcome of [check_files] as a table
+pub fn render_files(statuses: &[FileStatus]) -> String {
+    let rows = statuses
+        .iter()
+        .map(|s| {
+            [
+                s.fragment.clone(),
+                s.path.clone(),
+                s.expected_len.to_string(),
+                s.actual_len
+                    .map(|l| l.to_string())
+                    .unwrap_or("-".to_owned()),
+                match s.hash_ok {
+                    Some(true) => "ok",
+                    Some(false) => "MISMATCH",
+                    None => "-",
+                }
+                .to_owned(),
+                s.problem
+                    .clone()
+                    .or(s.skipped.clone())
+                    .unwrap_or("ok".to_owned()),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    let header = ["FRAGMENT", "PATH", "EXPECTED", "ACTUAL", "HASH", "STATUS"].map(String::from);
+    let mut widths = header.clone().map(|h| h.len());
+    for row in rows.iter() {
+        for (w, cell) in widths.iter_mut().zip(row.iter()) {
+            *w = (*w).max(cell.len());
+        }
+    }
+
+    let mut out = String::new();
+    for row in [header].iter().chain(rows.iter()) {
+        let line = row
+            .iter()
+            .zip(widths.iter())
+            .map(|(cell, w)| format!("{cell:w$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
@@ -0,0 +1,324 @@
+//! Content addressed store of variable sized chunks
+//!
+//! Data is cut into chunks with FastCDC, so chunk boundaries follow the
+//! content and data shared between images, even at different offsets, ends
+//! up in the same chunks. Every chunk is stored once, named by its hash; a
+//! manifest lists the chunks making up a fragment. The layout of a store is:
+//!
+//! | Path               | Content                                               |
+//! |--------------------|-------------------------------------------------------|
+//! | `splitfile-store`  | Marker identifying the directory as a store           |
+//! | `chunks/ab/<hash>` | Chunk data; `ab` are the first two characters of hash |
+//! | `manifests/<hash>` | One `<hash> <length>` line per chunk                  |
+//!
+//! Hashes are SHA3-256 in URL safe base64, like all hashes in the index;
+//! manifests are named by the hash of their content.
+
+use std::fs;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Result};
+use sha3::Digest;
+
+use crate::blocks::hash_block;
+use crate::copy::encode_hash;
+use crate::index::{ChunkStore, Offset};
+use crate::util::read_nointr;
+
+const MARKER: &str = "splitfile-store";
+const MARKER_CONTENT: &str = "splitfile chunk store, version 1\n";
+
+/// Average chunk size used unless the command line names one
+pub const DEFAULT_CHUNK_SIZE: Offset = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub len: Offset,
+}
+
+/// What [Store::store] added to the store
+pub struct Stored {
+    pub manifest: String,
+    pub len: Offset,
+    /// Hash of all data, if requested
+    pub hash: Option<String>,
+    pub chunks: usize,
+    pub new_chunks: usize,
+    pub new_bytes: Offset,
+}
+
+pub struct Store {
+    root: PathBuf,
+}
+
+impl Store {
+    /// Open the store in `dir`, which must have been created by [Store::create]
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let root = dir.as_ref().to_owned();
+        let marker = fs::read_to_string(root.join(MARKER))
+            .with_context(|| format!("`{}` is not a chunk store", root.display()))?;
+        ensure!(
+            marker == MARKER_CONTENT,
+            "`{}` is a chunk store of an unsupported version.",
+            root.display()
+        );
+        Ok(Self { root })
+    }
+
+    /// Open the store in `dir`, creating it if the directory is missing or empty
+    pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self> {
+        let root = dir.as_ref();
+        if !root.join(MARKER).exists() {
+            fs::create_dir_all(root)
+                .with_context(|| format!("Could not create `{}`", root.display()))?;
+            ensure!(
+                fs::read_dir(root)?.next().is_none(),
+                "`{}` is neither a chunk store nor empty.",
+                root.display()
+            );
+            fs::create_dir(root.join("chunks"))?;
+            fs::create_dir(root.join("manifests"))?;
+            fs::write(root.join(MARKER), MARKER_CONTENT)?;
+            log::info!("Created chunk store `{}`.", root.display());
+        }
+        Self::open(root)
+    }
+
+    /// Whether `dir` holds a chunk store
+    pub fn is_store(dir: &Path) -> bool {
+        dir.join(MARKER).is_file()
+    }
+
+    fn chunk_path(&self, hash: &str) -> PathBuf {
+        let fan = hash.get(..2).unwrap_or(hash);
+        self.root.join("chunks").join(fan).join(hash)
+    }
+
+    pub fn manifest_path(&self, hash: &str) -> PathBuf {
+        self.root.join("manifests").join(hash)
+    }
+
+    /// Write `data` to `path` unless it exists; returns whether it was written
+    ///
+    /// Data is written to a temporary file first, so an interrupted write
+    /// never leaves a truncated chunk under its final name.
+    fn put(path: &Path, data: &[u8]) -> Result<bool> {
+        if path.exists() {
+            return Ok(false);
+        }
+        let dir = path.parent().unwrap();
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!(
+            ".{}.tmp{}",
+            path.file_name().unwrap().to_string_lossy(),
+            std::process::id()
+        ));
+        let mut file = fs::File::create(&tmp)
+            .with_context(|| format!("Could not create `{}`", tmp.display()))?;
+        file.write_all(data)
+            .and_then(|()| file.sync_data())
+            .and_then(|()| fs::rename(&tmp, path))
+            .inspect_err(|_| {
+                let _ = fs::remove_file(&tmp);
+            })
+            .with_context(|| format!("Could not write `{}`", path.display()))?;
+        Ok(true)
+    }
+
+    pub fn read_manifest(&self, hash: &str) -> Result<Vec<ChunkRef>> {
+        let path = self.manifest_path(hash);
+        let data =
+            fs::read(&path).with_context(|| format!("Could not read `{}`", path.display()))?;
+        ensure!(
+            hash_block(&data) == hash,
+            "Manifest `{}` is corrupted; its hash does not match its name.",
+            path.display()
+        );
+
+        let mut chunks = vec![];
+        for (no, line) in String::from_utf8(data)?.lines().enumerate() {
+            let parsed = line
+                .split_once(' ')
+                .and_then(|(hash, len)| Some((hash, len.parse().ok()?)));
+            let Some((hash, len)) = parsed else {
+                bail!(
+                    "Line {} of manifest `{}` is malformed.",
+                    no + 1,
+                    path.display()
+                );
+            };
+            chunks.push(ChunkRef {
+                hash: hash.to_owned(),
+                len,
+            });
+        }
+        Ok(chunks)
+    }
+
+    fn write_manifest(&self, chunks: &[ChunkRef]) -> Result<String> {
+        let data = chunks
+            .iter()
+            .map(|c| format!("{} {}\n", c.hash, c.len))
+            .collect::<String>();
+        let hash = hash_block(data.as_bytes());
+        Self::put(&self.manifest_path(&hash), data.as_bytes())?;
+        Ok(hash)
+    }
+
+    /// Cut `src` into chunks of about `avg` bytes and add those missing to the store
+    ///
+    /// Chunks are stored as they are cut, so an interrupted run still saves
+    /// the next one from writing them again.
+    pub fn store<R: Read>(&self, src: R, avg: Offset, with_hash: bool) -> Result<Stored> {
+        let (min, avg, max) = chunk_sizes(avg)?;
+        let mut hasher = with_hash.then(sha3::Sha3_256::default);
+        let mut chunks = vec![];
+        let (mut new_chunks, mut new_bytes) = (0, 0);
+
+        for chunk in fastcdc::v2020::StreamCDC::new(src, min, avg, max) {
+            let chunk = chunk.map_err(IoError::from)?;
+            if let Some(hasher) = hasher.as_mut() {
+                hasher.update(&chunk.data);
+            }
+            let hash = hash_block(&chunk.data);
+            if Self::put(&self.chunk_path(&hash), &chunk.data)? {
+                new_chunks += 1;
+                new_bytes += chunk.length as Offset;
+            }
+            chunks.push(ChunkRef {
+                hash,
+                len: chunk.length as Offset,
+            });
+        }
+
+        Ok(Stored {
+            manifest: self.write_manifest(&chunks)?,
+            len: chunks.iter().map(|c| c.len).sum(),
+            hash: hasher.map(|h| encode_hash(h.finalize())),
+            chunks: chunks.len(),
+            new_chunks,
+            new_bytes,
+        })
+    }
+
+    /// Total length of the chunks of a manifest; fails if any chunk is missing or truncated
+    pub fn stored_len(&self, manifest: &str) -> Result<Offset> {
+        let mut len = 0;
+        for chunk in self.read_manifest(manifest)? {
+            let path = self.chunk_path(&chunk.hash);
+            let actual = fs::metadata(&path)
+                .with_context(|| format!("Chunk `{}` is missing", chunk.hash))?
+                .len();
+            ensure!(
+                actual == chunk.len,
+                "Chunk `{}` has {actual} bytes instead of {}.",
+                chunk.hash,
+                chunk.len
+            );
+            len += chunk.len;
+        }
+        Ok(len)
+    }
+}
+
+/// Minimum, average and maximum chunk size for an average of `avg` bytes
+fn chunk_sizes(avg: Offset) -> Result<(u32, u32, u32)> {
+    use fastcdc::v2020::{AVERAGE_MAX, AVERAGE_MIN};
+
+    ensure!(
+        (AVERAGE_MIN as Offset..=AVERAGE_MAX as Offset).contains(&avg),
+        "The average chunk size must be between {AVERAGE_MIN} and {AVERAGE_MAX} bytes."
+    );
+    let avg = avg as u32;
+    Ok((avg / 4, avg, avg * 4))
+}
+
+/// Sequential and random access to the data of a manifest
+pub struct ChunkReader {
+    store: Store,
+    chunks: Vec<ChunkRef>,
+    /// Offset of each chunk in the data
+    starts: Vec<Offset>,
+    len: Offset,
+    pos: Offset,
+    /// The chunk currently open and the position of the file as an offset into the data
+    open: Option<(usize, fs::File, Offset)>,
+}
+
+impl ChunkReader {
+    pub fn new(location: &ChunkStore) -> Result<Self> {
+        let store = Store::open(&location.store)?;
+        let chunks = store.read_manifest(&location.manifest)?;
+        let mut starts = vec![];
+        let mut len = 0;
+        for chunk in chunks.iter() {
+            starts.push(len);
+            len += chunk.len;
+        }
+        Ok(Self {
+            store,
+            chunks,
+            starts,
+            len,
+            pos: 0,
+            open: None,
+        })
+    }
+}
+
+impl Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let no = self.starts.partition_point(|start| *start <= self.pos) - 1;
+        let (start, chunk) = (self.starts[no], &self.chunks[no]);
+
+        if self.open.as_ref().map(|(open, ..)| *open) != Some(no) {
+            let path = self.store.chunk_path(&chunk.hash);
+            let file = fs::File::open(&path).map_err(|e| {
+                IoError::new(
+                    e.kind(),
+                    format!("Could not open chunk `{}`: {e}", chunk.hash),
+                )
+            })?;
+            self.open = Some((no, file, start));
+        }
+        let (_, file, at) = self.open.as_mut().unwrap();
+        if *at != self.pos {
+            file.seek(SeekFrom::Start(self.pos - start))?;
+            *at = self.pos;
+        }
+
+        let want = buf.len().min((start + chunk.len - self.pos) as usize);
+        let got = read_nointr(file, &mut buf[..want])?;
+        if got == 0 {
+            return Err(IoError::new(
+                ErrorKind::UnexpectedEof,
+                format!("Chunk `{}` is shorter than recorded.", chunk.hash),
+            ));
+        }
+        *at += got as Offset;
+        self.pos += got as Offset;
+        Ok(got)
+    }
+}
+
+impl Seek for ChunkReader {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new = match pos {
+            SeekFrom::Start(dst) => Some(dst),
+            SeekFrom::Current(dst) => self.pos.checked_add_signed(dst),
+            SeekFrom::End(dst) => self.len.checked_add_signed(dst),
+        };
+        self.pos = new.ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,254 @@
+//! Self-describing fragment files
+//!
+//! A container fragment starts with a header describing the fragment, followed
+//! by the fragment data. The layout of the header is:
+//!
+//! | Offset | Size       | Content                                           |
+//! |--------|------------|---------------------------------------------------|
+//! | 0      | 8          | Magic `SPLTFRAG`                                  |
+//! | 8      | 4          | Format version (little endian)                    |
+//! | 12     | 4          | Header length; the data starts at this offset     |
+//! | 16     | 4          | Length of the metadata                            |
+//! | 20     | 4          | Length of the index copy; zero if there is none   |
+//! | 24     | meta len   | [Header] as TOML                                  |
+//! | …      | index len  | zlib compressed copy of the index as TOML         |
+//!
+//! The rest of the header is zero padding up to a multiple of
+//! [HEADER_ALIGN], so the data stays block aligned.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::format::Format;
+use crate::index::{Fragment, Index, Offset};
+
+pub const MAGIC: &[u8; 8] = b"SPLTFRAG";
+pub const VERSION: u32 = 1;
+pub const HEADER_ALIGN: u64 = 4096;
+
+const FIXED_LEN: usize = 24;
+
+/// Space kept free in the header so the final metadata fits where the placeholder was
+const HEADER_SLACK: usize = 1024;
+
+/// Metadata stored at the start of each container fragment
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Header {
+    /// UUID of the index the fragment belongs to
+    pub index: String,
+    /// Length of the main file
+    pub main_len: Offset,
+    /// The fragment as recorded in the index, including geometry and hashes
+    pub fragment: Fragment,
+}
+
+/// Contents of a container header as read back from a file
+pub struct Container {
+    pub header: Header,
+    pub index: Option<Index>,
+    /// Offset of the fragment data in the file
+    pub data_offset: Offset,
+}
+
+fn encode(header: &Header, index: Option<&Index>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let meta = toml::to_string(header)?.into_bytes();
+    let index = match index {
+        Some(index) => {
+            let mut enc = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::best());
+            enc.write_all(toml::to_string(index)?.as_bytes())?;
+            enc.finish()?
+        }
+        None => vec![],
+    };
+    Ok((meta, index))
+}
+
+/// Length to reserve for a header, leaving room for the metadata to grow a little
+pub fn reserve(header: &Header, index: Option<&Index>) -> Result<Offset> {
+    let (meta, index) = encode(header, index)?;
+    let len = FIXED_LEN + meta.len() + index.len() + HEADER_SLACK;
+    Ok((len as u64).next_multiple_of(HEADER_ALIGN))
+}
+
+/// Write a header of exactly `header_len` bytes at the start of `file`
+pub fn write_header(
+    file: &mut fs::File,
+    header_len: Offset,
+    header: &Header,
+    index: Option<&Index>,
+) -> Result<()> {
+    let (meta, index) = encode(header, index)?;
+
+    let mut buf = Vec::with_capacity(header_len as usize);
+    buf.extend_from_slice(MAGIC);
+    buf.extend_from_slice(&VERSION.to_le_bytes());
+    buf.extend_from_slice(&(header_len as u32).to_le_bytes());
+    buf.extend_from_slice(&(meta.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(index.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&meta);
+    buf.extend_from_slice(&index);
+    ensure!(
+        buf.len() as u64 <= header_len,
+        "Fragment header needs {} bytes but only {header_len} were reserved.",
+        buf.len()
+    );
+    buf.resize(header_len as usize, 0);
+
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(&buf)?;
+    Ok(())
+}
+
+/// Read the container header of a fragment file; `Ok(None)` for plain fragments
+pub fn read_header<R: Read>(mut src: R) -> Result<Option<Container>> {
+    let mut fixed = [0u8; FIXED_LEN];
+    let mut got = 0;
+    while got < FIXED_LEN {
+        match src.read(&mut fixed[got..])? {
+            0 => return Ok(None),
+            n => got += n,
+        }
+    }
+    if &fixed[..8] != MAGIC {
+        return Ok(None);
+    }
+
+    let field = |at: usize| u32::from_le_bytes(fixed[at..at + 4].try_into().unwrap());
+    let (version, header_len, meta_len, index_len) = (field(8), field(12), field(16), field(20));
+    if version > VERSION {
+        bail!(
+            "Fragment container version {version} is newer than the supported version {VERSION}."
+        );
+    }
+    ensure!(
+        FIXED_LEN as u64 + meta_len as u64 + index_len as u64 <= header_len as u64,
+        "Corrupted fragment container header."
+    );
+
+    let mut meta = vec![0; meta_len as usize];
+    src.read_exact(&mut meta)?;
+    let mut header: Header = toml::from_str(std::str::from_utf8(&meta)?)
+        .context("Could not parse fragment container metadata")?;
+    header.fragment.normalize();
+
+    let index = match index_len {
+        0 => None,
+        len => {
+            let mut compressed = vec![0; len as usize];
+            src.read_exact(&mut compressed)?;
+            let mut index = String::new();
+            flate2::read::ZlibDecoder::new(&compressed[..]).read_to_string(&mut index)?;
+            Some(
+                Format::Toml
+                    .deserialize(index.as_bytes())
+                    .context("Could not parse index copy in fragment")?,
+            )
+        }
+    };
+
+    Ok(Some(Container {
+        header,
+        index,
+        data_offset: header_len as u64,
+    }))
+}
+
+/// Read the container header of the fragment file at `path`
+pub fn read_header_from(path: &str) -> Result<Option<Container>> {
+    let file = fs::File::open(path).with_context(|| format!("Could not open `{path}`"))?;
+    read_header(std::io::BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::HashIdentifier;
+    use crate::util::scratch_dir;
+
+    fn index() -> Index {
+        let mut idx: Index = toml::from_str(
+            r#"
+            [[fragments]]
+            name = ["main"]
+            type = "File"
+            path = "/data/main"
+            groups = ["main"]
+            start = 0
+            end = 100
+
+            [[fragments]]
+            name = ["part"]
+            type = "File"
+            path = "/media/part"
+            groups = ["backup"]
+            start = 20
+            end = 60
+            hashes = { Sha3_256 = "abc" }
+            "#,
+        )
+        .unwrap();
+        idx.normalize();
+        idx
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let dir = scratch_dir("container");
+        let idx = index();
+        let header = Header {
+            index: "some-uuid".to_owned(),
+            main_len: 100,
+            fragment: idx.fragments[1].clone(),
+        };
+
+        for copy in [None, Some(&idx)] {
+            let len = reserve(&header, copy).unwrap();
+            assert_eq!(len % HEADER_ALIGN, 0);
+
+            let path = dir.join("part");
+            let mut file = fs::File::create(&path).unwrap();
+            write_header(&mut file, len, &header, copy).unwrap();
+            file.write_all(b"data").unwrap();
+            drop(file);
+
+            let container = read_header_from(path.to_str().unwrap()).unwrap().unwrap();
+            assert_eq!(container.data_offset, len);
+            assert_eq!(container.header.index, "some-uuid");
+            assert_eq!(container.header.main_len, 100);
+            let frag = &container.header.fragment;
+            assert_eq!(frag.geometry, header.fragment.geometry);
+            assert_eq!(frag.plain_file(), Some("/media/part"));
+            assert_eq!(frag.hashes[&HashIdentifier::Sha3_256], "abc");
+            assert_eq!(
+                container.index.map(|i| i.fragments.len()),
+                copy.map(|i| i.fragments.len())
+            );
+        }
+
+        // A header that does not fit is refused rather than truncated
+        let mut file = fs::File::create(dir.join("small")).unwrap();
+        assert!(write_header(&mut file, 64, &header, Some(&idx)).is_err());
+    }
+
+    #[test]
+    fn plain_and_foreign_files() {
+        assert!(read_header(&b""[..]).unwrap().is_none());
+        assert!(read_header(&b"SPLTFRAG"[..]).unwrap().is_none());
+        assert!(read_header(&[0u8; 4096][..]).unwrap().is_none());
+
+        let mut newer = MAGIC.to_vec();
+        newer.extend_from_slice(&(VERSION + 1).to_le_bytes());
+        newer.resize(4096, 0);
+        assert!(read_header(&newer[..]).is_err());
+
+        let mut corrupted = MAGIC.to_vec();
+        for field in [VERSION, 64, 100, 0] {
+            corrupted.extend_from_slice(&field.to_le_bytes());
+        }
+        corrupted.resize(4096, 0);
+        assert!(read_header(&corrupted[..]).is_err());
+    }
+}
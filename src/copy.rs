@@ -1,6 +1,12 @@
-use std::io::{Read, Seek, Write};
+use std::fs::File;
+use std::io::{Read, Write};
+use std::os::fd::AsRawFd;
 
 use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::copy_file_range;
+use nix::sys::sendfile::sendfile;
+use nix::unistd::{lseek, Whence};
 
 use crate::util::{process_chunks, try_write_all, NullBuffer};
 
@@ -14,7 +20,7 @@ pub fn copy_and_hash_with<Src, Dst, Hasher>(
     mut hasher: Hasher,
 ) -> (usize, bool, Result<()>)
 where
-    Src: Read + Seek,
+    Src: Read,
     Dst: Write,
     Hasher: Write,
 {
@@ -58,31 +64,34 @@ where
 
 pub fn copy_and_hash<Src, Dst>(src: Src, dst: Dst) -> (String, usize, bool, Result<()>)
 where
-    Src: Read + Seek,
+    Src: Read,
     Dst: Write,
 {
-    use base64::Engine;
     use sha3::digest::FixedOutput;
 
     let mut hasher = sha3::Sha3_256::default();
     let (written, fatal, res) = copy_and_hash_with(src, dst, &mut hasher);
-    let hash = hasher.finalize_fixed();
-
-    let hash = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hash);
+    let hash = encode_hash(hasher.finalize_fixed());
 
     (hash, written, fatal, res)
 }
 
+/// Encode a digest the way hashes are stored in the index
+pub fn encode_hash<D: AsRef<[u8]>>(digest: D) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(digest)
+}
+
 pub fn copy_without_hash<Src, Dst>(src: Src, dst: Dst) -> (usize, bool, Result<()>)
 where
-    Src: Read + Seek,
+    Src: Read,
     Dst: Write,
 {
     let (written, fatal, res) = copy_and_hash_with(src, dst, &mut NullBuffer);
     (written, fatal, res)
 }
 
-pub fn hash_data<Src: Read + Seek>(src: Src) -> Result<String> {
+pub fn hash_data<Src: Read>(src: Src) -> Result<String> {
     match copy_and_hash(src, &mut NullBuffer) {
         // Expected results
         (hash, _, false, Ok(())) => Ok(hash), // Regular result
@@ -113,7 +122,7 @@ pub fn copy_and_optionally_hash<Src, Dst>(
     dst: Dst,
 ) -> (Option<String>, usize, bool, Result<()>)
 where
-    Src: Read + Seek,
+    Src: Read,
     Dst: Write,
 {
     if with_hash {
@@ -124,3 +133,104 @@ where
         (None, written, data, res)
     }
 }
+
+/// How [copy_in_kernel] moved the data
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum KernelCopy {
+    /// The destination shares the extents of the source on a copy-on-write filesystem
+    Reflink,
+    CopyFileRange,
+    Sendfile,
+}
+
+/// Errors meaning the kernel cannot copy between these files, as opposed to failing to
+fn unsupported(err: Errno) -> bool {
+    matches!(
+        err,
+        Errno::ENOSYS | Errno::EOPNOTSUPP | Errno::EXDEV | Errno::EINVAL | Errno::EBADF
+    )
+}
+
+/// Share the range with a reflink; only works on copy-on-write filesystems with aligned offsets
+fn reflink(src: &File, src_offset: u64, dst: &File, dst_offset: u64, len: u64) -> nix::Result<()> {
+    let range = libc::file_clone_range {
+        src_fd: src.as_raw_fd() as i64,
+        src_offset,
+        src_length: len,
+        dest_offset: dst_offset,
+    };
+    // SAFETY: The argument is a valid `file_clone_range` that outlives the call
+    let res = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONERANGE, &range) };
+    Errno::result(res).map(drop)
+}
+
+/// Copy `len` bytes from `src` at `src_offset` to `dst` at `dst_offset` without reading them
+///
+/// Tries a reflink first, which is instant on copy-on-write filesystems
+/// like btrfs and XFS, then `copy_file_range` and finally `sendfile`.
+/// Returns `None` if the kernel cannot copy between these files; nothing
+/// was copied then and the caller has to fall back to reading and writing.
+/// Otherwise returns the method, the number of bytes copied and the error
+/// that stopped the copy early, if any. `progress` is called with the
+/// number of bytes copied by every step.
+pub fn copy_in_kernel<F: FnMut(u64)>(
+    src: &File,
+    src_offset: u64,
+    dst: &File,
+    dst_offset: u64,
+    len: u64,
+    mut progress: F,
+) -> Option<(KernelCopy, u64, Result<()>)> {
+    // Large steps keep the number of system calls low, small ones keep the progress moving
+    const STEP: u64 = 64 << 20;
+
+    // A length of zero would clone everything up to the end of the source
+    if len > 0 {
+        match reflink(src, src_offset, dst, dst_offset, len) {
+            Ok(()) => {
+                progress(len);
+                return Some((KernelCopy::Reflink, len, Ok(())));
+            }
+            Err(e) => log::debug!("Cannot reflink, copying instead: {e}"),
+        }
+    }
+
+    let mut method = KernelCopy::CopyFileRange;
+    let mut copied = 0;
+    while copied < len {
+        let step = (len - copied).min(STEP) as usize;
+        let mut off_in = (src_offset + copied) as i64;
+        let mut off_out = (dst_offset + copied) as i64;
+        let res = match method {
+            KernelCopy::CopyFileRange => {
+                copy_file_range(src, Some(&mut off_in), dst, Some(&mut off_out), step)
+            }
+            // Unlike the input, sendfile writes the output at its file offset
+            _ => lseek(dst.as_raw_fd(), off_out, Whence::SeekSet)
+                .and_then(|_| sendfile(dst, src, Some(&mut off_in), step)),
+        };
+        match res {
+            Ok(0) => {
+                let e = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+                return Some((method, copied, Err(e).context("The source ended early")));
+            }
+            Ok(n) => {
+                copied += n as u64;
+                progress(n as u64);
+            }
+            Err(Errno::EINTR) => {}
+            Err(e) if copied == 0 && unsupported(e) => match method {
+                KernelCopy::CopyFileRange => {
+                    log::debug!("Cannot use copy_file_range, trying sendfile: {e}");
+                    method = KernelCopy::Sendfile;
+                }
+                _ => {
+                    log::debug!("Cannot use sendfile, copying through userspace: {e}");
+                    return None;
+                }
+            },
+            Err(e) => return Some((method, copied, Err(std::io::Error::from(e).into()))),
+        }
+    }
+    Some((method, copied, Ok(())))
+}
@@ -0,0 +1,303 @@
+//! Compare and combine indices describing the same main file
+//!
+//! Fragments are matched by the UUID in their names; fragments without a
+//! UUID are matched by their full list of names. The main fragment is
+//! always matched by the name `main`, since indices created independently
+//! for the same file assign it different UUIDs.
+
+use std::fmt::Write as _;
+
+use anyhow::{bail, Result};
+
+use crate::index::{Fragment, HashIdentifier, Index, LocationData};
+
+/// How two records of the same fragment differ
+#[derive(Default)]
+pub struct Changes {
+    /// Differences that cannot both be true; the fragments contradict each other
+    pub conflicts: Vec<String>,
+    /// Differences that can be reconciled by merging
+    pub other: Vec<String>,
+}
+
+impl Changes {
+    pub fn is_empty(&self) -> bool {
+        self.conflicts.is_empty() && self.other.is_empty()
+    }
+
+    pub fn describe(&self) -> String {
+        self.conflicts
+            .iter()
+            .chain(self.other.iter())
+            .cloned()
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+pub enum Entry<'a> {
+    Added(&'a Fragment),
+    Removed(&'a Fragment),
+    Changed {
+        frag: &'a Fragment,
+        changes: Changes,
+    },
+}
+
+/// Key by which fragments of different indices are matched
+pub fn fragment_key(frag: &Fragment) -> String {
+    if frag.is_named("main") {
+        return "main".to_owned();
+    }
+    match frag.meta.uuid() {
+        Some(uuid) => uuid.to_owned(),
+        None => frag.meta.name.join("/"),
+    }
+}
+
+fn find<'a>(idx: &'a Index, key: &str) -> Option<&'a Fragment> {
+    idx.fragments.iter().find(|f| fragment_key(f) == key)
+}
+
+pub fn describe_location(data: &LocationData) -> String {
+    match data {
+        LocationData::File(file) if file.device.is_none() => file.path.clone(),
+        LocationData::ChunkStore(chunks) => {
+            format!("{} (chunks {})", chunks.store, chunks.manifest)
+        }
+        data => format!("{data:?}"),
+    }
+}
+
+/// One line summary of a fragment
+pub fn describe(frag: &Fragment) -> String {
+    format!(
+        "{} {}..{} [{}] {}{}",
+        fragment_key(frag),
+        frag.geometry.start,
+        frag.geometry.end,
+        frag.groups.join(", "),
+        describe_location(&frag.location.data),
+        match frag.stale {
+            true => " (stale)",
+            false => "",
+        }
+    )
+}
+
+pub fn compare(a: &Fragment, b: &Fragment) -> Changes {
+    let mut changes = Changes::default();
+
+    if a.geometry != b.geometry {
+        changes.conflicts.push(format!(
+            "geometry {}..{} -> {}..{}",
+            a.geometry.start, a.geometry.end, b.geometry.start, b.geometry.end
+        ));
+    }
+
+    for id in [HashIdentifier::Sha3_256] {
+        match (a.hashes.get(&id), b.hashes.get(&id)) {
+            (Some(ha), Some(hb)) if ha != hb => {
+                changes.conflicts.push(format!("{id:?} hash {ha} -> {hb}"))
+            }
+            (None, Some(hb)) => changes.other.push(format!("{id:?} hash added: {hb}")),
+            (Some(ha), None) => changes.other.push(format!("{id:?} hash removed: {ha}")),
+            _ => {}
+        }
+    }
+
+    if a.generation != b.generation {
+        changes
+            .conflicts
+            .push(format!("generation {} -> {}", a.generation, b.generation));
+    }
+
+    if a.stale != b.stale {
+        changes
+            .conflicts
+            .push(format!("stale {} -> {}", a.stale, b.stale));
+    }
+
+    if a.holes != b.holes {
+        changes.conflicts.push(format!(
+            "holes {:?} -> {:?}",
+            a.holes.iter().map(|h| h.start..h.end).collect::<Vec<_>>(),
+            b.holes.iter().map(|h| h.start..h.end).collect::<Vec<_>>()
+        ));
+    }
+
+    let (mut ga, mut gb) = (a.groups.clone(), b.groups.clone());
+    ga.sort();
+    gb.sort();
+    if ga != gb {
+        changes
+            .other
+            .push(format!("groups [{}] -> [{}]", ga.join(", "), gb.join(", ")));
+    }
+
+    let (la, lb) = (
+        describe_location(&a.location.data),
+        describe_location(&b.location.data),
+    );
+    if la != lb || a.location.slice != b.location.slice {
+        changes.other.push(format!("location {la} -> {lb}"));
+    }
+
+    changes
+}
+
+/// Whether both indices describe the same sources, judging by length and hash
+pub fn check_same_main(a: &Index, b: &Index) -> Result<()> {
+    let keys = |idx: &Index| idx.sources().map(fragment_key).collect::<Vec<_>>();
+    let (ka, kb) = (keys(a), keys(b));
+    if ka.is_empty() || kb.is_empty() {
+        a.get_fragment_by_name("main")?;
+        b.get_fragment_by_name("main")?;
+    }
+    if ka != kb {
+        bail!(
+            "The indices describe different sources: {} and {}.",
+            ka.join(", "),
+            kb.join(", ")
+        );
+    }
+
+    for ((ma, mb), key) in a.sources().zip(b.sources()).zip(ka) {
+        let what = match key.as_str() {
+            "main" => "main files".to_owned(),
+            _ => format!("versions of source `{key}`"),
+        };
+
+        if ma.geometry.end != mb.geometry.end {
+            bail!(
+                "The indices describe different {what}: \
+                their lengths differ ({} and {}).",
+                ma.geometry.end,
+                mb.geometry.end
+            );
+        }
+
+        let id = HashIdentifier::Sha3_256;
+        match (ma.hashes.get(&id), mb.hashes.get(&id)) {
+            (Some(ha), Some(hb)) if ha != hb => bail!(
+                "The indices describe different {what}: \
+                their hashes differ ({ha} and {hb})."
+            ),
+            (Some(_), Some(_)) => {}
+            _ => log::warn!(
+                "Source `{key}` is missing a hash in at least one index; \
+                only its length could be compared."
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// All differences between `a` and `b`, in the order of the fragments in `a`, then `b`
+pub fn diff<'a>(a: &'a Index, b: &'a Index) -> Vec<Entry<'a>> {
+    let mut entries = vec![];
+    for fa in a.fragments.iter() {
+        match find(b, &fragment_key(fa)) {
+            None => entries.push(Entry::Removed(fa)),
+            Some(fb) => {
+                let changes = compare(fa, fb);
+                if !changes.is_empty() {
+                    entries.push(Entry::Changed { frag: fa, changes });
+                }
+            }
+        }
+    }
+    for fb in b.fragments.iter() {
+        if find(a, &fragment_key(fb)).is_none() {
+            entries.push(Entry::Added(fb));
+        }
+    }
+    entries
+}
+
+/// Which record wins when two records of a fragment conflict
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Side {
+    A,
+    B,
+}
+
+fn union<T: Clone + PartialEq>(into: &mut Vec<T>, from: &[T]) {
+    for item in from {
+        if !into.contains(item) {
+            into.push(item.clone());
+        }
+    }
+}
+
+/// Combine two records of a fragment that do not conflict
+fn merge_fragment(into: &mut Fragment, from: &Fragment) {
+    union(&mut into.meta.name, &from.meta.name);
+    union(&mut into.meta.comment, &from.meta.comment);
+    union(&mut into.groups, &from.groups);
+    for (id, hash) in from.hashes.iter() {
+        into.hashes.entry(*id).or_insert_with(|| hash.clone());
+    }
+}
+
+/// Union of the fragments of `a` and `b`
+///
+/// `resolve` is called for every conflicting fragment and decides which
+/// record to keep; returning an error aborts the merge. The merged index
+/// keeps the UUID of `a`, or takes the one of `b` if `a` has none.
+pub fn merge<F>(a: &Index, b: &Index, mut resolve: F) -> Result<Index>
+where
+    F: FnMut(&Fragment, &Fragment, &Changes) -> Result<Side>,
+{
+    let mut merged = a.clone();
+    let names = b
+        .meta
+        .name
+        .iter()
+        .filter(|name| a.meta.uuid().is_none() || uuid::Uuid::parse_str(name).is_err())
+        .cloned()
+        .collect::<Vec<_>>();
+    union(&mut merged.meta.name, &names);
+    union(&mut merged.meta.comment, &b.meta.comment);
+    union(&mut merged.replicas, &b.replicas);
+    merged.revision = a.revision.max(b.revision);
+
+    for fb in b.fragments.iter() {
+        let key = fragment_key(fb);
+        let Some(fa) = merged.fragments.iter_mut().find(|f| fragment_key(f) == key) else {
+            merged.fragments.push(fb.clone());
+            continue;
+        };
+
+        let changes = compare(fa, fb);
+        if changes.conflicts.is_empty() {
+            if !changes.other.is_empty() {
+                log::info!("Merging {key}: {}", changes.describe());
+            }
+            merge_fragment(fa, fb);
+            continue;
+        }
+
+        if resolve(fa, fb, &changes)? == Side::B {
+            *fa = fb.clone();
+        }
+    }
+
+    Ok(merged)
+}
+
+/// Render `entries` as a diff, one line per fragment
+pub fn render(entries: &[Entry]) -> String {
+    let mut out = String::new();
+    for entry in entries {
+        let _ = match entry {
+            Entry::Added(frag) => writeln!(out, "+ {}", describe(frag)),
+            Entry::Removed(frag) => writeln!(out, "- {}", describe(frag)),
+            Entry::Changed { frag, changes } => {
+                writeln!(out, "~ {}: {}", fragment_key(frag), changes.describe())
+            }
+        };
+    }
+    out
+}
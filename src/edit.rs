@@ -0,0 +1,244 @@
+//! Edit the fragments recorded in an index
+//!
+//! All edits work on an in-memory index; [validate] makes sure an edit did
+//! not introduce inconsistencies before the index is written back.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{bail, ensure, Result};
+
+use crate::check::check_index;
+use crate::container;
+use crate::copy::hash_data;
+use crate::index::{File, Fragment, HashIdentifier, Index, Meta, Offset, Slice};
+use crate::util::{pretty_path, uuidgen};
+
+/// Refuse edits that make the index violate invariants it satisfied before
+pub fn validate(before: &Index, after: &Index) -> Result<()> {
+    let known = check_index(before);
+    let new = check_index(after)
+        .into_iter()
+        .filter(|p| !known.contains(p))
+        .collect::<Vec<_>>();
+    ensure!(
+        new.is_empty(),
+        "Refusing to make the index inconsistent:\n\t{}",
+        new.join("\n\t")
+    );
+    Ok(())
+}
+
+/// Parts of `range` of `source` not covered by the fragments in `group`, ignoring fragment number `skip`
+fn uncovered(idx: &Index, source: usize, group: &str, range: Slice, skip: usize) -> Vec<Slice> {
+    let mut covering = idx
+        .fragments
+        .iter()
+        .enumerate()
+        .filter(|(no, frag)| *no != skip && frag.in_group(group) && !frag.stale)
+        .filter(|(_, frag)| idx.source_of(frag).is_ok_and(|s| s.idx() == source))
+        .map(|(_, frag)| frag.geometry)
+        .collect::<Vec<_>>();
+    covering.sort_by_key(|s| (s.start, s.end));
+
+    let mut gaps = vec![];
+    let mut pos = range.start;
+    for seg in covering {
+        if seg.end <= pos {
+            continue;
+        }
+        if seg.start >= range.end {
+            break;
+        }
+        if seg.start > pos {
+            gaps.push(Slice {
+                start: pos,
+                end: seg.start,
+            });
+        }
+        pos = seg.end;
+    }
+    if pos < range.end {
+        gaps.push(Slice {
+            start: pos,
+            end: range.end,
+        });
+    }
+    gaps
+}
+
+/// Refuse if dropping fragment `no` from `group` leaves part of its source without a copy in that group
+fn ensure_still_covered(idx: &Index, no: usize, group: &str) -> Result<()> {
+    let frag = &idx.fragments[no];
+    let source = frag.source_name();
+    let gaps = uncovered(idx, idx.source_of(frag)?.idx(), group, frag.geometry, no);
+    ensure!(
+        gaps.is_empty(),
+        "Group `{group}` would no longer cover {} of `{source}`; use --force to proceed anyway.",
+        gaps.iter()
+            .map(|g| format!("{}..{}", g.start, g.end))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+    Ok(())
+}
+
+fn find(idx: &Index, name: &str) -> Result<usize> {
+    Ok(idx.get_fragment_by_name(name)?.idx())
+}
+
+/// Register an existing file holding `source` from `offset` on; returns the name of the new fragment
+#[allow(clippy::too_many_arguments)]
+pub fn add(
+    idx: &mut Index,
+    path: &str,
+    source: Option<&str>,
+    offset: Offset,
+    len: Option<Offset>,
+    group: &str,
+    name: Option<&str>,
+    with_hash: bool,
+) -> Result<String> {
+    // Refer to the source by its UUID, which unlike its other names never changes
+    let source = match source {
+        Some(name) => {
+            let src = idx.get_fragment_by_name(name)?.get(idx);
+            ensure!(src.in_group("main"), "Fragment `{name}` is not a source.");
+            Some(src.meta.uuid().cloned().unwrap_or_else(|| name.to_owned()))
+        }
+        None => None,
+    };
+
+    let canonical = pretty_path(fs::canonicalize(path)?);
+    let mut file = fs::File::open(path)?;
+    let file_len = file.seek(SeekFrom::End(0))?;
+
+    let data_offset = match container::read_header_from(path)? {
+        Some(cont) => {
+            log::info!(
+                "`{path}` is a self-describing fragment; its data starts at {}.",
+                cont.data_offset
+            );
+            cont.data_offset
+        }
+        None => 0,
+    };
+    let available = file_len.saturating_sub(data_offset);
+    let len = len.unwrap_or(available);
+    ensure!(
+        len <= available,
+        "`{path}` holds only {available} bytes of data, not {len}."
+    );
+
+    let mut hashes = HashMap::new();
+    if with_hash {
+        log::info!("Hashing `{path}`…");
+        file.seek(SeekFrom::Start(data_offset))?;
+        hashes.insert(HashIdentifier::Sha3_256, hash_data((&mut file).take(len))?);
+    }
+
+    let name = name.map(str::to_owned).unwrap_or_else(uuidgen);
+    let mut location = File {
+        device: None,
+        path: canonical.clone(),
+    }
+    .as_location();
+    if data_offset > 0 {
+        location.slice = Some(Slice {
+            start: data_offset,
+            end: data_offset + len,
+        });
+    }
+
+    idx.fragments.push(Fragment {
+        meta: Meta {
+            name: vec![name.clone()],
+            comment: vec![
+                format!("Relative path during registration: {path}"),
+                format!("Canonical path during registration: {canonical}"),
+            ],
+        },
+        location,
+        groups: vec![group.to_owned()],
+        stale: false,
+        generation: 0,
+        source,
+        hashes,
+        geometry: Slice {
+            start: offset,
+            end: offset + len,
+        },
+        holes: vec![],
+        stat: None,
+        blocks: None,
+        extra: Default::default(),
+    });
+
+    Ok(name)
+}
+
+/// Remove a fragment from the index and return it
+pub fn remove(idx: &mut Index, name: &str, force: bool) -> Result<Fragment> {
+    let no = find(idx, name)?;
+    if idx.fragments[no].is_named("main") {
+        bail!("Refusing to remove the main fragment.");
+    }
+    if idx.fragments[no].in_group("main") {
+        bail!("Refusing to remove source `{name}`.");
+    }
+    if !force {
+        for group in idx.fragments[no].groups.iter() {
+            ensure_still_covered(idx, no, group)?;
+        }
+    }
+    Ok(idx.fragments.remove(no))
+}
+
+pub fn rename(idx: &mut Index, old: &str, new: &str) -> Result<()> {
+    ensure!(
+        old != "main" && new != "main",
+        "The name `main` is reserved for the main fragment."
+    );
+    ensure!(
+        idx.get_fragment_by_name(new).is_err(),
+        "There already is a fragment named `{new}`."
+    );
+    let no = find(idx, old)?;
+    for n in idx.fragments[no].meta.name.iter_mut() {
+        if n == old {
+            *n = new.to_owned();
+        }
+    }
+    Ok(())
+}
+
+pub fn add_group(idx: &mut Index, name: &str, group: &str) -> Result<()> {
+    let no = find(idx, name)?;
+    let frag = &mut idx.fragments[no];
+    if frag.in_group(group) {
+        log::info!("Fragment `{name}` already is in group `{group}`.");
+    } else {
+        frag.groups.push(group.to_owned());
+    }
+    Ok(())
+}
+
+pub fn remove_group(idx: &mut Index, name: &str, group: &str, force: bool) -> Result<()> {
+    let no = find(idx, name)?;
+    ensure!(
+        idx.fragments[no].in_group(group),
+        "Fragment `{name}` is not in group `{group}`."
+    );
+    if !force {
+        ensure_still_covered(idx, no, group)?;
+    }
+    idx.fragments[no].groups.retain(|g| g != group);
+    Ok(())
+}
+
+pub fn comment(idx: &mut Index, name: &str, text: &str) -> Result<()> {
+    let no = find(idx, name)?;
+    idx.fragments[no].meta.comment.push(text.to_owned());
+    Ok(())
+}
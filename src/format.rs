@@ -0,0 +1,153 @@
+//! Serializations of the index
+//!
+//! TOML is the default and meant for editing by hand. JSON is convenient for
+//! other tools; CBOR is compact and fast for indices with many fragments.
+//! The format is chosen by `--format` or the file extension when writing and
+//! detected from the content when reading.
+
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::index::{Index, SchemaVersion};
+use crate::util::try_read;
+use crate::{migrate, preserve};
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Format {
+    #[default]
+    Toml,
+    Json,
+    Cbor,
+}
+
+impl Format {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            "cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of serialized data
+    pub fn detect(data: &[u8]) -> Self {
+        match std::str::from_utf8(data) {
+            Err(_) => Self::Cbor,
+            Ok(text) if text.trim_start().starts_with('{') => Self::Json,
+            Ok(_) => Self::Toml,
+        }
+    }
+
+    /// Format to write `path` in: explicit choice, extension, format of the existing file, TOML
+    pub fn for_writing(path: &str, explicit: Option<Self>) -> Result<Self> {
+        if let Some(format) = explicit.or_else(|| Self::from_path(path)) {
+            return Ok(format);
+        }
+        Ok(try_read(path)?
+            .map(|data| Self::detect(&data))
+            .unwrap_or_default())
+    }
+
+    pub fn serialize(self, idx: &Index) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Toml => toml::to_string(idx)?.into_bytes(),
+            Self::Json => {
+                let mut data = serde_json::to_vec_pretty(idx)?;
+                data.push(b'\n');
+                data
+            }
+            Self::Cbor => {
+                let mut data = vec![];
+                ciborium::into_writer(idx, &mut data)?;
+                data
+            }
+        })
+    }
+
+    /// Serialize `idx` to replace the file at `path`; comments and layout of a TOML file are kept
+    pub fn serialize_over(self, idx: &Index, path: &str) -> Result<Vec<u8>> {
+        let new = self.serialize(idx)?;
+        if self != Self::Toml {
+            return Ok(new);
+        }
+        let old = match try_read(path)? {
+            Some(old) if Self::detect(&old) == Self::Toml => String::from_utf8(old)?,
+            _ => return Ok(new),
+        };
+        match preserve::update(&old, std::str::from_utf8(&new)?) {
+            Ok(updated) => Ok(updated.into_bytes()),
+            Err(e) => {
+                log::warn!("Rewriting `{path}` from scratch: {e:?}");
+                Ok(new)
+            }
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(self, data: &[u8]) -> Result<T> {
+        Ok(match self {
+            Self::Toml => toml::from_str(std::str::from_utf8(data)?)?,
+            Self::Json => serde_json::from_slice(data)?,
+            Self::Cbor => ciborium::from_reader(data)?,
+        })
+    }
+
+    /// Schema version of a serialized index
+    pub fn version(self, data: &[u8]) -> Result<SchemaVersion> {
+        Ok(self.parse::<VersionProbe>(data)?.version)
+    }
+
+    /// Deserialize an index, upgrading older layouts on the way
+    pub fn deserialize(self, data: &[u8]) -> Result<Index> {
+        Ok(self.deserialize_migrating(data)?.0)
+    }
+
+    /// Like [Format::deserialize], also returning the migration steps applied
+    pub fn deserialize_migrating(self, data: &[u8]) -> Result<(Index, Vec<&'static str>)> {
+        let version = self.version(data)?;
+        migrate::ensure_supported(version)?;
+
+        let (mut idx, steps): (Index, _) = match version == SchemaVersion::CURRENT {
+            true => (self.parse(data)?, vec![]),
+            false => {
+                let mut raw: toml::Table = self.parse(data)?;
+                let steps = migrate::migrate(&mut raw, version)?;
+                log::debug!(
+                    "Upgraded index from schema version {} to {}.",
+                    version.0,
+                    SchemaVersion::CURRENT.0
+                );
+                (toml::Value::Table(raw).try_into()?, steps)
+            }
+        };
+        idx.normalize();
+        Ok((idx, steps))
+    }
+}
+
+/// Just the version of an index
+#[derive(Deserialize)]
+struct VersionProbe {
+    #[serde(default = "legacy")]
+    version: SchemaVersion,
+}
+
+fn legacy() -> SchemaVersion {
+    SchemaVersion::LEGACY
+}
+
+/// Load an index in any format; `Ok(None)` if it does not exist
+pub fn read(path: &str) -> Result<Option<(Index, Format)>> {
+    let Some(data) = try_read(path)? else {
+        return Ok(None);
+    };
+    let format = Format::detect(&data);
+    let idx = format
+        .deserialize(&data)
+        .with_context(|| format!("Could not parse index `{path}` as {format:?}"))?;
+    Ok(Some((idx, format)))
+}
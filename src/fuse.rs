@@ -0,0 +1,599 @@
+//! A minimal read-only FUSE filesystem exposing main as a single file
+//!
+//! Only the handful of requests needed to list the root directory and read
+//! the file are implemented; the kernel protocol is spoken directly over
+//! `/dev/fuse`.
+
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Context, Result};
+use nix::errno::Errno;
+
+use crate::reader::IndexReader;
+
+const FUSE_KERNEL_VERSION: u32 = 7;
+const FUSE_KERNEL_MINOR_VERSION: u32 = 31;
+
+const MAX_WRITE: u32 = 128 * 1024;
+const BUFFER_SIZE: usize = MAX_WRITE as usize + 4096;
+
+const ROOT_INO: u64 = 1;
+const FILE_INO: u64 = 2;
+
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+/// Validity of attributes and entries handed to the kernel; nothing ever changes
+const TTL_SECS: u64 = 3600;
+
+mod opcode {
+    pub const LOOKUP: u32 = 1;
+    pub const FORGET: u32 = 2;
+    pub const GETATTR: u32 = 3;
+    pub const OPEN: u32 = 14;
+    pub const READ: u32 = 15;
+    pub const STATFS: u32 = 17;
+    pub const RELEASE: u32 = 18;
+    pub const FLUSH: u32 = 25;
+    pub const INIT: u32 = 26;
+    pub const OPENDIR: u32 = 27;
+    pub const READDIR: u32 = 28;
+    pub const RELEASEDIR: u32 = 29;
+    pub const ACCESS: u32 = 34;
+    pub const INTERRUPT: u32 = 36;
+    pub const DESTROY: u32 = 38;
+    pub const BATCH_FORGET: u32 = 42;
+}
+
+/// How the filesystem got mounted, which determines how to unmount it
+enum Mounted {
+    Direct,
+    Fusermount(String),
+}
+
+pub struct MountOptions {
+    /// Name of the file in the root of the mount
+    pub name: String,
+    /// Let users other than the one mounting access the filesystem
+    pub allow_other: bool,
+}
+
+struct Request<'a> {
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    body: &'a [u8],
+}
+
+/// Little builder for replies in the kernel's native layout
+#[derive(Default)]
+struct Reply(Vec<u8>);
+
+impl Reply {
+    fn u16(mut self, v: u16) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn u32(mut self, v: u32) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn u64(mut self, v: u64) -> Self {
+        self.0.extend_from_slice(&v.to_ne_bytes());
+        self
+    }
+
+    fn bytes(mut self, v: &[u8]) -> Self {
+        self.0.extend_from_slice(v);
+        self
+    }
+
+    fn pad_to(mut self, align: usize) -> Self {
+        let len = self.0.len().next_multiple_of(align);
+        self.0.resize(len, 0);
+        self
+    }
+}
+
+struct Filesystem {
+    reader: IndexReader,
+    name: String,
+    uid: u32,
+    gid: u32,
+    time: u64,
+}
+
+fn ne_u32(buf: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+fn ne_u64(buf: &[u8], at: usize) -> u64 {
+    u64::from_ne_bytes(buf[at..at + 8].try_into().unwrap())
+}
+
+/// Size of the input struct a request of `opcode` starts with, as far as it is read
+fn body_len(opcode: u32) -> usize {
+    use opcode::*;
+
+    match opcode {
+        // fuse_init_in as of protocol 7.6
+        INIT => 16,
+        // fuse_open_in
+        OPEN | OPENDIR => 8,
+        // fuse_read_in
+        READ | READDIR => 40,
+        _ => 0,
+    }
+}
+
+impl Filesystem {
+    fn attr(&self, ino: u64) -> Reply {
+        let (size, mode, nlink) = match ino {
+            ROOT_INO => (0, libc::S_IFDIR | 0o555, 2),
+            _ => (self.reader.len(), libc::S_IFREG | 0o444, 1),
+        };
+        Reply::default()
+            .u64(ino)
+            .u64(size)
+            .u64(size.div_ceil(512))
+            .u64(self.time) // atime
+            .u64(self.time) // mtime
+            .u64(self.time) // ctime
+            .u32(0)
+            .u32(0)
+            .u32(0)
+            .u32(mode)
+            .u32(nlink)
+            .u32(self.uid)
+            .u32(self.gid)
+            .u32(0) // rdev
+            .u32(4096) // blksize
+            .u32(0) // flags
+    }
+
+    fn attr_out(&self, ino: u64) -> Reply {
+        Reply::default()
+            .u64(TTL_SECS)
+            .u32(0)
+            .u32(0)
+            .bytes(&self.attr(ino).0)
+    }
+
+    fn entry_out(&self, ino: u64) -> Reply {
+        Reply::default()
+            .u64(ino)
+            .u64(0) // generation
+            .u64(TTL_SECS)
+            .u64(TTL_SECS)
+            .u32(0)
+            .u32(0)
+            .bytes(&self.attr(ino).0)
+    }
+
+    fn readdir(&self, offset: u64, size: usize) -> Reply {
+        let entries = [
+            (ROOT_INO, libc::DT_DIR, "."),
+            (ROOT_INO, libc::DT_DIR, ".."),
+            (FILE_INO, libc::DT_REG, self.name.as_str()),
+        ];
+
+        let mut out = Reply::default();
+        for (no, (ino, typ, name)) in entries.iter().enumerate().skip(offset as usize) {
+            let dirent = Reply::default()
+                .u64(*ino)
+                .u64(no as u64 + 1)
+                .u32(name.len() as u32)
+                .u32(*typ as u32)
+                .bytes(name.as_bytes())
+                .pad_to(8);
+            if out.0.len() + dirent.0.len() > size {
+                break;
+            }
+            out = out.bytes(&dirent.0);
+        }
+        out
+    }
+
+    fn read(&mut self, offset: u64, size: usize) -> std::result::Result<Reply, Errno> {
+        let size = size.min(self.reader.len().saturating_sub(offset) as usize);
+        let mut data = vec![0; size];
+        let mut filled = 0;
+
+        let res = self.reader.seek(SeekFrom::Start(offset)).and_then(|_| {
+            while filled < size {
+                match self.reader.read(&mut data[filled..])? {
+                    0 => break,
+                    n => filled += n,
+                }
+            }
+            Ok(())
+        });
+
+        if let Err(e) = res {
+            log::error!("Read of {size} bytes at offset {offset} failed: {e}");
+            return Err(Errno::EIO);
+        }
+
+        data.truncate(filled);
+        Ok(Reply(data))
+    }
+
+    /// Handle one request; `None` means the kernel expects no reply
+    fn handle(&mut self, req: &Request) -> Option<std::result::Result<Reply, Errno>> {
+        use opcode::*;
+
+        if req.body.len() < body_len(req.opcode) {
+            log::warn!(
+                "Malformed FUSE request {} with a body of only {} bytes",
+                req.opcode,
+                req.body.len()
+            );
+            return Some(Err(Errno::EINVAL));
+        }
+
+        let res = match req.opcode {
+            INIT => {
+                let major = ne_u32(req.body, 0);
+                let minor = ne_u32(req.body, 4);
+                let max_readahead = ne_u32(req.body, 8);
+                if major != FUSE_KERNEL_VERSION {
+                    log::error!("Unsupported FUSE protocol version {major}.{minor}");
+                    return Some(Err(Errno::EPROTO));
+                }
+                Ok(Reply::default()
+                    .u32(FUSE_KERNEL_VERSION)
+                    .u32(minor.min(FUSE_KERNEL_MINOR_VERSION))
+                    .u32(max_readahead)
+                    .u32(0) // flags
+                    .u16(16) // max_background
+                    .u16(12) // congestion_threshold
+                    .u32(MAX_WRITE)
+                    .u32(1) // time_gran
+                    .u16(0) // max_pages
+                    .u16(0) // map_alignment
+                    .u32(0) // flags2
+                    .bytes(&[0; 28]))
+            }
+            LOOKUP => {
+                let name = req.body.split(|&b| b == 0).next().unwrap_or_default();
+                match req.nodeid == ROOT_INO && name == self.name.as_bytes() {
+                    true => Ok(self.entry_out(FILE_INO)),
+                    false => Err(Errno::ENOENT),
+                }
+            }
+            GETATTR => match req.nodeid {
+                ROOT_INO | FILE_INO => Ok(self.attr_out(req.nodeid)),
+                _ => Err(Errno::ENOENT),
+            },
+            OPEN | OPENDIR => {
+                let flags = ne_u32(req.body, 0) as i32;
+                if flags & libc::O_ACCMODE != libc::O_RDONLY {
+                    Err(Errno::EROFS)
+                } else {
+                    Ok(Reply::default().u64(0).u32(FOPEN_KEEP_CACHE).u32(0))
+                }
+            }
+            READ => {
+                let offset = ne_u64(req.body, 8);
+                let size = ne_u32(req.body, 16) as usize;
+                match req.nodeid {
+                    FILE_INO => self.read(offset, size),
+                    _ => Err(Errno::EISDIR),
+                }
+            }
+            READDIR => {
+                let offset = ne_u64(req.body, 8);
+                let size = ne_u32(req.body, 16) as usize;
+                Ok(self.readdir(offset, size))
+            }
+            STATFS => {
+                let blocks = self.reader.len().div_ceil(4096);
+                Ok(Reply::default()
+                    .u64(blocks)
+                    .u64(0) // bfree
+                    .u64(0) // bavail
+                    .u64(2) // files
+                    .u64(0) // ffree
+                    .u32(4096) // bsize
+                    .u32(255) // namelen
+                    .u32(4096) // frsize
+                    .bytes(&[0; 28]))
+            }
+            ACCESS | RELEASE | RELEASEDIR | FLUSH => Ok(Reply::default()),
+            FORGET | BATCH_FORGET | INTERRUPT => return None,
+            DESTROY => Ok(Reply::default()),
+            op => {
+                log::debug!("Unsupported FUSE request {op}");
+                Err(Errno::ENOSYS)
+            }
+        };
+        Some(res)
+    }
+}
+
+fn send(dev: &mut fs::File, unique: u64, res: std::result::Result<Reply, Errno>) -> Result<()> {
+    let (error, body) = match res {
+        Ok(reply) => (0, reply.0),
+        Err(errno) => (-(errno as i32), vec![]),
+    };
+    let msg = Reply::default()
+        .u32(16 + body.len() as u32)
+        .u32(error as u32)
+        .u64(unique)
+        .bytes(&body);
+
+    match dev.write(&msg.0) {
+        Ok(_) => Ok(()),
+        // The request was interrupted in the meantime
+        Err(e) if e.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+        Err(e) => Err(e).context("Failed to send reply to the FUSE device"),
+    }
+}
+
+fn mount_direct(mountpoint: &Path, opts: &MountOptions) -> Result<fs::File> {
+    use nix::mount::{mount, MsFlags};
+
+    let dev = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/fuse")
+        .context("Could not open /dev/fuse; is the fuse kernel module loaded?")?;
+
+    let mut data = format!(
+        "fd={},rootmode=40000,user_id={},group_id={},default_permissions",
+        dev.as_raw_fd(),
+        nix::unistd::getuid(),
+        nix::unistd::getgid()
+    );
+    if opts.allow_other {
+        data.push_str(",allow_other");
+    }
+
+    mount(
+        Some("splitfile"),
+        mountpoint,
+        Some("fuse.splitfile"),
+        MsFlags::MS_RDONLY | MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+        Some(data.as_str()),
+    )?;
+
+    Ok(dev)
+}
+
+/// Mount through the setuid `fusermount` helper, which passes the device back over a socket
+fn mount_fusermount(mountpoint: &Path, opts: &MountOptions) -> Result<(fs::File, String)> {
+    use nix::sys::socket::{
+        recvmsg, socketpair, AddressFamily, ControlMessageOwned, MsgFlags, SockFlag, SockType,
+    };
+    use std::process::Command;
+
+    let (ours, theirs) = socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::empty(),
+    )?;
+    nix::fcntl::fcntl(
+        ours.as_raw_fd(),
+        nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+    )?;
+
+    let mut options = "ro,nosuid,nodev,fsname=splitfile,subtype=splitfile".to_owned();
+    if opts.allow_other {
+        options.push_str(",allow_other");
+    }
+
+    let mut last_err = None;
+    for helper in ["fusermount3", "fusermount"] {
+        let status = Command::new(helper)
+            .arg("-o")
+            .arg(&options)
+            .arg("--")
+            .arg(mountpoint)
+            .env("_FUSE_COMMFD", theirs.as_raw_fd().to_string())
+            .status();
+        match status {
+            Ok(status) if status.success() => {
+                drop(theirs);
+
+                let mut byte = [0u8; 1];
+                let mut iov = [std::io::IoSliceMut::new(&mut byte)];
+                let mut cmsg = nix::cmsg_space!(std::os::fd::RawFd);
+                let msg = recvmsg::<()>(
+                    ours.as_raw_fd(),
+                    &mut iov,
+                    Some(&mut cmsg),
+                    MsgFlags::empty(),
+                )?;
+                for cmsg in msg.cmsgs() {
+                    if let ControlMessageOwned::ScmRights(fds) = cmsg {
+                        if let Some(&fd) = fds.first() {
+                            // SAFETY: The descriptor was just received and is owned by nobody else
+                            let dev = unsafe { OwnedFd::from_raw_fd(fd) };
+                            return Ok((fs::File::from(dev), helper.to_owned()));
+                        }
+                    }
+                }
+                bail!("`{helper}` did not pass back a FUSE device");
+            }
+            Ok(status) => last_err = Some(anyhow::anyhow!("`{helper}` failed: {status}")),
+            Err(e) => last_err = Some(anyhow::anyhow!("Could not run `{helper}`: {e}")),
+        }
+    }
+
+    Err(last_err.unwrap())
+}
+
+fn mount(mountpoint: &Path, opts: &MountOptions) -> Result<(fs::File, Mounted)> {
+    match mount_direct(mountpoint, opts) {
+        Ok(dev) => Ok((dev, Mounted::Direct)),
+        Err(direct) => {
+            log::debug!("Direct mount failed, trying fusermount: {direct:?}");
+            let (dev, helper) = mount_fusermount(mountpoint, opts).with_context(|| {
+                format!("Could not mount the filesystem directly ({direct}) nor through fusermount")
+            })?;
+            Ok((dev, Mounted::Fusermount(helper)))
+        }
+    }
+}
+
+fn unmount(mountpoint: &Path, how: &Mounted) {
+    let res = match how {
+        Mounted::Direct => nix::mount::umount2(mountpoint, nix::mount::MntFlags::MNT_DETACH)
+            .map_err(anyhow::Error::from),
+        Mounted::Fusermount(helper) => std::process::Command::new(helper)
+            .args([OsStr::new("-u"), OsStr::new("-z"), mountpoint.as_os_str()])
+            .status()
+            .map_err(anyhow::Error::from)
+            .and_then(|status| match status.success() {
+                true => Ok(()),
+                false => bail!("`{helper} -u` failed: {status}"),
+            }),
+    };
+    if let Err(e) = res {
+        log::debug!("Unmounting `{}` failed: {e:?}", mountpoint.display());
+    }
+}
+
+/// Mount main read-only at `mountpoint` and serve requests until it is unmounted
+pub fn serve(reader: IndexReader, mountpoint: &Path, opts: MountOptions) -> Result<()> {
+    let (mut dev, how) = mount(mountpoint, &opts)?;
+    log::info!(
+        "Mounted `{}` at `{}`. Unmount it to stop serving.",
+        opts.name,
+        mountpoint.display()
+    );
+
+    let mut fs = Filesystem {
+        reader,
+        name: opts.name,
+        uid: nix::unistd::getuid().as_raw(),
+        gid: nix::unistd::getgid().as_raw(),
+        time: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+    };
+
+    let mut buf = vec![0u8; BUFFER_SIZE];
+    let res = loop {
+        let len = match dev.read(&mut buf) {
+            Ok(len) => len,
+            Err(e) => match Errno::from_raw(e.raw_os_error().unwrap_or(0)) {
+                Errno::EINTR | Errno::EAGAIN | Errno::ENOENT => continue,
+                // Filesystem got unmounted
+                Errno::ENODEV => break Ok(()),
+                _ => break Err(e).context("Failed to read from the FUSE device"),
+            },
+        };
+        if len < 40 {
+            break Err(anyhow::anyhow!(
+                "Short read of {len} bytes from FUSE device"
+            ));
+        }
+
+        let req = Request {
+            opcode: ne_u32(&buf, 4),
+            unique: ne_u64(&buf, 8),
+            nodeid: ne_u64(&buf, 16),
+            body: &buf[40..len],
+        };
+        let destroy = req.opcode == opcode::DESTROY;
+
+        if let Some(res) = fs.handle(&req) {
+            if let Err(e) = send(&mut dev, req.unique, res) {
+                break Err(e);
+            }
+        }
+        if destroy {
+            break Ok(());
+        }
+    };
+
+    unmount(mountpoint, &how);
+    log::info!("Unmounted `{}`.", mountpoint.display());
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filesystem() -> Filesystem {
+        Filesystem {
+            reader: IndexReader::from_fragments([]),
+            name: "main".to_owned(),
+            uid: 0,
+            gid: 0,
+            time: 0,
+        }
+    }
+
+    fn request(opcode: u32, nodeid: u64, body: &[u8]) -> Option<std::result::Result<Reply, Errno>> {
+        let req = Request {
+            opcode,
+            unique: 1,
+            nodeid,
+            body,
+        };
+        filesystem().handle(&req)
+    }
+
+    #[test]
+    fn short_requests_are_refused() {
+        use opcode::*;
+
+        for (opcode, nodeid) in [
+            (INIT, 0),
+            (OPEN, FILE_INO),
+            (OPENDIR, ROOT_INO),
+            (READ, FILE_INO),
+            (READDIR, ROOT_INO),
+        ] {
+            let len = body_len(opcode);
+            let res = request(opcode, nodeid, &vec![0; len - 1]).unwrap();
+            assert_eq!(res.err(), Some(Errno::EINVAL), "opcode {opcode}");
+            assert!(request(opcode, nodeid, &[]).unwrap().is_err());
+        }
+    }
+
+    #[test]
+    fn well_formed_requests() {
+        use opcode::*;
+
+        let mut init = vec![];
+        for field in [FUSE_KERNEL_VERSION, 40, 1 << 16, 0] {
+            init.extend_from_slice(&field.to_ne_bytes());
+        }
+        let reply = request(INIT, 0, &init).unwrap().unwrap();
+        assert_eq!(ne_u32(&reply.0, 0), FUSE_KERNEL_VERSION);
+        assert_eq!(ne_u32(&reply.0, 4), FUSE_KERNEL_MINOR_VERSION);
+        init[..4].copy_from_slice(&6u32.to_ne_bytes());
+        assert_eq!(request(INIT, 0, &init).unwrap().err(), Some(Errno::EPROTO));
+
+        let open = (libc::O_RDWR as u32).to_ne_bytes();
+        assert_eq!(
+            request(OPEN, FILE_INO, &[open, [0; 4]].concat())
+                .unwrap()
+                .err(),
+            Some(Errno::EROFS)
+        );
+        assert!(request(OPEN, FILE_INO, &[0; 8]).unwrap().is_ok());
+
+        let mut read = vec![0; 40];
+        read[16..20].copy_from_slice(&4096u32.to_ne_bytes());
+        let reply = request(READ, FILE_INO, &read).unwrap().unwrap();
+        assert!(reply.0.is_empty());
+        let reply = request(READDIR, ROOT_INO, &read).unwrap().unwrap();
+        assert!(reply.0.windows(4).any(|w| w == b"main"));
+
+        assert!(request(FORGET, FILE_INO, &[]).is_none());
+        assert_eq!(request(1000, 0, &[]).unwrap().err(), Some(Errno::ENOSYS));
+    }
+}
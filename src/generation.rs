@@ -0,0 +1,450 @@
+//! Dated states of the sources that can be restored and pruned
+//!
+//! Every fragment carries a generation number. Once a group holds a complete
+//! backup of all sources, the state is recorded as a [Generation] of the
+//! highest number among the current fragments of the group. The state of a
+//! generation is made up of the fragments of its groups up to its number,
+//! where fragments of higher numbers take precedence; older fragments are
+//! kept around, marked stale, until no recorded generation needs them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write as _;
+
+use anyhow::{bail, ensure, Result};
+
+use crate::diff::fragment_key;
+use crate::index::{Fragment, Generation, HashIdentifier, Index, Slice, SourceState};
+use crate::util::utc_now;
+
+/// Length and hash of all sources as currently recorded
+pub fn source_states(idx: &Index) -> BTreeMap<String, SourceState> {
+    idx.sources()
+        .map(|source| {
+            let state = SourceState {
+                len: source.geometry.end,
+                sha3_256: source.hashes.get(&HashIdentifier::Sha3_256).cloned(),
+            };
+            (fragment_key(source), state)
+        })
+        .collect()
+}
+
+/// A generation number not used by any fragment or generation yet
+pub fn next_number(idx: &Index) -> u64 {
+    let fragments = idx
+        .fragments
+        .iter()
+        .filter(|frag| !frag.in_group("main"))
+        .map(|frag| frag.generation);
+    idx.generations
+        .iter()
+        .map(|gen| gen.no)
+        .chain(fragments)
+        .max()
+        .map_or(0, |no| no + 1)
+}
+
+/// The newest generation recording the current state of the sources, if any
+///
+/// Without hashes the state cannot be told apart from one of the same length.
+pub fn current(idx: &Index) -> Option<u64> {
+    let states = source_states(idx);
+    if states.values().any(|state| state.sha3_256.is_none()) {
+        return None;
+    }
+    idx.generations
+        .iter()
+        .filter(|gen| gen.sources == states)
+        .map(|gen| gen.no)
+        .max()
+}
+
+/// Number of the state `group` currently holds, if it holds any data
+fn group_generation(idx: &Index, group: &str) -> Option<u64> {
+    idx.fragments
+        .iter()
+        .filter(|frag| frag.in_group(group) && !frag.in_group("main") && !frag.stale)
+        .map(|frag| frag.generation)
+        .max()
+}
+
+/// Record that `group` holds a complete backup of the current state of the sources
+///
+/// Returns the number of the generation, which may have been recorded before.
+pub fn record(idx: &mut Index, group: &str) -> Option<u64> {
+    let no = group_generation(idx, group)?;
+    let states = source_states(idx);
+    match idx.generations.iter_mut().find(|gen| gen.no == no) {
+        Some(gen) => {
+            if !gen.groups.iter().any(|g| g == group) {
+                gen.groups.push(group.to_owned());
+            }
+        }
+        None => idx.generations.push(Generation {
+            no,
+            time: utc_now(),
+            groups: vec![group.to_owned()],
+            sources: states,
+        }),
+    }
+    Some(no)
+}
+
+pub fn find(idx: &Index, no: u64) -> Result<&Generation> {
+    match idx.generations.iter().find(|gen| gen.no == no) {
+        Some(gen) => Ok(gen),
+        None => bail!(
+            "Generation {no} is not recorded in the index; known generations: {}.",
+            idx.generations
+                .iter()
+                .map(|gen| gen.no.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// The `keep_last` newest generations and the newest of each of the `keep_monthly` most recent months
+pub fn retain(gens: &[Generation], keep_last: usize, keep_monthly: usize) -> BTreeSet<u64> {
+    let mut newest_first = gens.iter().collect::<Vec<_>>();
+    newest_first.sort_by_key(|gen| std::cmp::Reverse(gen.no));
+
+    let mut keep = newest_first
+        .iter()
+        .take(keep_last)
+        .map(|gen| gen.no)
+        .collect::<BTreeSet<_>>();
+    let mut months = vec![];
+    for gen in newest_first {
+        if months.len() >= keep_monthly {
+            break;
+        }
+        // `YYYY-MM` of the RFC 3339 timestamp
+        let month = gen.time.get(..7).unwrap_or(&gen.time);
+        if !months.contains(&month) {
+            months.push(month);
+            keep.insert(gen.no);
+        }
+    }
+    keep
+}
+
+/// `ranges` without the parts covered by `cut`
+fn subtract(ranges: Vec<Slice>, cut: &Slice) -> Vec<Slice> {
+    let mut out = vec![];
+    for range in ranges {
+        if cut.end <= range.start || range.end <= cut.start {
+            out.push(range);
+            continue;
+        }
+        if range.start < cut.start {
+            out.push(Slice {
+                start: range.start,
+                end: cut.start,
+            });
+        }
+        if cut.end < range.end {
+            out.push(Slice {
+                start: cut.end,
+                end: range.end,
+            });
+        }
+    }
+    out
+}
+
+/// The parts of the source a fragment holds data for
+fn held(frag: &Fragment) -> Vec<Slice> {
+    frag.holes.iter().fold(vec![frag.geometry], subtract)
+}
+
+/// Whether any data of fragment `no` is part of the state of generation `gen`
+fn visible(idx: &Index, no: usize, gen: &Generation) -> bool {
+    let frag = &idx.fragments[no];
+    if frag.generation > gen.no {
+        return false;
+    }
+    let len = match idx.source_of(frag) {
+        Ok(source) => gen
+            .sources
+            .get(&fragment_key(source.get(idx)))
+            .map(|s| s.len),
+        Err(_) => None,
+    };
+    let Some(len) = len else {
+        return false;
+    };
+
+    frag.groups
+        .iter()
+        .filter(|group| gen.groups.contains(group))
+        .any(|group| {
+            let shadowing = idx
+                .fragments
+                .iter()
+                .enumerate()
+                .filter(|(other_no, other)| {
+                    *other_no != no
+                        && other.in_group(group)
+                        && !other.in_group("main")
+                        && other.source_name() == frag.source_name()
+                        && frag.generation < other.generation
+                        && other.generation <= gen.no
+                });
+            let beyond = Slice {
+                start: len,
+                end: u64::MAX,
+            };
+            let remaining = shadowing
+                .flat_map(|(_, other)| held(other))
+                .fold(subtract(held(frag), &beyond), |ranges, cut| {
+                    subtract(ranges, &cut)
+                });
+            !remaining.is_empty()
+        })
+}
+
+/// What [prune] removed from the index
+pub struct Pruned {
+    pub generations: Vec<Generation>,
+    pub fragments: Vec<Fragment>,
+}
+
+/// Drop all generations [retain] does not keep and the fragments only they needed
+///
+/// Only fragments belonging to a recorded generation are candidates; those
+/// of backups still in progress are left alone.
+pub fn prune(idx: &mut Index, keep_last: usize, keep_monthly: usize) -> Result<Pruned> {
+    ensure!(
+        keep_last > 0 || keep_monthly > 0,
+        "Keep at least one generation with --keep-last or --keep-monthly."
+    );
+    ensure!(
+        !idx.generations.is_empty(),
+        "The index records no generations; there is nothing to prune."
+    );
+
+    let keep = retain(&idx.generations, keep_last, keep_monthly);
+    let (kept, dropped): (Vec<_>, Vec<_>) = idx
+        .generations
+        .iter()
+        .cloned()
+        .partition(|gen| keep.contains(&gen.no));
+
+    let unneeded = (0..idx.fragments.len())
+        .filter(|&no| {
+            let frag = &idx.fragments[no];
+            let settled = idx.generations.iter().any(|gen| {
+                frag.generation <= gen.no && frag.groups.iter().any(|g| gen.groups.contains(g))
+            });
+            !frag.in_group("main") && settled && !kept.iter().any(|gen| visible(idx, no, gen))
+        })
+        .collect::<BTreeSet<_>>();
+
+    let mut fragments = vec![];
+    let mut no = 0;
+    idx.fragments.retain(|frag| {
+        let keep = !unneeded.contains(&no);
+        if !keep {
+            fragments.push(frag.clone());
+        }
+        no += 1;
+        keep
+    });
+    idx.generations = kept;
+
+    Ok(Pruned {
+        generations: dropped,
+        fragments,
+    })
+}
+
+/// Human readable list of the recorded generations, oldest first
+pub fn render(idx: &Index) -> String {
+    let mut gens = idx.generations.iter().collect::<Vec<_>>();
+    gens.sort_by_key(|gen| gen.no);
+
+    let mut out = String::new();
+    for gen in gens {
+        let _ = writeln!(
+            out,
+            "Generation {} from {} in group(s) {}",
+            gen.no,
+            gen.time,
+            gen.groups.join(", ")
+        );
+        for (key, state) in gen.sources.iter() {
+            let _ = writeln!(
+                out,
+                "    {key}: {} bytes, sha3-256 {}",
+                state.len,
+                state.sha3_256.as_deref().unwrap_or("unknown")
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generation(no: u64, time: &str, len: u64) -> String {
+        format!(
+            r#"
+            [[generations]]
+            no = {no}
+            time = "{time}"
+            groups = ["backup"]
+            sources = {{ main = {{ len = {len} }} }}
+            "#
+        )
+    }
+
+    fn fragment(name: &str, start: u64, end: u64, gen: u64) -> String {
+        format!(
+            r#"
+            [[fragments]]
+            name = ["{name}"]
+            type = "File"
+            path = "/media/{name}"
+            groups = ["backup"]
+            start = {start}
+            end = {end}
+            generation = {gen}
+            "#
+        )
+    }
+
+    fn index(toml: &str) -> Index {
+        let mut idx: Index = toml::from_str(&format!(
+            r#"
+            [[fragments]]
+            name = ["main"]
+            type = "File"
+            path = "/data/main"
+            groups = ["main"]
+            start = 0
+            end = 30
+            {toml}
+            "#
+        ))
+        .unwrap();
+        idx.normalize();
+        idx
+    }
+
+    fn names(frags: &[Fragment]) -> Vec<&str> {
+        frags.iter().map(|f| f.meta.name[0].as_str()).collect()
+    }
+
+    #[test]
+    fn retained_generations() {
+        let gens = [
+            (1, "2024-01-05T00:00:00Z"),
+            (2, "2024-01-20T00:00:00Z"),
+            (3, "2024-02-03T00:00:00Z"),
+            (4, "2024-03-01T00:00:00Z"),
+            (5, "2024-03-02T00:00:00Z"),
+        ]
+        .map(|(no, time)| Generation {
+            no,
+            time: time.to_owned(),
+            groups: vec!["backup".to_owned()],
+            sources: BTreeMap::new(),
+        });
+
+        let keep = |last, monthly| retain(&gens, last, monthly).into_iter().collect::<Vec<_>>();
+        assert_eq!(keep(2, 0), [4, 5]);
+        assert_eq!(keep(0, 2), [3, 5]);
+        assert_eq!(keep(1, 3), [2, 3, 5]);
+        assert_eq!(keep(10, 10), [1, 2, 3, 4, 5]);
+        assert!(keep(0, 0).is_empty());
+    }
+
+    #[test]
+    fn held_ranges() {
+        let s = |start, end| Slice { start, end };
+        assert_eq!(subtract(vec![s(0, 10)], &s(3, 5)), [s(0, 3), s(5, 10)]);
+        assert_eq!(subtract(vec![s(0, 10)], &s(0, 10)), []);
+        assert_eq!(
+            subtract(vec![s(0, 5), s(8, 10)], &s(4, 9)),
+            [s(0, 4), s(9, 10)]
+        );
+        assert_eq!(subtract(vec![s(0, 5)], &s(5, 9)), [s(0, 5)]);
+
+        let idx = index(
+            r#"
+            [[fragments]]
+            name = ["holey"]
+            type = "File"
+            path = "/media/holey"
+            groups = ["backup"]
+            start = 0
+            end = 30
+            holes = [{ start = 10, end = 20 }]
+            "#,
+        );
+        assert_eq!(held(&idx.fragments[1]), [s(0, 10), s(20, 30)]);
+    }
+
+    #[test]
+    fn prune_keeps_what_retained_generations_show() {
+        let mut idx = index(
+            &[
+                generation(1, "2024-01-01T00:00:00Z", 40),
+                generation(2, "2024-02-01T00:00:00Z", 30),
+                generation(3, "2024-03-01T00:00:00Z", 30),
+                fragment("base", 0, 30, 1),
+                fragment("tail", 30, 40, 1),
+                fragment("delta2", 10, 20, 2),
+                fragment("delta3", 5, 25, 3),
+                fragment("unfinished", 0, 10, 4),
+            ]
+            .concat(),
+        );
+        assert_eq!(next_number(&idx), 5);
+
+        let gen3 = find(&idx, 3).unwrap().clone();
+        let visible_in_3 = (0..idx.fragments.len())
+            .filter(|&no| visible(&idx, no, &gen3))
+            .map(|no| idx.fragments[no].meta.name[0].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(visible_in_3, ["base", "delta3"]);
+        assert!(find(&idx, 7).is_err());
+
+        let pruned = prune(&mut idx, 1, 0).unwrap();
+        assert_eq!(
+            pruned.generations.iter().map(|g| g.no).collect::<Vec<_>>(),
+            [1, 2]
+        );
+        assert_eq!(names(&pruned.fragments), ["tail", "delta2"]);
+        assert_eq!(
+            names(&idx.fragments),
+            ["main", "base", "delta3", "unfinished"]
+        );
+
+        assert!(prune(&mut idx, 0, 0).is_err());
+        idx.generations.clear();
+        assert!(prune(&mut idx, 1, 0).is_err());
+    }
+
+    #[test]
+    fn record_and_current() {
+        let mut idx = index(&fragment("base", 0, 30, 1));
+        assert_eq!(current(&idx), None);
+        assert_eq!(record(&mut idx, "backup"), Some(1));
+        assert_eq!(record(&mut idx, "other"), None);
+        // Without a hash of the source, the state cannot be recognized
+        assert_eq!(current(&idx), None);
+
+        let mut idx = index(&fragment("base", 0, 30, 1));
+        idx.fragments[0]
+            .hashes
+            .insert(HashIdentifier::Sha3_256, "abc".to_owned());
+        assert_eq!(record(&mut idx, "backup"), Some(1));
+        assert_eq!(current(&idx), Some(1));
+        assert_eq!(idx.generations[0].sources["main"].len, 30);
+        assert!(render(&idx).contains("main: 30 bytes, sha3-256 abc"));
+    }
+}
@@ -0,0 +1,405 @@
+//! Serve a source and the individual fragments over HTTP with range support
+//!
+//! Endpoints:
+//!
+//! - `/main` – the served source, main by default, reassembled from the fragments of the served group
+//! - `/fragments/<name>` – the data of a single fragment
+//! - `/index.json` – the index together with the coverage of the served group
+
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+
+use crate::index::{HashIdentifier, Index, Offset, Slice};
+use crate::reader::IndexReader;
+
+pub struct Server {
+    pub index: Index,
+    pub group: String,
+    /// Source served at `/main`
+    pub source: String,
+}
+
+#[derive(Serialize)]
+struct Coverage<'a> {
+    group: &'a str,
+    len: Offset,
+    covered: Vec<Slice>,
+    missing: Vec<Slice>,
+}
+
+#[derive(Serialize)]
+struct IndexDescription<'a> {
+    index: &'a Index,
+    coverage: Coverage<'a>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    range: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, String)>,
+    body: Body,
+}
+
+enum Body {
+    Bytes(Vec<u8>),
+    Data {
+        reader: Box<IndexReader>,
+        range: Slice,
+    },
+}
+
+impl Response {
+    fn text(status: u16, reason: &'static str, text: String) -> Self {
+        Self {
+            status,
+            reason,
+            headers: vec![("Content-Type", "text/plain; charset=utf-8".to_owned())],
+            body: Body::Bytes(text.into_bytes()),
+        }
+    }
+
+    fn not_found() -> Self {
+        Self::text(404, "Not Found", "Not found\n".to_owned())
+    }
+}
+
+/// Parse a `Range` header into a slice of `0..len`; `Ok(None)` means the header should be ignored
+fn parse_range(header: &str, len: Offset) -> std::result::Result<Option<Slice>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    // Multiple ranges would need a multipart response; the header may legally be ignored
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let (start, end) = spec.split_once('-').ok_or(())?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        let suffix: Offset = end.parse().map_err(|_| ())?;
+        Slice {
+            start: len.saturating_sub(suffix),
+            end: len,
+        }
+    } else {
+        let start: Offset = start.parse().map_err(|_| ())?;
+        let end = match end {
+            "" => len,
+            end => end
+                .parse::<Offset>()
+                .map_err(|_| ())?
+                .saturating_add(1)
+                .min(len),
+        };
+        Slice { start, end }
+    };
+
+    match range.start < range.end {
+        true => Ok(Some(range)),
+        false => Err(()),
+    }
+}
+
+impl Server {
+    fn coverage(&self) -> Result<Coverage<'_>> {
+        let reader = IndexReader::for_source(&self.index, &self.source, &self.group)?;
+        let all = Slice {
+            start: 0,
+            end: reader.len(),
+        };
+        let missing = reader.gaps(all);
+
+        let mut covered = vec![];
+        let mut pos = 0;
+        for gap in missing.iter().chain([&Slice {
+            start: all.end,
+            end: all.end,
+        }]) {
+            if pos < gap.start {
+                covered.push(Slice {
+                    start: pos,
+                    end: gap.start,
+                });
+            }
+            pos = gap.end;
+        }
+
+        Ok(Coverage {
+            group: &self.group,
+            len: all.end,
+            covered,
+            missing,
+        })
+    }
+
+    /// Respond with (a range of) `reader`, whose data starts at `base`
+    fn data(
+        &self,
+        req: &Request,
+        reader: IndexReader,
+        base: Offset,
+        len: Offset,
+        etag: Option<&String>,
+    ) -> Response {
+        let mut headers = vec![
+            ("Accept-Ranges", "bytes".to_owned()),
+            ("Content-Type", "application/octet-stream".to_owned()),
+        ];
+        if let Some(etag) = etag {
+            headers.push(("ETag", format!("\"{etag}\"")));
+        }
+
+        let range = match req.range.as_deref().map(|r| parse_range(r, len)) {
+            None | Some(Ok(None)) => None,
+            Some(Ok(Some(range))) => Some(range),
+            Some(Err(())) => {
+                let mut res = Response::text(
+                    416,
+                    "Range Not Satisfiable",
+                    "Range not satisfiable\n".to_owned(),
+                );
+                res.headers
+                    .push(("Content-Range", format!("bytes */{len}")));
+                return res;
+            }
+        };
+
+        let (status, reason) = match range {
+            Some(range) => {
+                headers.push((
+                    "Content-Range",
+                    format!("bytes {}-{}/{len}", range.start, range.end - 1),
+                ));
+                (206, "Partial Content")
+            }
+            None => (200, "OK"),
+        };
+        let range = range.unwrap_or(Slice { start: 0, end: len });
+        let range = Slice {
+            start: base + range.start,
+            end: base + range.end,
+        };
+
+        let gaps = reader.gaps(range);
+        if !gaps.is_empty() {
+            let gaps = gaps
+                .iter()
+                .map(|g| format!("{}..{}", g.start - base, g.end - base))
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Response::text(
+                503,
+                "Service Unavailable",
+                format!("The requested range is not covered by any fragment: {gaps}\n"),
+            );
+        }
+
+        Response {
+            status,
+            reason,
+            headers,
+            body: Body::Data {
+                reader: Box::new(reader),
+                range,
+            },
+        }
+    }
+
+    fn route(&self, req: &Request) -> Result<Response> {
+        if req.method != "GET" && req.method != "HEAD" {
+            let mut res =
+                Response::text(405, "Method Not Allowed", "Method not allowed\n".to_owned());
+            res.headers.push(("Allow", "GET, HEAD".to_owned()));
+            return Ok(res);
+        }
+
+        let path = req.path.split('?').next().unwrap_or_default();
+        match path.trim_end_matches('/') {
+            "" => Ok(Response::text(
+                200,
+                "OK",
+                format!(
+                    "splitfile serving group `{}`\n\n/main\n/index.json\n{}",
+                    self.group,
+                    self.index
+                        .fragments
+                        .iter()
+                        .filter_map(|frag| frag.meta.name.first())
+                        .map(|name| format!("/fragments/{name}\n"))
+                        .collect::<String>()
+                ),
+            )),
+            "/index.json" => {
+                let desc = IndexDescription {
+                    index: &self.index,
+                    coverage: self.coverage()?,
+                };
+                let mut body = serde_json::to_vec_pretty(&desc)?;
+                body.push(b'\n');
+                Ok(Response {
+                    status: 200,
+                    reason: "OK",
+                    headers: vec![("Content-Type", "application/json".to_owned())],
+                    body: Body::Bytes(body),
+                })
+            }
+            "/main" => {
+                let reader = IndexReader::for_source(&self.index, &self.source, &self.group)?;
+                let source = self.index.get_fragment_by_name(&self.source)?;
+                let hash = source
+                    .get(&self.index)
+                    .hashes
+                    .get(&HashIdentifier::Sha3_256);
+                let len = reader.len();
+                Ok(self.data(req, reader, 0, len, hash))
+            }
+            path => {
+                let Some(name) = path.strip_prefix("/fragments/") else {
+                    return Ok(Response::not_found());
+                };
+                let Ok(frag) = self.index.get_fragment_by_name(name) else {
+                    return Ok(Response::not_found());
+                };
+                let frag = frag.get(&self.index);
+                let reader = IndexReader::from_fragments([frag]);
+                let hash = frag.hashes.get(&HashIdentifier::Sha3_256);
+                Ok(self.data(req, reader, frag.geometry.start, frag.geometry.len(), hash))
+            }
+        }
+    }
+
+    fn write_response(stream: &mut TcpStream, req: &Request, res: Response) -> Result<()> {
+        let len = match &res.body {
+            Body::Bytes(bytes) => bytes.len() as Offset,
+            Body::Data { range, .. } => range.len(),
+        };
+
+        let mut head = format!("HTTP/1.1 {} {}\r\n", res.status, res.reason);
+        for (key, value) in res.headers.iter() {
+            head.push_str(&format!("{key}: {value}\r\n"));
+        }
+        head.push_str(&format!(
+            "Content-Length: {len}\r\nConnection: close\r\n\r\n"
+        ));
+        stream.write_all(head.as_bytes())?;
+
+        if req.method == "HEAD" {
+            return Ok(());
+        }
+
+        match res.body {
+            Body::Bytes(bytes) => stream.write_all(&bytes)?,
+            Body::Data { mut reader, range } => {
+                reader.seek(SeekFrom::Start(range.start))?;
+                let copied = io::copy(&mut reader.take(range.len()), stream)?;
+                anyhow::ensure!(copied == range.len(), "Short read from fragments");
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&self, mut stream: TcpStream) -> Result<()> {
+        let mut lines = BufReader::new(stream.try_clone()?);
+
+        let mut line = String::new();
+        lines.read_line(&mut line)?;
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Ok(());
+        };
+        let mut req = Request {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            range: None,
+        };
+
+        loop {
+            line.clear();
+            if lines.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                if key.trim().eq_ignore_ascii_case("range") {
+                    req.range = Some(value.trim().to_owned());
+                }
+            }
+        }
+
+        let res = self.route(&req).unwrap_or_else(|e| {
+            log::error!("Request for `{}` failed: {e:?}", req.path);
+            Response::text(500, "Internal Server Error", format!("{e}\n"))
+        });
+        log::info!("{} {} {}", req.method, req.path, res.status);
+        Self::write_response(&mut stream, &req, res)
+    }
+}
+
+/// Serve HTTP requests on `addr` until the process is terminated
+pub fn serve(server: Server, addr: &str) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Could not listen on `{addr}`"))?;
+    log::info!(
+        "Serving group `{}` on http://{}/",
+        server.group,
+        listener.local_addr()?
+    );
+
+    let server = Arc::new(server);
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Failed to accept connection: {e}");
+                continue;
+            }
+        };
+        let server = server.clone();
+        std::thread::spawn(move || {
+            if let Err(e) = server.handle(stream) {
+                log::debug!("Connection ended with an error: {e:?}");
+            }
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(header: &str) -> std::result::Result<Option<(Offset, Offset)>, ()> {
+        parse_range(header, 100).map(|r| r.map(|r| (r.start, r.end)))
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range("bytes=0-9"), Ok(Some((0, 10))));
+        assert_eq!(range(" bytes=10- "), Ok(Some((10, 100))));
+        assert_eq!(range("bytes=90-200"), Ok(Some((90, 100))));
+        assert_eq!(range("bytes=-30"), Ok(Some((70, 100))));
+        assert_eq!(range("bytes=-200"), Ok(Some((0, 100))));
+
+        // Ignored rather than refused
+        assert_eq!(range("items=0-9"), Ok(None));
+        assert_eq!(range("bytes=0-9,20-29"), Ok(None));
+
+        // Unsatisfiable or malformed
+        assert_eq!(range("bytes=100-"), Err(()));
+        assert_eq!(range("bytes=10-5"), Err(()));
+        assert_eq!(range("bytes=-0"), Err(()));
+        assert_eq!(range("bytes=5"), Err(()));
+        assert_eq!(range("bytes=a-b"), Err(()));
+        assert_eq!(range("bytes=-"), Err(()));
+    }
+}
@@ -0,0 +1,219 @@
+//! Import archives split into parts by other tools
+//!
+//! Covers coreutils `split` (`xaa`, `xab`, …, or numeric suffixes),
+//! 7-Zip style volumes (`.001`, `.002`, …) and `.partN` files: the parts
+//! are concatenated in natural order to form main.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{ensure, Context, Result};
+
+use crate::copy::hash_data;
+use crate::index::{File, Fragment, HashIdentifier, Index, Meta, Offset, Slice};
+use crate::util::{pretty_path, uuidgen};
+
+#[derive(PartialEq, Eq, PartialOrd, Ord, Debug)]
+enum Chunk {
+    Num(u64),
+    Text(String),
+}
+
+/// Sort key comparing runs of digits by their numeric value, so `part10` sorts after `part9`
+fn natural_key(name: &str) -> Vec<Chunk> {
+    let mut key = vec![];
+    let mut rest = name;
+    while let Some(c) = rest.chars().next() {
+        let digit = c.is_ascii_digit();
+        let len = rest
+            .find(|c: char| c.is_ascii_digit() != digit)
+            .unwrap_or(rest.len());
+        let (run, tail) = rest.split_at(len);
+        key.push(match digit {
+            true => run
+                .parse()
+                .map(Chunk::Num)
+                .unwrap_or(Chunk::Text(run.to_owned())),
+            false => Chunk::Text(run.to_owned()),
+        });
+        rest = tail;
+    }
+    key
+}
+
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    natural_key(a).cmp(&natural_key(b)).then_with(|| a.cmp(b))
+}
+
+/// Match `name` against a pattern supporting `*` and `?`
+fn wildcard_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            wildcard_match(&pattern[1..], name)
+                || (!name.is_empty() && wildcard_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => wildcard_match(&pattern[1..], &name[1..]),
+        (Some(p), Some(n)) if p == n => wildcard_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Expand a path whose file name may contain the wildcards `*` and `?`
+fn expand(arg: &str) -> Result<Vec<String>> {
+    let path = Path::new(arg);
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    if !name.contains(['*', '?']) {
+        return Ok(vec![arg.to_owned()]);
+    }
+
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let pattern = name.chars().collect::<Vec<_>>();
+    let mut matches = vec![];
+    for entry in fs::read_dir(dir).with_context(|| format!("Could not list `{}`", dir.display()))? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if entry.file_type()?.is_file()
+            && wildcard_match(&pattern, &file_name.chars().collect::<Vec<_>>())
+        {
+            matches.push(pretty_path(dir.join(file_name)));
+        }
+    }
+    ensure!(!matches.is_empty(), "No files match `{arg}`.");
+    Ok(matches)
+}
+
+/// Expand wildcards and order the parts the way they were split
+pub fn collect_parts(args: &[String]) -> Result<Vec<String>> {
+    let mut parts = vec![];
+    for arg in args {
+        parts.extend(expand(arg)?);
+    }
+    parts.sort_by(|a, b| natural_cmp(a, b));
+    parts.dedup();
+    Ok(parts)
+}
+
+/// Outcome of an import
+pub struct Imported {
+    pub index: Index,
+    /// Number of bytes of main not covered by the parts
+    pub missing: Offset,
+}
+
+/// Add one fragment per part to `idx`, or to a new index if there is none
+pub fn import(
+    idx: Option<Index>,
+    parts: &[String],
+    main_size: Option<Offset>,
+    group: &str,
+    with_hash: bool,
+) -> Result<Imported> {
+    let mut lens = vec![];
+    for part in parts {
+        let len = fs::File::open(part)
+            .and_then(|mut f| f.seek(SeekFrom::End(0)))
+            .with_context(|| format!("Could not open `{part}`"))?;
+        lens.push(len);
+    }
+    let total: Offset = lens.iter().sum();
+
+    let mut idx = match idx {
+        Some(idx) => {
+            let len = idx.get_fragment_by_name("main")?.get(&idx).geometry.end;
+            ensure!(
+                main_size.is_none_or(|size| size == len),
+                "The index describes a main file of {len} bytes, not {}.",
+                main_size.unwrap()
+            );
+            idx
+        }
+        None => {
+            let len = main_size.unwrap_or(total);
+            Index {
+                meta: Meta {
+                    name: vec![uuidgen()],
+                    comment: vec![format!("Imported from {} split parts", parts.len())],
+                },
+                fragments: vec![Fragment {
+                    meta: Meta {
+                        name: vec!["main".to_owned(), uuidgen()],
+                        comment: vec![
+                            "Location of the main file is unknown; imported from split parts"
+                                .to_owned(),
+                        ],
+                    },
+                    groups: vec!["main".to_owned()],
+                    stale: false,
+                    generation: 0,
+                    source: None,
+                    location: File::default().as_location(),
+                    hashes: HashMap::new(),
+                    geometry: Slice { start: 0, end: len },
+                    holes: vec![],
+                    stat: None,
+                    blocks: None,
+                    extra: Default::default(),
+                }],
+                ..Default::default()
+            }
+        }
+    };
+    let main_len = idx.get_fragment_by_name("main")?.get(&idx).geometry.end;
+    ensure!(
+        total <= main_len,
+        "The parts hold {total} bytes, more than the {main_len} bytes of main."
+    );
+
+    let mut offset = 0;
+    for (part, len) in parts.iter().zip(lens) {
+        let canonical = pretty_path(fs::canonicalize(part)?);
+        let mut hashes = HashMap::new();
+        if with_hash {
+            log::info!("Hashing `{part}`…");
+            hashes.insert(HashIdentifier::Sha3_256, hash_data(fs::File::open(part)?)?);
+        }
+        idx.fragments.push(Fragment {
+            meta: Meta {
+                name: vec![uuidgen()],
+                comment: vec![
+                    format!("Relative path during import: {part}"),
+                    format!("Canonical path during import: {canonical}"),
+                ],
+            },
+            location: File {
+                device: None,
+                path: canonical,
+            }
+            .as_location(),
+            groups: vec![group.to_owned()],
+            stale: false,
+            generation: 0,
+            source: None,
+            hashes,
+            geometry: Slice {
+                start: offset,
+                end: offset + len,
+            },
+            holes: vec![],
+            stat: None,
+            blocks: None,
+            extra: Default::default(),
+        });
+        offset += len;
+    }
+
+    Ok(Imported {
+        index: idx,
+        missing: main_len - total,
+    })
+}
@@ -1,10 +1,47 @@
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use anyhow::{bail, Context, Result};
 
 pub type Offset = u64;
 
+/// Keys this version does not know about; kept so rewriting the index does not drop them
+///
+/// TOML has no null, so null values, also within arrays and tables, are dropped
+/// when reading an index in any format; see [deserialize_extra].
+pub type Extra = BTreeMap<String, toml::Value>;
+
+/// Read [Extra] from any format, dropping null values
+///
+/// The values pass through [serde_json::Value], which holds nulls as well as
+/// the tables TOML dates are represented as in other formats.
+fn deserialize_extra<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Extra, D::Error> {
+    fn without_nulls(value: serde_json::Value) -> Option<serde_json::Value> {
+        use serde_json::Value;
+        match value {
+            Value::Null => None,
+            Value::Array(items) => Some(Value::Array(
+                items.into_iter().filter_map(without_nulls).collect(),
+            )),
+            Value::Object(map) => Some(Value::Object(
+                map.into_iter()
+                    .filter_map(|(key, value)| Some((key, without_nulls(value)?)))
+                    .collect(),
+            )),
+            value => Some(value),
+        }
+    }
+
+    BTreeMap::<String, serde_json::Value>::deserialize(de)?
+        .into_iter()
+        .filter_map(|(key, value)| Some((key, without_nulls(value)?)))
+        .map(|(key, value)| {
+            let value = toml::Value::deserialize(value).map_err(serde::de::Error::custom)?;
+            Ok((key, value))
+        })
+        .collect()
+}
+
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
 pub struct Meta {
     #[serde(default)]
@@ -162,6 +199,15 @@ pub struct URI {
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
 pub struct ThisBuffer;
 
+/// Data kept as chunks in a content addressed store; see [crate::chunks]
+#[derive(Clone, Default, Serialize, Deserialize, Debug)]
+pub struct ChunkStore {
+    /// Directory of the store
+    pub store: String,
+    /// Hash of the manifest listing the chunks of the fragment
+    pub manifest: String,
+}
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
 pub enum LocationData {
@@ -169,6 +215,7 @@ pub enum LocationData {
     Device(Device),
     File(File),
     URI(URI),
+    ChunkStore(ChunkStore),
 }
 
 impl LocationData {
@@ -211,6 +258,20 @@ pub struct Fragment {
     pub location: Location,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub groups: Vec<String>,
+    /// Set once the source changed after this fragment was written; its data is outdated
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_false")]
+    pub stale: bool,
+    /// Incremental backups add fragments of a higher generation, which take precedence
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_zero")]
+    pub generation: u64,
+    /// Name of the source whose data this fragment holds; the fragment named `main` if unset
+    ///
+    /// Sources themselves, the fragments in group `main`, hold their own data.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
     #[serde(default)]
     #[serde(skip_serializing_if = "HashMap::is_empty")]
     pub hashes: HashMap<HashIdentifier, String>,
@@ -219,19 +280,154 @@ pub struct Fragment {
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub holes: Vec<Slice>,
+    /// State of the file when it was last recorded; only kept for sources
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stat: Option<Stat>,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocks: Option<BlockHashes>,
+    #[serde(flatten, deserialize_with = "deserialize_extra")]
+    pub extra: Extra,
+}
+
+/// Hashes of the blocks of a fragment; see [crate::blocks]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct BlockHashes {
+    /// Blocks start at multiples of the size in the source, so the first and last may be partial
+    pub size: Offset,
+    pub sha3_256: Vec<String>,
+}
+
+/// Identity and state of a file, used to notice when a source changed
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct Stat {
+    pub len: Offset,
+    /// Modification time in nanoseconds since the epoch
+    pub mtime: i64,
+    /// Status change time in nanoseconds since the epoch; unlike the mtime it cannot be reset
+    pub ctime: i64,
+    pub device: u64,
+    pub inode: u64,
 }
 
 pub struct FragmentPtr {
     no: usize,
 }
 
+/// Version of the layout of an index file
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct SchemaVersion(pub u32);
+
+impl SchemaVersion {
+    /// Version of indices written before the version was recorded
+    pub const LEGACY: Self = Self(1);
+    pub const CURRENT: Self = Self(6);
+
+    fn legacy() -> Self {
+        Self::LEGACY
+    }
+}
+
+impl Default for SchemaVersion {
+    fn default() -> Self {
+        Self::CURRENT
+    }
+}
+
 #[derive(Clone, Default, Serialize, Deserialize, Debug)]
 pub struct Index {
+    /// Layout of this index; see [crate::migrate]
+    #[serde(default = "SchemaVersion::legacy")]
+    pub version: SchemaVersion,
     #[serde(flatten)]
     pub meta: Meta,
+    /// Incremented every time the index is saved; the highest revision is the newest
+    #[serde(default)]
+    #[serde(skip_serializing_if = "is_zero")]
+    pub revision: u64,
+    /// Copies of this index stored on the backup media
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub replicas: Vec<String>,
     #[serde(default)]
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub fragments: Vec<Fragment>,
+    /// Dated states of the sources that can be restored; see [crate::generation]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub generations: Vec<Generation>,
+    #[serde(flatten, deserialize_with = "deserialize_extra")]
+    pub extra: Extra,
+}
+
+/// A completed backup of the sources into one or more groups
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Generation {
+    /// The fragments of its groups up to this generation make up the state
+    pub no: u64,
+    /// When the backup was completed, in RFC 3339 format and UTC
+    pub time: String,
+    pub groups: Vec<String>,
+    /// State of each source, by its name or UUID
+    pub sources: BTreeMap<String, SourceState>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct SourceState {
+    pub len: Offset,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sha3_256: Option<String>,
+}
+
+fn is_zero(v: &u64) -> bool {
+    *v == 0
+}
+
+fn is_false(v: &bool) -> bool {
+    !*v
+}
+
+impl Stat {
+    pub fn of(path: &str) -> Result<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut file =
+            std::fs::File::open(path).with_context(|| format!("Could not open `{path}`"))?;
+        let meta = file.metadata()?;
+        // Block devices report a size of zero through metadata
+        let len = std::io::Seek::seek(&mut file, std::io::SeekFrom::End(0))?;
+        Ok(Self {
+            len,
+            mtime: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+            ctime: meta.ctime() * 1_000_000_000 + meta.ctime_nsec(),
+            device: meta.dev(),
+            inode: meta.ino(),
+        })
+    }
+
+    /// Human readable list of the ways `now` differs from `self`
+    pub fn changes(&self, now: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        if self.len != now.len {
+            changes.push(format!("size {} -> {}", self.len, now.len));
+        }
+        if self.mtime != now.mtime {
+            changes.push("modification time".to_owned());
+        }
+        if self.ctime != now.ctime {
+            changes.push("change time".to_owned());
+        }
+        if (self.device, self.inode) != (now.device, now.inode) {
+            changes.push(format!(
+                "inode {}:{} -> {}:{}",
+                self.device, self.inode, now.device, now.inode
+            ));
+        }
+        changes
+    }
 }
 
 impl Slice {
@@ -245,15 +441,49 @@ impl Slice {
 }
 
 impl Index {
+    /// Load an index file in any supported format; `Ok(None)` if it does not exist
+    pub fn from_file(path: &str) -> Result<Option<Self>> {
+        Ok(crate::format::read(path)?.map(|(idx, _)| idx))
+    }
+
+    /// Drop keys from the catch-all maps that were also read into regular fields
+    ///
+    /// Serde hands flattened enums such as [LocationData] all keys of the
+    /// fragment, so these end up in [Fragment::extra] as well. Must be called
+    /// after deserializing, lest they are written twice.
+    pub fn normalize(&mut self) {
+        self.fragments.iter_mut().for_each(Fragment::normalize);
+    }
+
+    /// UUID identifying the index, generated on demand for indices that have none
+    pub fn ensure_uuid(&mut self) -> String {
+        if let Some(uuid) = self.meta.uuid() {
+            return uuid.to_owned();
+        }
+        let uuid = uuid::Uuid::new_v4().to_string();
+        self.meta.name.push(uuid.clone());
+        uuid
+    }
+
+    /// The files backed up by this index: the fragments in group `main`, in backup order
+    pub fn sources(&self) -> impl Iterator<Item = &Fragment> {
+        self.fragments.iter().filter(|frag| frag.in_group("main"))
+    }
+
+    /// The source whose data `frag` holds
+    pub fn source_of(&self, frag: &Fragment) -> Result<FragmentPtr> {
+        self.get_fragment_by_name(frag.source_name())
+    }
+
     pub fn get_fragment_by_name(&self, name: &str) -> Result<FragmentPtr> {
         self.fragments
             .iter()
             .enumerate()
-            .fold(Ok(None), |state, (idx, frag)| -> Result<Option<usize>> {
-                match (frag.is_named(name), &state) {
-                    (false, _) | (_, Err(_)) => state, // nop: regular search | error propagation
-                    (true, Ok(Some(_))) => bail!("Found two fragments named `{}` in index.", name),
-                    (true, Ok(None)) => Ok(Some(idx)), // found!
+            .try_fold(None, |state, (idx, frag)| -> Result<Option<usize>> {
+                match (frag.is_named(name), state) {
+                    (false, _) => Ok(state), // nop: regular search
+                    (true, Some(_)) => bail!("Found two fragments named `{}` in index.", name),
+                    (true, None) => Ok(Some(idx)), // found!
                 }
             })?
             .with_context(|| format!("No such fragment `{name}`."))
@@ -265,19 +495,72 @@ impl Meta {
     pub fn is_named(&self, name: &str) -> bool {
         self.name.iter().any(|n| *n == name)
     }
+
+    /// The first name that is a UUID
+    pub fn uuid(&self) -> Option<&String> {
+        self.name.iter().find(|n| uuid::Uuid::parse_str(n).is_ok())
+    }
 }
 
 impl Fragment {
+    /// Path of the plain file holding the data; `None` for other kinds of locations
+    pub fn plain_file(&self) -> Option<&str> {
+        match &self.location.data {
+            LocationData::File(File { device: None, path }) => Some(path),
+            _ => None,
+        }
+    }
+
     pub fn filepath(&self) -> &String {
         match &self.location {
             Location {
-                slice: None,
                 data: LocationData::File(File { device: None, path }),
+                ..
             } => path,
             _ => todo!("Not implemented: file_location() for Fragment format: {self:?}"),
         }
     }
 
+    /// See [Index::normalize]
+    pub fn normalize(&mut self) {
+        if self.extra.is_empty() {
+            return;
+        }
+        let extra = std::mem::take(&mut self.extra);
+        let known = match toml::Value::try_from(&*self) {
+            Ok(toml::Value::Table(known)) => known,
+            _ => toml::Table::new(),
+        };
+        self.extra = extra
+            .into_iter()
+            .filter(|(key, _)| !known.contains_key(key))
+            .collect();
+    }
+
+    /// Offset in the file at which the fragment data starts
+    pub fn data_offset(&self) -> Offset {
+        self.location.slice.map(|s| s.start).unwrap_or(0)
+    }
+
+    /// Name of the source whose data this fragment holds; see [Fragment::source]
+    pub fn source_name(&self) -> &str {
+        match (&self.source, self.in_group("main")) {
+            (Some(source), _) => source,
+            // Sources hold their own data
+            (None, true) => self
+                .meta
+                .uuid()
+                .or(self.meta.name.first())
+                .map_or("main", String::as_str),
+            (None, false) => "main",
+        }
+    }
+
+    /// Whether this fragment holds data of the source `source`
+    pub fn carries(&self, source: &Fragment) -> bool {
+        source.is_named(self.source_name())
+    }
+
     pub fn in_group(&self, group: &str) -> bool {
         self.groups.iter().any(|g| *g == group)
     }
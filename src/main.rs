@@ -1,24 +1,55 @@
 use std::collections::HashMap;
 use std::fs;
-use std::io::{Seek, SeekFrom};
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::process::{exit, ExitCode};
 
 use anyhow::{bail, ensure, Context, Result};
 use clap::{Args, Parser, Subcommand};
 use indicatif::ProgressBar;
 
+use crate::cache::{CacheMode, CacheWriter};
 use crate::copy::{copy_and_optionally_hash, hash_data};
 use crate::index::Index;
-use crate::util::{pretty_path, try_read_to_string, uuidgen, NullBuffer, TruncateReadStream};
-
+use crate::reader::IndexReader;
+use crate::util::{
+    absolute_path, parse_size, pretty_path, try_read_to_string, try_write_all, uuidgen,
+    write_atomic, NullBuffer,
+};
+
+pub(crate) mod blocks;
+pub(crate) mod cache;
+pub(crate) mod check;
+pub(crate) mod chunks;
+pub mod container;
 pub(crate) mod copy;
+pub(crate) mod diff;
+pub(crate) mod edit;
+pub(crate) mod format;
+pub(crate) mod fuse;
+pub(crate) mod generation;
+pub(crate) mod http;
+pub(crate) mod import;
 pub mod index;
+pub(crate) mod migrate;
+pub(crate) mod nbd;
+pub(crate) mod preserve;
+pub mod reader;
+pub(crate) mod rebuild;
+pub(crate) mod replica;
+pub(crate) mod script;
+pub(crate) mod throttle;
+#[cfg(feature = "io-uring")]
+pub(crate) mod uring;
 pub(crate) mod util;
 
 #[derive(Clone, Args, Debug)]
 struct CreateCommand {
-    #[arg(short, long)]
-    pub path: String,
+    /// Files to back up; directories are added with all files below them
+    ///
+    /// A single file becomes the source `main`; otherwise each file becomes a
+    /// source named by its path.
+    #[arg(short, long, required = true, num_args = 1..)]
+    pub path: Vec<String>,
 
     #[arg(short, long)]
     pub name: Option<String>,
@@ -29,14 +60,77 @@ struct CreateCommand {
 
 #[derive(Clone, Args, Debug)]
 struct WriteBackupCommand {
-    #[arg(short = 'd', long = "dest")]
-    pub destination: String,
+    #[arg(short = 'd', long = "dest", required_unless_present = "store")]
+    pub destination: Option<String>,
 
     #[arg(short = 'g', long, default_value = "backup")]
     pub backup_group: String,
 
     #[arg(long)]
     pub no_hash: bool,
+
+    /// Write a self-describing fragment with a header identifying its content
+    #[arg(long)]
+    pub container: bool,
+
+    /// Do not keep a copy of the index next to the new fragment
+    #[arg(long)]
+    pub no_replicate_index: bool,
+
+    /// Back up sources that changed since they were recorded instead of refusing
+    #[arg(long)]
+    pub force: bool,
+
+    /// Only write the blocks that differ from the newest generation of the base group
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// Group to update incrementally, which the new generation is added to; defaults to --backup-group
+    #[arg(long, requires = "incremental")]
+    pub base_group: Option<String>,
+
+    /// Record hashes of blocks of this size, which later incremental backups compare against
+    #[arg(long, value_parser = blocks::parse_block_size)]
+    pub block_size: Option<index::Offset>,
+
+    /// Write to a content addressed chunk store, adding only the chunks it does not have yet
+    #[arg(
+        long,
+        conflicts_with_all = ["destination", "container", "incremental", "block_size"]
+    )]
+    pub store: Option<String>,
+
+    /// Average size of the chunks the data is cut into for --store
+    #[arg(long, requires = "store", value_parser = parse_size)]
+    pub chunk_size: Option<index::Offset>,
+
+    /// Always copy through userspace instead of letting the kernel reflink or copy the data
+    #[arg(long)]
+    pub no_fast_copy: bool,
+
+    /// Bypass the page cache with O_DIRECT; sources are packed at sector aligned offsets
+    #[arg(long, conflicts_with_all = ["drop_cache", "incremental", "store"])]
+    pub direct: bool,
+
+    /// Write data back as it is copied and drop it from the page cache
+    #[arg(long, conflicts_with_all = ["incremental", "store"])]
+    pub drop_cache: bool,
+
+    /// Copy through io_uring with several reads and writes in flight instead of letting the kernel copy
+    #[arg(long, conflicts_with_all = ["direct", "drop_cache", "incremental", "store"])]
+    pub io_uring: bool,
+
+    /// Read sources at no more than this many bytes per second, e.g. `200M`
+    #[arg(long, value_parser = throttle::parse_rate)]
+    pub limit_rate: Option<index::Offset>,
+
+    /// Go below --limit-rate while reading the sources takes longer than it used to
+    #[arg(long, requires = "limit_rate")]
+    pub adaptive: bool,
+
+    /// I/O priority of the backup: `idle`, or `best-effort:N` with N from 0 (highest) to 7
+    #[arg(long)]
+    pub ionice: Option<throttle::IoNice>,
 }
 
 #[derive(Clone, Args, Debug)]
@@ -49,12 +143,414 @@ struct RestoreFromFragment {
 
     #[arg(long)]
     pub no_hash: bool,
+
+    /// Read holes and data missing from a short source file as zeros instead of failing
+    #[arg(long)]
+    pub zeros: bool,
+
+    /// Bypass the page cache with O_DIRECT
+    #[arg(long, conflicts_with = "drop_cache")]
+    pub direct: bool,
+
+    /// Write data back as it is copied and drop it from the page cache
+    #[arg(long)]
+    pub drop_cache: bool,
 }
 
 #[derive(Clone, Args, Debug)]
 struct ValidateHash {
     #[arg(short = 'f', long = "fragment")]
     pub fragment: String,
+
+    /// Hash holes and data missing from a short fragment file as zeros instead of failing
+    #[arg(long)]
+    pub zeros: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct ExtractCommand {
+    #[arg(short = 'g', long = "group", default_value = "backup")]
+    pub group: String,
+
+    /// Source to extract
+    #[arg(short = 's', long = "source", default_value = "main")]
+    pub source: String,
+
+    /// Byte range of main to extract, e.g. `0..1M`; either end may be omitted
+    #[arg(short = 'r', long = "range", default_value = "..")]
+    pub range: RangeArg,
+
+    /// Output file; `-` writes to stdout
+    #[arg(short = 'o', long = "out", default_value = "-")]
+    pub out: String,
+
+    /// Restore the state of an earlier generation instead of the current one
+    #[arg(long)]
+    pub generation: Option<u64>,
+
+    /// Bypass the page cache with O_DIRECT; needs an output file
+    #[arg(long, conflicts_with = "drop_cache")]
+    pub direct: bool,
+
+    /// Drop the data from the page cache once it was read or written back; needs an output file
+    #[arg(long)]
+    pub drop_cache: bool,
+}
+
+/// A possibly open-ended range `START..END` of offsets into main
+#[derive(Copy, Clone, Debug)]
+struct RangeArg {
+    pub start: Option<index::Offset>,
+    pub end: Option<index::Offset>,
+}
+
+impl RangeArg {
+    pub fn resolve(&self, len: index::Offset) -> Result<index::Slice> {
+        let start = self.start.unwrap_or(0);
+        let end = self.end.unwrap_or(len);
+        ensure!(
+            start <= end,
+            "Range start {start} lies after range end {end}."
+        );
+        ensure!(
+            end <= len,
+            "Range end {end} lies beyond the end of the main file ({len})."
+        );
+        Ok(index::Slice { start, end })
+    }
+}
+
+impl std::str::FromStr for RangeArg {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (start, end) = s
+            .split_once("..")
+            .with_context(|| format!("Range `{s}` is not of the form START..END"))?;
+        let parse = |v: &str| (!v.is_empty()).then(|| parse_size(v)).transpose();
+        Ok(Self {
+            start: parse(start)?,
+            end: parse(end)?,
+        })
+    }
+}
+
+#[derive(Clone, Args, Debug)]
+struct MountCommand {
+    #[arg(short = 'g', long = "group", default_value = "backup")]
+    pub group: String,
+
+    /// Source to mount
+    #[arg(short = 's', long = "source", default_value = "main")]
+    pub source: String,
+
+    /// Name of the reconstructed file inside the mount
+    #[arg(short = 'n', long = "name", default_value = "main")]
+    pub name: String,
+
+    /// Serve zeros instead of failing reads of ranges no fragment covers
+    #[arg(long)]
+    pub zeros: bool,
+
+    #[arg(long)]
+    pub allow_other: bool,
+
+    pub mountpoint: String,
+}
+
+#[derive(Clone, Args, Debug)]
+#[command(group(clap::ArgGroup::new("listen").required(true)))]
+struct ServeNbdCommand {
+    #[arg(short = 'g', long = "group", default_value = "backup")]
+    pub group: String,
+
+    /// Listen on this unix socket
+    #[arg(short = 's', long = "socket", group = "listen")]
+    pub socket: Option<String>,
+
+    /// Listen on this TCP address, e.g. `127.0.0.1:10809`
+    #[arg(short = 'l', long = "listen", group = "listen")]
+    pub tcp: Option<String>,
+
+    /// Source to export
+    #[arg(long = "source", default_value = "main")]
+    pub source: String,
+
+    /// Name of the export
+    #[arg(short = 'n', long = "name", default_value = "main")]
+    pub name: String,
+
+    /// Make the device writable, keeping all writes in this copy-on-write file
+    #[arg(long)]
+    pub overlay: Option<String>,
+
+    /// Serve zeros instead of failing reads of ranges no fragment covers
+    #[arg(long)]
+    pub zeros: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct ServeHttpCommand {
+    #[arg(short = 'g', long = "group", default_value = "backup")]
+    pub group: String,
+
+    /// Source to serve at `/main`
+    #[arg(short = 's', long = "source", default_value = "main")]
+    pub source: String,
+
+    /// Address to listen on
+    #[arg(short = 'l', long = "listen", default_value = "127.0.0.1:8080")]
+    pub listen: String,
+}
+
+#[derive(Clone, Args, Debug)]
+struct InspectFragmentCommand {
+    /// Fragment file to inspect
+    pub path: String,
+
+    /// Write the copy of the index embedded in the fragment to this file
+    #[arg(long)]
+    pub extract_index: Option<String>,
+}
+
+#[derive(Clone, Args, Debug)]
+struct RebuildIndexCommand {
+    /// Files, directories or devices to scan for fragments
+    #[arg(required = true)]
+    pub paths: Vec<String>,
+
+    /// Older copy of the index used to recognize plain fragments by size and hash
+    #[arg(short = 's', long)]
+    pub skeleton: Option<String>,
+
+    /// Only consider fragments belonging to the index with this UUID
+    #[arg(long)]
+    pub uuid: Option<String>,
+
+    /// Match plain fragments by size only
+    #[arg(long)]
+    pub no_hash: bool,
+
+    /// Replace an existing index; it is also used as skeleton
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct SyncIndexCommand {
+    /// Further index copies to merge and keep up to date, e.g. from media the index does not know
+    pub replicas: Vec<String>,
+}
+
+#[derive(Clone, Args, Debug)]
+struct DiffIndexCommand {
+    pub a: String,
+    pub b: String,
+}
+
+#[derive(Clone, Args, Debug)]
+struct MergeIndexCommand {
+    pub a: String,
+    pub b: String,
+
+    /// File to write the merged index to; may be one of the inputs
+    #[arg(short = 'o', long = "out")]
+    pub out: String,
+
+    /// Resolve conflicting fragments by keeping the record from this index
+    #[arg(long, value_enum)]
+    pub prefer: Option<diff::Side>,
+
+    /// Ask which record to keep for every conflicting fragment
+    #[arg(long, conflicts_with = "prefer")]
+    pub interactive: bool,
+
+    /// Merge indices with different UUIDs; the merged index keeps the UUID of the first
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct CheckCommand {
+    /// Also check that the files referenced by the index exist and have the expected size
+    #[arg(long)]
+    pub files: bool,
+
+    /// Also verify the hashes of all files; implies --files
+    #[arg(long)]
+    pub hash: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct FragmentAddCommand {
+    /// File holding the fragment data
+    pub path: String,
+
+    /// Source the fragment holds data of; defaults to main
+    #[arg(short = 's', long)]
+    pub source: Option<String>,
+
+    /// Offset in the source at which the fragment data starts
+    #[arg(short = 'o', long, value_parser = parse_size)]
+    pub offset: index::Offset,
+
+    /// Number of bytes of the source in the file; defaults to the size of the file
+    #[arg(short = 'l', long, value_parser = parse_size)]
+    pub len: Option<index::Offset>,
+
+    #[arg(short = 'g', long, default_value = "backup")]
+    pub group: String,
+
+    /// Name of the fragment; defaults to a new UUID
+    #[arg(short = 'n', long)]
+    pub name: Option<String>,
+
+    #[arg(long)]
+    pub no_hash: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct FragmentRmCommand {
+    pub fragment: String,
+
+    /// Also delete the fragment file
+    #[arg(long)]
+    pub delete_file: bool,
+
+    /// Remove the fragment even if its group no longer covers main afterwards
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct FragmentRenameCommand {
+    pub old: String,
+    pub new: String,
+}
+
+#[derive(Clone, Args, Debug)]
+struct FragmentGroupCommand {
+    pub fragment: String,
+    pub group: String,
+
+    /// Remove the fragment from the group even if the group no longer covers main afterwards
+    #[arg(long)]
+    pub force: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct FragmentCommentCommand {
+    pub fragment: String,
+    pub comment: String,
+}
+
+#[derive(Clone, Subcommand, Debug)]
+enum FragmentGroupAction {
+    /// Add the fragment to a group
+    Add(FragmentGroupCommand),
+    /// Remove the fragment from a group
+    Rm(FragmentGroupCommand),
+}
+
+#[derive(Clone, Subcommand, Debug)]
+enum FragmentAction {
+    /// Register an existing file as fragment
+    Add(FragmentAddCommand),
+    /// Remove a fragment from the index
+    Rm(FragmentRmCommand),
+    /// Change the name of a fragment
+    Rename(FragmentRenameCommand),
+    /// Change the groups of a fragment
+    #[command(subcommand)]
+    Group(FragmentGroupAction),
+    /// Add a comment to a fragment
+    Comment(FragmentCommentCommand),
+}
+
+#[derive(Clone, Args, Debug)]
+struct FragmentCommand {
+    #[command(subcommand)]
+    pub action: FragmentAction,
+}
+
+#[derive(Clone, Args, Debug)]
+struct ImportSplitCommand {
+    /// The parts in any order; wildcards `*` and `?` in file names are expanded
+    #[arg(required = true)]
+    pub parts: Vec<String>,
+
+    /// Size of the original file; defaults to the total size of the parts
+    #[arg(long, value_parser = parse_size)]
+    pub main_size: Option<index::Offset>,
+
+    #[arg(short = 'g', long, default_value = "backup")]
+    pub group: String,
+
+    #[arg(long)]
+    pub no_hash: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct ExportScriptCommand {
+    #[arg(short = 'g', long = "group", default_value = "backup")]
+    pub group: String,
+
+    /// Source the script restores
+    #[arg(short = 's', long = "source", default_value = "main")]
+    pub source: String,
+
+    /// Output file; `-` writes to stdout
+    #[arg(short = 'o', long = "out", default_value = "-")]
+    pub out: String,
+}
+
+#[derive(Clone, Args, Debug)]
+struct ConvertIndexCommand {
+    /// Index to convert, in any format
+    pub input: String,
+
+    /// Where to write the converted index; the format is taken from --format or the extension
+    #[arg(short = 'o', long = "out")]
+    pub out: String,
+}
+
+#[derive(Clone, Args, Debug)]
+struct RefreshCommand {
+    /// Source to refresh; may be given several times, defaults to all sources
+    #[arg(short = 's', long = "source")]
+    pub sources: Vec<String>,
+
+    #[arg(long)]
+    pub no_hash: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct GenerationsCommand {}
+
+#[derive(Clone, Args, Debug)]
+struct PruneCommand {
+    /// Keep the N newest generations
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub keep_last: usize,
+
+    /// Keep the newest generation of each of the M most recent months
+    #[arg(long, value_name = "M", default_value_t = 0)]
+    pub keep_monthly: usize,
+
+    /// Delete the files of removed fragments that no remaining fragment uses
+    #[arg(long)]
+    pub delete_files: bool,
+
+    /// Only show what would be removed
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Args, Debug)]
+struct MigrateIndexCommand {
+    /// Only show how the index would change
+    #[arg(long)]
+    pub dry_run: bool,
 }
 
 #[derive(Clone, Subcommand, Debug)]
@@ -63,13 +559,56 @@ enum Command {
     WriteBackup(WriteBackupCommand),
     RestoreFromFragment(RestoreFromFragment),
     ValidateHash(ValidateHash),
+    /// Stream a range of the main file from the fragments of a group
+    #[command(alias = "cat")]
+    Extract(ExtractCommand),
+    /// Expose the main file read-only through a FUSE filesystem
+    Mount(MountCommand),
+    /// Export the main file as a network block device
+    ServeNbd(ServeNbdCommand),
+    /// Serve the main file, the fragments and the index over HTTP
+    ServeHttp(ServeHttpCommand),
+    /// Show the metadata stored in a self-describing fragment file
+    InspectFragment(InspectFragmentCommand),
+    /// Recover the index by scanning media for fragment files
+    RebuildIndex(RebuildIndexCommand),
+    /// Merge the index with its replicas and bring all reachable replicas up to date
+    SyncIndex(SyncIndexCommand),
+    /// Show how the fragments of two indices of the same main file differ
+    DiffIndex(DiffIndexCommand),
+    /// Combine two indices of the same main file
+    MergeIndex(MergeIndexCommand),
+    /// Validate the invariants of the index and optionally the files it references
+    #[command(alias = "fsck")]
+    Check(CheckCommand),
+    /// Edit the fragments recorded in the index
+    Fragment(FragmentCommand),
+    /// Create an index for an archive split into parts by `split`, 7-Zip or similar tools
+    ImportSplit(ImportSplitCommand),
+    /// Write a shell script that restores main using only standard tools
+    ExportScript(ExportScriptCommand),
+    /// Translate an index between TOML, JSON and CBOR
+    ConvertIndex(ConvertIndexCommand),
+    /// Upgrade the index to the current schema version
+    MigrateIndex(MigrateIndexCommand),
+    /// Record the current state of changed sources and mark their backups as stale
+    Refresh(RefreshCommand),
+    /// List the generations recorded in the index
+    Generations(GenerationsCommand),
+    /// Drop old generations and the fragments only they needed
+    Prune(PruneCommand),
 }
 
 #[derive(Clone, Parser, Debug)]
 #[command(author, version, about)]
 struct CliArgs {
+    /// The index file; required by all commands that do not take index files as arguments
     #[arg(short, long)]
-    pub index: String,
+    pub index: Option<String>,
+
+    /// Format to write the index in; by default taken from the file extension or the existing file
+    #[arg(long, global = true, value_enum)]
+    pub format: Option<format::Format>,
 
     #[command(subcommand)]
     pub command: Command,
@@ -78,6 +617,7 @@ struct CliArgs {
 struct CommandInvocation<Command> {
     pub index_file: String,
     pub index: Option<index::Index>,
+    pub format: Option<format::Format>,
     pub command: Command,
 }
 
@@ -86,11 +626,101 @@ impl<T> CommandInvocation<T> {
         Ok(self
             .index
             .as_ref()
-            .with_context(|| format!("Index file `{}` is missing!", self.index_file))?
+            .with_context(|| match self.index_file.is_empty() {
+                true => "This command requires an index; specify one with --index.".to_owned(),
+                false => format!("Index file `{}` is missing!", self.index_file),
+            })?
             .clone())
     }
 }
 
+/// Files named by the paths given to `create`, in backup order; directories are walked recursively
+fn collect_sources(paths: &[String]) -> Result<Vec<String>> {
+    fn walk(dir: &std::path::Path, files: &mut Vec<String>) -> Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .with_context(|| format!("Could not list `{}`", dir.display()))?
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            let kind = entry.file_type()?;
+            if kind.is_dir() {
+                walk(&entry.path(), files)?;
+            } else if kind.is_file() {
+                files.push(pretty_path(entry.path()));
+            } else {
+                log::info!(
+                    "Skipping `{}`, which is not a regular file.",
+                    entry.path().display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    let mut files = vec![];
+    for path in paths {
+        match fs::metadata(path)?.is_dir() {
+            true => walk(std::path::Path::new(path), &mut files)?,
+            false => files.push(path.clone()),
+        }
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    for file in files.iter() {
+        ensure!(seen.insert(file), "`{file}` was given more than once.");
+    }
+    ensure!(!files.is_empty(), "There are no files to back up.");
+    Ok(files)
+}
+
+/// Hash and length of a source file
+fn measure(path: &str, with_hash: bool) -> Result<(Option<String>, index::Offset)> {
+    let mut file = fs::File::open(path)?;
+
+    let len = file.seek(SeekFrom::End(0)).ok();
+    if len.is_some() {
+        file.seek(SeekFrom::Start(0))?;
+    }
+
+    Ok(match (with_hash, len) {
+        // Determined len through seek and no hashing; this is quick
+        (false, Some(len)) => (None, len),
+
+        // Could not determine len through seek, we will have to consume the stream to
+        // determine the length. Hashing disabled.
+        (false, None) => {
+            let progress =
+                ProgressBar::new_spinner().with_message("Determining length of input file.");
+            std::io::copy(&mut file, &mut progress.wrap_write(&mut NullBuffer))?;
+            progress.finish();
+            (None, progress.position())
+        }
+
+        // Hashing enabled. We will have to consume the stream in any case.
+        (true, Some(len)) => {
+            let progress = ProgressBar::new(len).with_message(format!("Hashing `{path}`"));
+            let hash = hash_data(&mut progress.wrap_read(&mut file))?;
+            progress.finish();
+            let pos = progress.position();
+            ensure!(
+                pos == len,
+                "Mismatch between position determined through seek ({len}) \
+                and the position determined by consuming the stream ({pos})."
+            );
+            (Some(hash), len)
+        }
+
+        // Hashing enabled, no length estimate. Consuming the stream manually to determine
+        // length
+        (true, None) => {
+            let progress = ProgressBar::new_spinner().with_message(format!("Hashing `{path}`"));
+            let hash = hash_data(&mut progress.wrap_read(&mut file))?;
+            progress.finish();
+            (Some(hash), progress.position())
+        }
+    })
+}
+
 fn create(args: &CommandInvocation<CreateCommand>) -> Result<(ExitCode, Index)> {
     use crate::index::*;
 
@@ -106,113 +736,111 @@ fn create(args: &CommandInvocation<CreateCommand>) -> Result<(ExitCode, Index)>
     } = args.command;
     let with_hash = !no_hash;
 
-    let canonical = pretty_path(fs::canonicalize(path)?);
-
-    let (hash, len) = {
-        let mut file = fs::File::open(path)?;
-
-        let len = file.seek(SeekFrom::End(0)).ok();
-        if len.is_some() {
-            file.seek(SeekFrom::Start(0))?;
-        }
-
-        match (with_hash, len) {
-            // Determined len through seek and no hashing; this is quick
-            (false, Some(len)) => (None, len),
-
-            // Could not determine len through seek, we will have to consume the stream to
-            // determine the length. Hashing disabled.
-            (false, None) => {
-                let progress =
-                    ProgressBar::new_spinner().with_message("Determining length of input file.");
-                std::io::copy(&mut file, &mut progress.wrap_write(&mut NullBuffer))?;
-                progress.finish();
-                (None, progress.position())
-            }
-
-            // Hashing enabled. We will have to consume the stream in any case.
-            (true, Some(len)) => {
-                let progress = ProgressBar::new(len).with_message("Hashing source file");
-                let hash = hash_data(&mut progress.wrap_read(&mut file))?;
-                progress.finish();
-                let pos = progress.position();
-                ensure!(
-                    pos == len,
-                    "Mismatch between position determined through seek ({len}) \
-                    and the position determined by consuming the stream ({pos})."
-                );
-                (Some(hash), len)
+    let files = collect_sources(path)?;
+    // A single file keeps the traditional layout, which every command understands; files
+    // found in a directory keep their relative names, even if there is only one
+    let single = path.len() == 1 && !fs::metadata(&path[0])?.is_dir();
+
+    let mut sources = vec![];
+    for file in files.iter() {
+        let canonical = pretty_path(fs::canonicalize(file)?);
+        let stat = Stat::of(file)?;
+        let (hash, len) = measure(file, with_hash)?;
+        ensure!(
+            stat == Stat::of(file)?,
+            "`{file}` changed while it was being read."
+        );
+
+        sources.push(Fragment {
+            meta: Meta {
+                name: vec![
+                    match single {
+                        true => "main".to_owned(),
+                        false => file.clone(),
+                    },
+                    uuidgen(),
+                ],
+                comment: vec![
+                    format!("Relative path during fragment creation: {file}"),
+                    format!("Canonical path during fragment creation: {canonical}"),
+                ],
+            },
+            groups: vec!["main".to_owned()],
+            stale: false,
+            generation: 0,
+            source: None,
+            location: File {
+                device: None,
+                path: canonical,
             }
+            .as_location(),
+            hashes: {
+                let mut t = HashMap::new();
+                if let Some(hash) = hash {
+                    t.insert(HashIdentifier::Sha3_256, hash);
+                }
+                t
+            },
+            geometry: Slice { start: 0, end: len },
+            holes: vec![],
+            stat: Some(stat),
+            blocks: None,
+            extra: Default::default(),
+        });
+    }
 
-            // Hashing enabled, no length estimate. Consuming the stream manually to determine
-            // length
-            (true, None) => {
-                let progress = ProgressBar::new_spinner().with_message("Hashing source file");
-                let hash = hash_data(&mut progress.wrap_read(&mut file))?;
-                progress.finish();
-                (Some(hash), progress.position())
-            }
-        }
-    };
-
-    let main_frag = Fragment {
-        meta: Meta {
-            name: vec!["main".to_owned(), uuidgen()],
-            comment: vec![
-                format!("Relative path during fragment creation: {path}"),
-                format!("Canonical path during fragment creation: {canonical}"),
-            ],
-        },
-        groups: vec!["main".to_owned()],
-        location: File {
-            device: None,
-            path: canonical.clone(),
-        }
-        .as_location(),
-        hashes: {
-            let mut t = HashMap::new();
-            if let Some(hash) = hash {
-                t.insert(HashIdentifier::Sha3_256, hash);
-            }
-            t
-        },
-        geometry: Slice { start: 0, end: len },
-        holes: vec![],
-    };
+    let mut comment = vec![];
+    for path in path.iter() {
+        comment.push(format!("Relative path during creation: {path}"));
+        comment.push(format!(
+            "Canonical path during creation: {}",
+            pretty_path(fs::canonicalize(path)?)
+        ));
+    }
 
     let index = Index {
         meta: Meta {
             name: name.iter().by_ref().map(|v| v.to_owned()).collect(),
-            comment: vec![
-                format!("Relative path during creation: {path}"),
-                format!("Canonical path during creation: {canonical}"),
-            ],
+            comment,
         },
-        fragments: vec![main_frag],
+        fragments: sources,
+        ..Default::default()
     };
 
     Ok((ExitCode::from(0), index))
 }
 
-fn get_fragment_group(idx: &Index, group: &str) -> Vec<index::Slice> {
+/// The current fragments of `group` holding data of `source`
+fn get_fragments<'a>(
+    idx: &'a Index,
+    source: &index::Fragment,
+    group: &str,
+) -> Vec<&'a index::Fragment> {
     idx.fragments
         .iter()
-        .filter(|frag| frag.in_group(group))
+        .filter(|frag| frag.in_group(group) && frag.carries(source) && !frag.stale)
+        .collect::<Vec<_>>()
+}
+
+fn get_fragment_group(idx: &Index, source: &index::Fragment, group: &str) -> Vec<index::Slice> {
+    get_fragments(idx, source, group)
+        .into_iter()
         .map(|frag| frag.geometry)
         .collect::<Vec<_>>()
 }
 
 fn determine_next_backup(
     idx: &Index,
-    mut to_backup: index::Slice,
+    source: &index::Fragment,
     group: &str,
 ) -> Option<index::Slice> {
-    let mut backed_up = get_fragment_group(idx, group);
+    let mut to_backup = source.geometry;
+    let mut backed_up = get_fragment_group(idx, source, group);
     backed_up.sort_by_key(|frag| (frag.start, frag.end));
 
     for seg in backed_up.iter() {
         if seg.start <= to_backup.start {
-            to_backup.start = seg.end;
+            to_backup.start = to_backup.start.max(seg.end);
         } else {
             to_backup.end = seg.start;
             break;
@@ -222,38 +850,54 @@ fn determine_next_backup(
     (to_backup.start < to_backup.end).then_some(to_backup)
 }
 
+/// The next range missing from `group` and the number of the source it belongs to
+///
+/// Sources are backed up in the order they appear in the index.
+fn next_backup(idx: &Index, group: &str) -> Option<(usize, index::Slice)> {
+    idx.fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, frag)| frag.in_group("main"))
+        .find_map(|(no, source)| determine_next_backup(idx, source, group).map(|s| (no, s)))
+}
+
 fn write_backup(args: &CommandInvocation<WriteBackupCommand>) -> Result<(ExitCode, Index)> {
     use index::*;
 
+    if args.command.incremental {
+        return write_incremental(args);
+    }
+    if args.command.store.is_some() {
+        return write_to_store(args);
+    }
+
     let mut idx = args.use_index()?;
     let WriteBackupCommand {
         destination,
         backup_group,
         no_hash,
+        container,
+        no_replicate_index,
+        force,
+        block_size,
+        no_fast_copy,
+        direct,
+        drop_cache,
+        io_uring,
+        ..
     } = args.command.clone();
     let with_hash = !no_hash;
+    let destination = destination.context("Specify a destination with --dest.")?;
+    let cache = CacheMode::from_flags(direct, drop_cache);
+    let mut throttle = backup_limits(&args.command)?;
 
-    // Open the main fragment
-    let main_frag = idx.get_fragment_by_name("main")?;
-    let main_frag_geom = main_frag.get(&idx).geometry;
-    let main_path = match &main_frag.get(&idx).location.data {
-        LocationData::File(File { path, .. }) => path,
-        data => bail!("Reading from location data of this type is not implemented: {data:?}"),
-    };
+    check_sources_unchanged(&idx, force)?;
 
     // Which segments have been backed up
-    let to_backup = determine_next_backup(&idx, main_frag_geom, &backup_group);
-    let to_backup = match to_backup {
-        Some(v) => v,
-        None => {
-            log::info!("Backup already complete, no data was written!");
-            exit(3);
-        }
-    };
-
-    // Open main data file for backing up
-    let mut main_data = fs::File::open(main_path)?;
-    main_data.seek(SeekFrom::Start(to_backup.start))?;
+    if next_backup(&idx, &backup_group).is_none() {
+        log::info!("Backup already complete, no data was written!");
+        exit(3);
+    }
 
     // Open backup storage
     let mut backup_data = fs::File::create(&destination)?;
@@ -261,36 +905,245 @@ fn write_backup(args: &CommandInvocation<WriteBackupCommand>) -> Result<(ExitCod
     // Get canonical path of backup file
     let dest_canonical = pretty_path(fs::canonicalize(&destination)?);
 
-    let progress = ProgressBar::new(to_backup.end - to_backup.start).with_message("Copying data");
-    let (hash, written, fatal, res) = copy_and_optionally_hash(
-        with_hash,
-        &mut main_data,
-        progress.wrap_write(&mut backup_data),
-    );
+    // Sources the group holds nothing of yet start the generation of their current data
+    let fresh_generation =
+        generation::current(&idx).unwrap_or_else(|| generation::next_number(&idx));
+
+    // Pack sources into the destination in order until it is full or all are backed up
+    let mut pos: Offset = 0;
+    let mut packed = vec![];
+    while let Some((source_no, to_backup)) = next_backup(&idx, &backup_group) {
+        // Keep the data of every source sector aligned for O_DIRECT
+        if cache == CacheMode::Direct {
+            pos = pos.next_multiple_of(cache::ALIGN);
+        }
+        let source = &idx.fragments[source_no];
+        let source_len = source.geometry.end;
+        let source_path = source_path(source)?;
+        // Sources other than main are referred to by their UUID
+        let source_ref = match source.is_named("main") {
+            true => None,
+            false => Some(source.source_name().to_owned()),
+        };
+        let source_key = source.meta.name.first().cloned().unwrap_or_default();
+
+        // Keep recording block hashes once a group has them
+        let group = get_fragments(&idx, source, &backup_group);
+        let block_size = block_size.or_else(|| blocks::recorded_size(&group));
+        let generation = group
+            .iter()
+            .map(|f| f.generation)
+            .max()
+            .unwrap_or(fresh_generation);
+
+        let before = Stat::of(&source_path)?;
+
+        // Open source data file for backing up
+        let mut source_data = fs::File::open(&source_path)?;
+        source_data.seek(SeekFrom::Start(to_backup.start))?;
+
+        let mut frag = Fragment {
+            meta: Meta {
+                name: vec![uuidgen()],
+                comment: vec![
+                    format!("Relative path during fragment creation: {destination}"),
+                    format!("Canonical path during fragment creation: {dest_canonical}"),
+                ],
+            },
+            groups: vec![backup_group.clone()],
+            stale: false,
+            generation,
+            source: source_ref,
+            location: File {
+                device: None,
+                path: dest_canonical.clone(),
+            }
+            .as_location(),
+            hashes: HashMap::new(),
+            geometry: to_backup,
+            holes: vec![],
+            stat: None,
+            blocks: None,
+            extra: Default::default(),
+        };
+
+        // Reserve space for the container header; it is rewritten once the data is known
+        let container = if container {
+            let mut placeholder = container::Header {
+                index: idx.ensure_uuid(),
+                main_len: source_len,
+                fragment: frag.clone(),
+            };
+            if with_hash {
+                let dummy = copy::encode_hash([0xff; 32]);
+                placeholder
+                    .fragment
+                    .hashes
+                    .insert(HashIdentifier::Sha3_256, dummy);
+            }
+            // The embedded index includes the fragment the container holds
+            let mut embedded = idx.clone();
+            embedded.fragments.push(placeholder.fragment.clone());
+            let header_len = container::reserve(&placeholder, Some(&embedded))?;
+            container::write_header(&mut backup_data, header_len, &placeholder, Some(&embedded))?;
+            pos = header_len;
+            Some((placeholder, header_len))
+        } else {
+            None
+        };
+        backup_data.seek(SeekFrom::Start(pos))?;
+
+        let progress = ProgressBar::new(to_backup.len())
+            .with_message(format!("Copying data of `{source_key}`"));
+        // The kernel would copy through the page cache, without throttling, and have the
+        // source read twice for hashing
+        let offloaded =
+            match no_fast_copy || io_uring || throttle.is_some() || cache != CacheMode::Buffered {
+                true => None,
+                false => copy::copy_in_kernel(
+                    &source_data,
+                    to_backup.start,
+                    &backup_data,
+                    pos,
+                    to_backup.len(),
+                    |n| progress.inc(n),
+                ),
+            };
+        // Hash data that did not pass through here by reading it from the source
+        let mut rehash = |copied: u64, res: Result<()>| {
+            if !with_hash && block_size.is_none() {
+                return (None, copied as usize, false, res, None);
+            }
+            progress.set_position(0);
+            progress.set_length(copied);
+            progress.set_message(format!("Hashing data of `{source_key}`"));
+            let read = source_data.seek(SeekFrom::Start(to_backup.start));
+            let (hash, _, fatal, hash_res, blocks) = copy_recording_blocks(
+                with_hash,
+                (&mut source_data).take(copied),
+                progress.wrap_write(NullBuffer),
+                block_size,
+                to_backup.start,
+            );
+            match read.map_err(Into::into).and(hash_res) {
+                Ok(()) => (hash, copied as usize, fatal, res, blocks),
+                Err(e) => (hash, copied as usize, true, Err(e), blocks),
+            }
+        };
+        let (hash, written, fatal, res, block_hashes) = match offloaded {
+            None => {
+                let (src, mut dst) = copy_ends(
+                    &source_path,
+                    to_backup,
+                    (&backup_data, &destination, pos),
+                    cache,
+                    io_uring,
+                )?;
+                if let Some(throttle) = throttle.as_mut() {
+                    throttle.watch(&progress);
+                }
+                let (hash, written, fatal, res, blocks) = copy_recording_blocks(
+                    with_hash,
+                    throttle::Throttled::new(src, throttle.as_mut()),
+                    progress.wrap_write(&mut dst),
+                    block_size,
+                    to_backup.start,
+                );
+                let (stored, flushed) = dst.finish();
+                let res = res.and(flushed.map_err(Into::into));
+                match stored == written as u64 {
+                    true => (hash, written, fatal, res, blocks),
+                    _ if fatal => (hash, stored as usize, fatal, res, blocks),
+                    // Data held back by the writer never made it, so the hashes cover too much
+                    false => rehash(stored, res),
+                }
+            }
+            Some((method, copied, res)) => {
+                log::debug!("Copied {copied} bytes with {method:?}.");
+                rehash(copied, res)
+            }
+        };
 
-    // Deal with the fatal bit
-    if fatal {
-        match res {
-            Ok(()) => bail!("Fatal error indication without an error value; This is likely a programming error."),
-            Err(e) => return Err(e),
+        // Deal with the fatal bit
+        if fatal {
+            match res {
+                Ok(()) => bail!("Fatal error indication without an error value; This is likely a programming error."),
+                Err(e) => return Err(e),
+            }
         }
-    }
 
-    // Deal with the written length: Since there was *no* fatal error, it should be greater than zero
-    if written == 0 {
-        match res {
-            Ok(()) => bail!("No data written to backup destination for unknown reason; this is likely a programming error."),
-            Err(e) => return Err(e),
+        // Deal with the written length: Since there was *no* fatal error, it should be greater than zero
+        if written == 0 {
+            match (res, pos) {
+                (Ok(()), _) => bail!("No data written to backup destination for unknown reason; this is likely a programming error."),
+                (Err(e), 0) => return Err(e),
+                // The destination filled up with an earlier source
+                (Err(e), _) => {
+                    progress.abandon();
+                    log::debug!("Stopped packing sources into the destination: {e:?}");
+                    break;
+                }
+            }
+        }
+
+        check_unchanged(
+            &source_key,
+            &before,
+            &source_path,
+            "while it was being backed up",
+            force,
+        )?;
+
+        // Deal with the non-fatal error
+        let complete = res.is_ok() && written as u64 == to_backup.len();
+        if let Err(e) = res {
+            progress.abandon_with_message(format!(
+                "Writing data to the backup terminated with non-fatal error: {e:?}"
+            ));
+        } else {
+            progress.finish();
+        }
+
+        // Figure out what was actually backed up
+        frag.geometry = Slice {
+            start: to_backup.start,
+            end: to_backup.start + (written as u64),
+        };
+        if let Some(hash) = hash {
+            frag.hashes.insert(HashIdentifier::Sha3_256, hash);
+        }
+        frag.blocks = block_hashes;
+        if pos > 0 {
+            frag.location.slice = Some(Slice {
+                start: pos,
+                end: pos + written as u64,
+            });
+        }
+        pos += written as u64;
+        packed.push(idx.fragments.len());
+
+        // Add the backup fragment
+        idx.fragments.push(frag);
+
+        // A container describes a single fragment, and embeds the index recording it
+        let single = container.is_some();
+        if let Some((mut header, header_len)) = container {
+            header.fragment = idx.fragments[idx.fragments.len() - 1].clone();
+            container::write_header(&mut backup_data, header_len, &header, Some(&idx))?;
+        }
+
+        if !complete || single {
+            break;
         }
     }
 
-    // Deal with the non-fatal error
-    if let Err(e) = res {
-        progress.abandon_with_message(format!(
-            "Writing data to the backup terminated with non-fatal error: {e:?}"
-        ));
-    } else {
-        progress.finish();
+    // Once the file holds several fragments, the first one also says which part it occupies
+    if let [first, _, ..] = packed[..] {
+        let frag = &mut idx.fragments[first];
+        frag.location.slice.get_or_insert(Slice {
+            start: 0,
+            end: frag.geometry.len(),
+        });
     }
 
     let progress = ProgressBar::new_spinner().with_message("Making sure all data was written…");
@@ -300,50 +1153,26 @@ fn write_backup(args: &CommandInvocation<WriteBackupCommand>) -> Result<(ExitCod
     backup_data
         .sync_data()
         .context("Failed to sync written backup to underlieing storage.")
-        .map_err(|e| {
-            progress.abandon();
-            e
-        })?;
+        .inspect_err(|_| progress.abandon())?;
 
     progress.abandon();
 
-    // Figure out what was actually backed up
-    let actually_backed_up = Slice {
-        start: to_backup.start,
-        end: to_backup.start + (written as u64),
-    };
-
-    // Add the backup fragment
-    idx.fragments.push(Fragment {
-        meta: Meta {
-            name: vec![uuidgen()],
-            comment: vec![
-                format!("Relative path during fragment creation: {destination}"),
-                format!("Canonical path during fragment creation: {dest_canonical}"),
-            ],
-        },
-        groups: vec![backup_group],
-        location: File {
-            device: None,
-            path: dest_canonical,
-        }
-        .as_location(),
-        hashes: {
-            let mut t = HashMap::new();
-            if let Some(hash) = hash {
-                t.insert(HashIdentifier::Sha3_256, hash);
-            }
-            t
-        },
-        geometry: actually_backed_up,
-        holes: vec![],
-    });
+    // Keep a copy of the index on the backup medium; it is written along with the index
+    if !no_replicate_index {
+        idx.ensure_uuid();
+        let replica = replica::replica_path(&dest_canonical);
+        if !idx.replicas.contains(&replica) {
+            idx.replicas.push(replica);
+        }
+    }
 
     // Determine next backup step for data reporting
-    let to_backup = determine_next_backup(&idx, main_frag_geom, &args.command.backup_group);
-    match to_backup {
+    match next_backup(&idx, &backup_group) {
         None => {
             log::info!("Backup complete!");
+            if let Some(no) = generation::record(&mut idx, &backup_group) {
+                log::info!("Group `{backup_group}` holds generation {no}.");
+            }
             Ok((ExitCode::from(0), idx))
         }
         Some(_) => {
@@ -353,6 +1182,682 @@ fn write_backup(args: &CommandInvocation<WriteBackupCommand>) -> Result<(ExitCod
     }
 }
 
+/// Add the missing ranges of all sources to a chunk store, one fragment per source
+fn write_to_store(args: &CommandInvocation<WriteBackupCommand>) -> Result<(ExitCode, Index)> {
+    use index::*;
+
+    let mut idx = args.use_index()?;
+    let WriteBackupCommand {
+        backup_group,
+        no_hash,
+        no_replicate_index,
+        force,
+        store,
+        chunk_size,
+        ..
+    } = args.command.clone();
+    let with_hash = !no_hash;
+    let store = store.unwrap();
+    let mut throttle = backup_limits(&args.command)?;
+
+    check_sources_unchanged(&idx, force)?;
+    if next_backup(&idx, &backup_group).is_none() {
+        log::info!("Backup already complete, no data was written!");
+        exit(3);
+    }
+
+    let chunk_store = chunks::Store::create(&store)?;
+    let store_canonical = pretty_path(fs::canonicalize(&store)?);
+    let chunk_size = chunk_size.unwrap_or(chunks::DEFAULT_CHUNK_SIZE);
+    let fresh_generation =
+        generation::current(&idx).unwrap_or_else(|| generation::next_number(&idx));
+
+    while let Some((source_no, to_backup)) = next_backup(&idx, &backup_group) {
+        let source = &idx.fragments[source_no];
+        let source_path = source_path(source)?;
+        let source_ref = match source.is_named("main") {
+            true => None,
+            false => Some(source.source_name().to_owned()),
+        };
+        let source_key = source.meta.name.first().cloned().unwrap_or_default();
+        let generation = get_fragments(&idx, source, &backup_group)
+            .iter()
+            .map(|f| f.generation)
+            .max()
+            .unwrap_or(fresh_generation);
+
+        let before = Stat::of(&source_path)?;
+        let mut source_data = fs::File::open(&source_path)?;
+        source_data.seek(SeekFrom::Start(to_backup.start))?;
+
+        let progress = ProgressBar::new(to_backup.len())
+            .with_message(format!("Storing chunks of `{source_key}`"));
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.watch(&progress);
+        }
+        let source_data = (&mut source_data).take(to_backup.len());
+        let stored = chunk_store.store(
+            progress.wrap_read(throttle::Throttled::new(source_data, throttle.as_mut())),
+            chunk_size,
+            with_hash,
+        )?;
+        progress.finish();
+        ensure!(
+            stored.len == to_backup.len(),
+            "`{source_key}` ended after {} of {} bytes.",
+            stored.len,
+            to_backup.len()
+        );
+        check_unchanged(
+            &source_key,
+            &before,
+            &source_path,
+            "while it was being backed up",
+            force,
+        )?;
+        log::info!(
+            "Stored `{source_key}` in {} chunks; {} of them ({} bytes) were new to the store.",
+            stored.chunks,
+            stored.new_chunks,
+            stored.new_bytes
+        );
+
+        idx.fragments.push(Fragment {
+            meta: Meta {
+                name: vec![uuidgen()],
+                comment: vec![format!(
+                    "Chunk store during fragment creation: {store_canonical}"
+                )],
+            },
+            groups: vec![backup_group.clone()],
+            stale: false,
+            generation,
+            source: source_ref,
+            location: LocationData::ChunkStore(ChunkStore {
+                store: store_canonical.clone(),
+                manifest: stored.manifest,
+            })
+            .as_location(),
+            hashes: stored
+                .hash
+                .into_iter()
+                .map(|hash| (HashIdentifier::Sha3_256, hash))
+                .collect(),
+            geometry: to_backup,
+            holes: vec![],
+            stat: None,
+            blocks: None,
+            extra: Default::default(),
+        });
+    }
+
+    // Keep a copy of the index next to the store; it is written along with the index
+    if !no_replicate_index {
+        idx.ensure_uuid();
+        let replica = replica::replica_path(&store_canonical);
+        if !idx.replicas.contains(&replica) {
+            idx.replicas.push(replica);
+        }
+    }
+
+    log::info!("Backup complete!");
+    if let Some(no) = generation::record(&mut idx, &backup_group) {
+        log::info!("Group `{backup_group}` holds generation {no}.");
+    }
+    Ok((ExitCode::from(0), idx))
+}
+
+/// Apply --ionice and set up the throttle for --limit-rate
+fn backup_limits(args: &WriteBackupCommand) -> Result<Option<throttle::Throttle>> {
+    if let Some(ionice) = args.ionice {
+        ionice.apply()?;
+    }
+    Ok(args
+        .limit_rate
+        .map(|limit| throttle::Throttle::new(limit, args.adaptive)))
+}
+
+/// [copy_and_optionally_hash], also recording block hashes if `block_size` is given
+///
+/// `start` is the offset in the source of the first byte copied.
+fn copy_recording_blocks<Src: Read, Dst: Write>(
+    with_hash: bool,
+    src: Src,
+    dst: Dst,
+    block_size: Option<index::Offset>,
+    start: index::Offset,
+) -> (
+    Option<String>,
+    usize,
+    bool,
+    Result<()>,
+    Option<index::BlockHashes>,
+) {
+    match block_size {
+        Some(size) => {
+            let mut dst = blocks::BlockWriter::new(dst, size, start);
+            let (hash, written, fatal, res) = copy_and_optionally_hash(with_hash, src, &mut dst);
+            (hash, written, fatal, res, Some(dst.finish()))
+        }
+        None => {
+            let (hash, written, fatal, res) = copy_and_optionally_hash(with_hash, src, dst);
+            (hash, written, fatal, res, None)
+        }
+    }
+}
+
+/// Reader of `range` of a source and writer to a destination file, path and offset
+///
+/// Uses io_uring if asked to and available, otherwise files in the given cache mode.
+fn copy_ends(
+    source_path: &str,
+    range: index::Slice,
+    (dst, dst_path, pos): (&fs::File, &str, index::Offset),
+    cache: CacheMode,
+    io_uring: bool,
+) -> Result<(Box<dyn Read>, CacheWriter)> {
+    #[cfg(feature = "io-uring")]
+    if io_uring {
+        let src = fs::File::open(source_path)?;
+        let ends = uring::UringReader::new(src, range.start, range.end)
+            .and_then(|src| Ok((src, uring::UringWriter::new(dst.try_clone()?, pos)?)));
+        match ends {
+            Ok((src, dst)) => {
+                log::debug!("Copying through io_uring.");
+                return Ok((Box::new(src), CacheWriter::Uring(Box::new(dst))));
+            }
+            Err(e) => log::warn!("Cannot use io_uring, copying with plain reads and writes: {e}"),
+        }
+    }
+    #[cfg(not(feature = "io-uring"))]
+    if io_uring {
+        log::warn!("Built without io_uring support, copying with plain reads and writes.");
+    }
+
+    let mut src = cache::open_read(source_path, cache)?;
+    src.seek(SeekFrom::Start(range.start))?;
+    let dst = CacheWriter::new(dst, dst_path, pos, cache)?;
+    Ok((Box::new(src.take(range.len())), dst))
+}
+
+/// A fragment of an incremental backup while it is being written
+struct Delta {
+    frag: index::Fragment,
+    hasher: Option<sha3::Sha3_256>,
+    blocks: Vec<String>,
+}
+
+impl Delta {
+    fn finish(mut self, size: index::Offset) -> index::Fragment {
+        use sha3::Digest;
+
+        if let Some(hasher) = self.hasher {
+            self.frag.hashes.insert(
+                index::HashIdentifier::Sha3_256,
+                copy::encode_hash(hasher.finalize()),
+            );
+        }
+        self.frag.blocks = Some(index::BlockHashes {
+            size,
+            sha3_256: self.blocks,
+        });
+        self.frag
+    }
+}
+
+/// Write the blocks of all sources that differ from the newest generation of the base group
+fn write_incremental(args: &CommandInvocation<WriteBackupCommand>) -> Result<(ExitCode, Index)> {
+    use index::*;
+    use sha3::Digest;
+
+    let mut idx = args.use_index()?;
+    let WriteBackupCommand {
+        destination,
+        backup_group,
+        base_group,
+        no_hash,
+        container,
+        no_replicate_index,
+        force,
+        block_size,
+        ..
+    } = args.command.clone();
+    let with_hash = !no_hash;
+    let destination = destination.context("Specify a destination with --dest.")?;
+    let group = base_group.unwrap_or(backup_group);
+    let mut throttle = backup_limits(&args.command)?;
+    ensure!(
+        !container,
+        "Incremental backups cannot be written as self-describing fragments."
+    );
+
+    // Open backup storage
+    let mut backup_data = fs::File::create(&destination)?;
+
+    // Get canonical path of backup file
+    let dest_canonical = pretty_path(fs::canonicalize(&destination)?);
+
+    let sources = idx
+        .fragments
+        .iter()
+        .enumerate()
+        .filter(|(_, frag)| frag.in_group("main"))
+        .map(|(no, _)| no)
+        .collect::<Vec<_>>();
+
+    // Every run adds a new generation; it is only recorded once all sources are done
+    let generation = generation::next_number(&idx);
+    let mut pos = 0;
+    let mut full = false;
+    let mut recorded = 0;
+    for source_no in sources {
+        let source = idx.fragments[source_no].clone();
+        let source_key = source.meta.name.first().cloned().unwrap_or_default();
+        let source_path = source_path(&source)?;
+
+        let before = Stat::of(&source_path)?;
+        ensure!(
+            before.len >= source.geometry.end,
+            "`{source_key}` shrank from {} to {} bytes, which incremental backups cannot describe; \
+            run `refresh` and start a new group.",
+            source.geometry.end,
+            before.len
+        );
+
+        let base = get_fragments(&idx, &source, &group);
+        let size = match (blocks::recorded_size(&base), block_size) {
+            (Some(recorded), Some(size)) => {
+                ensure!(
+                    recorded == size,
+                    "Group `{group}` records blocks of {recorded} bytes, not {size}."
+                );
+                size
+            }
+            (recorded, size) => recorded.or(size).unwrap_or(blocks::DEFAULT_BLOCK_SIZE),
+        };
+        if !base.is_empty() && base.iter().all(|f| f.blocks.is_none()) {
+            log::warn!(
+                "The backups of `{source_key}` in group `{group}` have no block hashes, \
+                so all of it is written again. Use --block-size to record them."
+            );
+        }
+        // Whether the data differs from what the group holds, as opposed to filling gaps
+        let mut changed = before.len != source.geometry.end;
+
+        let progress = ProgressBar::new(before.len)
+            .with_message(format!("Looking for changes in `{source_key}`"));
+        if let Some(throttle) = throttle.as_mut() {
+            throttle.watch(&progress);
+        }
+
+        let mut source_data =
+            throttle::Throttled::new(fs::File::open(&source_path)?, throttle.as_mut());
+        let mut source_hasher = with_hash.then(sha3::Sha3_256::default);
+        let mut deltas = vec![];
+        let mut current: Option<Delta> = None;
+        let mut buf = vec![0u8; size as usize];
+
+        let mut offset = 0;
+        while offset < before.len {
+            let block = Slice {
+                start: offset,
+                end: (offset + size).min(before.len),
+            };
+            offset = block.end;
+            let data = &mut buf[..block.len() as usize];
+            source_data.read_exact(data)?;
+            progress.inc(block.len());
+            if let Some(hasher) = source_hasher.as_mut() {
+                hasher.update(&data[..]);
+            }
+
+            let hash = blocks::hash_block(data);
+            if blocks::recorded_hash(&base, block, size) == Some(hash.as_str()) {
+                deltas.extend(current.take().map(|d| d.finish(size)));
+                continue;
+            }
+            changed |= base
+                .iter()
+                .any(|f| f.geometry.start < block.end && block.start < f.geometry.end);
+
+            let fresh = current.is_none();
+            let delta = current.get_or_insert_with(|| Delta {
+                frag: Fragment {
+                    meta: Meta {
+                        name: vec![uuidgen()],
+                        comment: vec![
+                            format!("Relative path during fragment creation: {destination}"),
+                            format!("Canonical path during fragment creation: {dest_canonical}"),
+                        ],
+                    },
+                    groups: vec![group.clone()],
+                    stale: false,
+                    generation,
+                    source: match source.is_named("main") {
+                        true => None,
+                        false => Some(source.source_name().to_owned()),
+                    },
+                    location: File {
+                        device: None,
+                        path: dest_canonical.clone(),
+                    }
+                    .as_location(),
+                    hashes: HashMap::new(),
+                    geometry: Slice {
+                        start: block.start,
+                        end: block.start,
+                    },
+                    holes: vec![],
+                    stat: None,
+                    blocks: None,
+                    extra: Default::default(),
+                },
+                hasher: with_hash.then(sha3::Sha3_256::default),
+                blocks: vec![],
+            });
+            if fresh && pos > 0 {
+                delta.frag.location.slice = Some(Slice {
+                    start: pos,
+                    end: pos,
+                });
+            }
+
+            let (written, res) = try_write_all(&mut backup_data, data);
+            let data = &data[..written];
+            pos += written as Offset;
+            delta.frag.geometry.end += written as Offset;
+            if let Some(slice) = delta.frag.location.slice.as_mut() {
+                slice.end += written as Offset;
+            }
+            if let Some(hasher) = delta.hasher.as_mut() {
+                hasher.update(data);
+            }
+            if !data.is_empty() {
+                delta.blocks.push(match written as Offset == block.len() {
+                    true => hash,
+                    false => blocks::hash_block(data),
+                });
+            }
+
+            if let Err(e) = res {
+                progress.abandon_with_message(format!(
+                    "Writing data to the backup terminated with non-fatal error: {e}"
+                ));
+                full = true;
+                break;
+            }
+        }
+
+        // An empty fragment is left behind if the destination was full to begin with
+        deltas.extend(
+            current
+                .take()
+                .filter(|d| !d.blocks.is_empty())
+                .map(|d| d.finish(size)),
+        );
+        let written: Offset = deltas.iter().map(|d| d.geometry.len()).sum();
+        let count = deltas.len();
+
+        if full {
+            // The newer generation would take precedence over the blocks not scanned yet,
+            // so readers would get a blend of the old and the new version of the source
+            log::warn!(
+                "Wrote {written} bytes of `{source_key}` before the destination filled up; \
+                they are not recorded, as the rest of it was not scanned."
+            );
+            break;
+        }
+        recorded += count;
+        idx.fragments.extend(deltas);
+        progress.finish();
+        check_unchanged(
+            &source_key,
+            &before,
+            &source_path,
+            "while it was being backed up",
+            force,
+        )?;
+
+        // Only now the group holds the current state of the source
+        let source_hash = source_hasher.map(|hasher| copy::encode_hash(hasher.finalize()));
+        let source = &mut idx.fragments[source_no];
+        changed |= source_hash
+            .as_ref()
+            .is_some_and(|hash| source.hashes.get(&HashIdentifier::Sha3_256) != Some(hash));
+        source.stat = Some(before.clone());
+        source.geometry = Slice {
+            start: 0,
+            end: before.len,
+        };
+        match source_hash {
+            Some(hash) => {
+                source.hashes.insert(HashIdentifier::Sha3_256, hash);
+            }
+            None if changed => source.hashes.clear(),
+            None => {}
+        }
+
+        match count {
+            0 => log::info!("`{source_key}` is unchanged."),
+            _ => log::info!(
+                "Wrote {written} bytes of `{source_key}` in {count} fragment(s) as generation {generation}."
+            ),
+        }
+        if changed {
+            if let Some(groups) = mark_stale(&mut idx, source_no, Some(&group)) {
+                log::info!("The backups of `{source_key}` in group(s) {groups} are now stale.");
+            }
+        }
+    }
+
+    let progress = ProgressBar::new_spinner().with_message("Making sure all data was written…");
+    progress.enable_steady_tick(std::time::Duration::from_millis(100));
+    backup_data
+        .sync_data()
+        .context("Failed to sync written backup to underlieing storage.")
+        .inspect_err(|_| progress.abandon())?;
+    progress.abandon();
+
+    if recorded == 0 {
+        fs::remove_file(&destination)?;
+        match pos {
+            0 => log::info!("No blocks changed, no data was written."),
+            _ => log::info!("No complete changes of any source fit, no data was recorded."),
+        }
+    } else if !no_replicate_index {
+        // Keep a copy of the index on the backup medium; it is written along with the index
+        idx.ensure_uuid();
+        let replica = replica::replica_path(&dest_canonical);
+        if !idx.replicas.contains(&replica) {
+            idx.replicas.push(replica);
+        }
+    }
+
+    match full {
+        false => {
+            log::info!("Incremental backup complete!");
+            if let Some(no) = generation::record(&mut idx, &group) {
+                log::info!("Group `{group}` holds generation {no}.");
+            }
+            Ok((ExitCode::from(0), idx))
+        }
+        true => {
+            log::info!("Specify further backup destinations to write the remaining changes.");
+            Ok((ExitCode::from(3), idx))
+        }
+    }
+}
+
+/// Path of the file holding the data of a source
+fn source_path(source: &index::Fragment) -> Result<String> {
+    match &source.location.data {
+        index::LocationData::File(index::File { path, .. }) => Ok(path.clone()),
+        data => bail!("Reading from location data of this type is not implemented: {data:?}"),
+    }
+}
+
+/// Refuse to mix data of different versions of a source in one group
+fn check_sources_unchanged(idx: &Index, force: bool) -> Result<()> {
+    for source in idx.sources() {
+        if let (Some(recorded), index::LocationData::File(index::File { path, .. })) =
+            (&source.stat, &source.location.data)
+        {
+            let name = source.meta.name.first().cloned().unwrap_or_default();
+            check_unchanged(&name, recorded, path, "since it was recorded", force)?;
+        }
+    }
+    Ok(())
+}
+
+/// Refuse to back up a source that changed, unless forced
+fn check_unchanged(
+    name: &str,
+    recorded: &index::Stat,
+    path: &str,
+    when: &str,
+    force: bool,
+) -> Result<()> {
+    let changes = recorded.changes(&index::Stat::of(path)?);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let problem = format!(
+        "Source `{name}` changed {when} ({}); its backup would mix old and new data.",
+        changes.join(", ")
+    );
+    ensure!(
+        force,
+        "{problem} Run `refresh` to record its current state, write an incremental backup \
+        with --incremental, or use --force to back it up anyway."
+    );
+    log::warn!("{problem}");
+    Ok(())
+}
+
+fn refresh(args: &CommandInvocation<RefreshCommand>) -> Result<(ExitCode, Index)> {
+    use index::*;
+
+    let mut idx = args.use_index()?;
+    let RefreshCommand {
+        ref sources,
+        no_hash,
+    } = args.command;
+    let with_hash = !no_hash;
+
+    let nos = match sources.is_empty() {
+        true => idx
+            .fragments
+            .iter()
+            .enumerate()
+            .filter(|(_, frag)| frag.in_group("main"))
+            .map(|(no, _)| no)
+            .collect::<Vec<_>>(),
+        false => sources
+            .iter()
+            .map(|name| {
+                let no = idx.get_fragment_by_name(name)?.idx();
+                ensure!(
+                    idx.fragments[no].in_group("main"),
+                    "Fragment `{name}` is not a source."
+                );
+                Ok(no)
+            })
+            .collect::<Result<Vec<_>>>()?,
+    };
+
+    for no in nos {
+        let source = &idx.fragments[no];
+        let name = source.meta.name.first().cloned().unwrap_or_default();
+        let path = source.filepath().clone();
+
+        let stat = Stat::of(&path)?;
+        if source.stat.as_ref() == Some(&stat) {
+            log::info!("Source `{name}` is unchanged.");
+            continue;
+        }
+
+        let (hash, len) = measure(&path, with_hash)?;
+        ensure!(
+            stat == Stat::of(&path)?,
+            "`{path}` changed while it was being read."
+        );
+
+        let source = &mut idx.fragments[no];
+        let same_len = len == source.geometry.end;
+        let same_data = match (&hash, source.hashes.get(&HashIdentifier::Sha3_256)) {
+            (Some(new), Some(old)) => same_len && new == old,
+            // Without hashes, only the recorded state could have told
+            _ => same_len && source.stat.is_none(),
+        };
+        if same_data && hash.is_none() {
+            log::warn!(
+                "Cannot tell whether `{name}` changed without hashing; assuming it did not."
+            );
+        }
+
+        source.stat = Some(stat);
+        source.geometry = Slice { start: 0, end: len };
+        match hash {
+            Some(hash) => {
+                source.hashes.insert(HashIdentifier::Sha3_256, hash);
+            }
+            None if !same_data => source.hashes.clear(),
+            None => {}
+        }
+        if same_data {
+            log::info!("Recorded the state of `{name}`; its data is unchanged.");
+            continue;
+        }
+
+        match mark_stale(&mut idx, no, None) {
+            None => log::info!("Recorded the new state of `{name}`."),
+            Some(groups) => log::info!(
+                "Recorded the new state of `{name}`; its backups in group(s) {groups} are now stale."
+            ),
+        }
+    }
+
+    Ok((ExitCode::from(0), idx))
+}
+
+/// Mark the backups of source number `no` as stale, except those in group `keep`
+///
+/// Returns the groups affected, ready for display.
+fn mark_stale(idx: &mut Index, no: usize, keep: Option<&str>) -> Option<String> {
+    let source = idx.fragments[no].clone();
+    let mut stale = std::collections::BTreeSet::new();
+    for frag in idx
+        .fragments
+        .iter_mut()
+        .filter(|f| !f.in_group("main") && !f.stale && f.carries(&source))
+        .filter(|f| keep.is_none_or(|keep| !f.in_group(keep)))
+    {
+        frag.stale = true;
+        stale.extend(frag.groups.iter().map(|g| format!("`{g}`")));
+    }
+    (!stale.is_empty()).then(|| stale.into_iter().collect::<Vec<_>>().join(", "))
+}
+
+/// Let `reader` read the holes in `range` as zeros if asked to, and say which ranges these are
+///
+/// Data missing from short fragment files is only noticed, and warned about, while reading.
+fn with_zeros(reader: IndexReader, range: index::Slice, zeros: bool) -> IndexReader {
+    if !zeros {
+        return reader;
+    }
+    let gaps = reader.gaps(range);
+    if !gaps.is_empty() {
+        log::warn!(
+            "Reading the holes at {} as zeros.",
+            gaps.iter()
+                .map(|gap| format!("{}..{}", gap.start, gap.end))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+    reader.with_uncovered(crate::reader::Uncovered::Zeros)
+}
+
 fn restore_from_fragment(args: &CommandInvocation<RestoreFromFragment>) -> Result<ExitCode> {
     use index::*;
 
@@ -360,13 +1865,21 @@ fn restore_from_fragment(args: &CommandInvocation<RestoreFromFragment>) -> Resul
         source_fragment: ref src,
         dest_fragment: ref dst,
         no_hash,
+        zeros,
+        direct,
+        drop_cache,
     } = args.command;
     let with_hash = !no_hash;
+    let cache = CacheMode::from_flags(direct, drop_cache);
 
     let idx = args.use_index()?;
 
     let src = idx.get_fragment_by_name(src)?;
     let dst = idx.get_fragment_by_name(dst.as_deref().unwrap_or("main"))?;
+    ensure!(
+        dst.get(&idx).plain_file().is_some(),
+        "Data can only be restored into fragments kept in plain files."
+    );
 
     let src_geo = src.get(&idx).geometry;
     let dst_geo = dst.get(&idx).geometry;
@@ -416,31 +1929,41 @@ fn restore_from_fragment(args: &CommandInvocation<RestoreFromFragment>) -> Resul
         return Ok(ExitCode::from(0));
     }
 
-    let mut srcio = fs::File::open(src.get(&idx).filepath())?;
-    srcio.seek(SeekFrom::Start(
-        copy_geo.start - src.get(&idx).geometry.start,
-    ))?;
-    let srcio = TruncateReadStream::new(srcio, copy_geo.len() as usize)?;
+    // Hashes are checked below, against whichever fragment is fully covered
+    let srcio = IndexReader::from_fragments([src.get(&idx)])
+        .with_verify(false)
+        .with_cache(cache);
+    let mut srcio = with_zeros(srcio, copy_geo, zeros);
+    srcio.seek(SeekFrom::Start(copy_geo.start))?;
+    let srcio = srcio.take(copy_geo.len());
 
     // TODO: Move into function
-    let mut dstio = fs::OpenOptions::new()
+    let dst_path = dst.get(&idx).filepath();
+    let dstio = fs::OpenOptions::new()
         .read(false)
         .write(true)
         .create(true)
         .truncate(false)
-        .open(dst.get(&idx).filepath())?;
-    if let Err(e) = nix::unistd::ftruncate(&dstio, dst.get(&idx).geometry.len() as i64) {
+        .open(dst_path)?;
+    let dst_off = dst.get(&idx).data_offset();
+    if let Err(e) = nix::unistd::ftruncate(&dstio, (dst_off + dst_geo.len()) as i64) {
         log::warn!("Unable to truncate destination file: {e:?}");
     }
 
-    dstio.seek(SeekFrom::Start(
-        copy_geo.start - dst.get(&idx).geometry.start,
-    ))?;
+    let mut dstio = CacheWriter::new(
+        &dstio,
+        dst_path,
+        dst_off + copy_geo.start - dst.get(&idx).geometry.start,
+        cache,
+    )?;
 
     let progress = ProgressBar::new(copy_geo.len()).with_message("Copying data");
 
     let (hash, written, fatal, res) =
         copy_and_optionally_hash(with_hash, srcio, progress.wrap_write(&mut dstio));
+    let (stored, flushed) = dstio.finish();
+    let res = res.and(flushed.map_err(Into::into));
+    let written = written.min(stored as usize);
 
     if fatal {
         match res {
@@ -477,7 +2000,10 @@ fn restore_from_fragment(args: &CommandInvocation<RestoreFromFragment>) -> Resul
 fn validate_hash(args: &CommandInvocation<ValidateHash>) -> Result<ExitCode> {
     use index::*;
 
-    let ValidateHash { fragment: ref frag } = args.command;
+    let ValidateHash {
+        fragment: ref frag,
+        zeros,
+    } = args.command;
 
     let idx = args.use_index()?;
     let frag = idx.get_fragment_by_name(frag)?;
@@ -487,8 +2013,10 @@ fn validate_hash(args: &CommandInvocation<ValidateHash>) -> Result<ExitCode> {
         log::warn!("Source fragment is missing its reference hash. Will calculate the hash…");
     }
 
-    let fragio = fs::File::open(frag.get(&idx).filepath())?;
-    let mut fragio = TruncateReadStream::new(fragio, frag.get(&idx).geometry.len() as usize)?;
+    let fragio = IndexReader::from_fragments([frag.get(&idx)]).with_verify(false);
+    let mut fragio = with_zeros(fragio, frag.get(&idx).geometry, zeros);
+    fragio.seek(SeekFrom::Start(frag.get(&idx).geometry.start))?;
+    let mut fragio = fragio.take(frag.get(&idx).geometry.len());
 
     let progress = ProgressBar::new(frag.get(&idx).geometry.len()).with_message("Calculating hash");
     let hash = hash_data(progress.wrap_read(&mut fragio))?;
@@ -509,40 +2037,910 @@ fn validate_hash(args: &CommandInvocation<ValidateHash>) -> Result<ExitCode> {
     }
 }
 
-fn main() -> Result<ExitCode> {
-    pretty_env_logger::formatted_builder()
-        .filter_level(log::LevelFilter::Info)
-        .parse_default_env()
-        .init();
+fn extract(args: &CommandInvocation<ExtractCommand>) -> Result<ExitCode> {
+    use index::*;
 
-    let cli = CliArgs::parse();
+    let ExtractCommand {
+        ref group,
+        ref source,
+        range,
+        ref out,
+        generation,
+        direct,
+        drop_cache,
+    } = args.command;
+    let cache = CacheMode::from_flags(direct, drop_cache);
+    ensure!(
+        cache == CacheMode::Buffered || out != "-",
+        "--direct and --drop-cache need an output file given with --out."
+    );
 
-    // TODO: Use open and keep file locked
-    let index_file = cli.index.to_owned();
-    let index = try_read_to_string(&index_file)?
-        .map(|str| toml::from_str::<Index>(&str))
-        .transpose()?;
+    let idx = args.use_index()?;
+    let reader = match generation {
+        Some(no) => {
+            let gen = generation::find(&idx, no)?;
+            IndexReader::for_generation(&idx, source, group, gen)?
+        }
+        None => IndexReader::for_source(&idx, source, group)?,
+    };
+    let range = range.resolve(reader.len())?;
 
-    let (status, index) = {
-        use Command as C;
-        match cli.command {
-            C::Create(command) => create(&CommandInvocation {
-                index_file,
-                index,
-                command,
-            })?,
-            C::WriteBackup(command) => write_backup(&CommandInvocation {
-                index_file,
-                index,
-                command,
-            })?,
-            C::RestoreFromFragment(command) => {
-                // TODO: Dirty!
-                let status = restore_from_fragment(&CommandInvocation {
-                    index_file,
-                    index,
-                    command,
-                })?;
+    let gaps = reader.gaps(range);
+    ensure!(
+        gaps.is_empty(),
+        "The fragments in group `{group}` do not cover the requested range {}..{}. Missing: {}",
+        range.start,
+        range.end,
+        gaps.iter()
+            .map(|Slice { start, end }| format!("{start}..{end}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut reader = reader.with_cache(cache);
+    reader.seek(SeekFrom::Start(range.start))?;
+    let mut reader = reader.take(range.len());
+
+    let progress = ProgressBar::new(range.len()).with_message("Extracting data");
+    let written = match out.as_str() {
+        "-" => {
+            let mut dst = std::io::stdout().lock();
+            let written = std::io::copy(&mut reader, &mut progress.wrap_write(&mut dst))?;
+            dst.flush()?;
+            written
+        }
+        path => {
+            let file = fs::File::create(path)?;
+            let mut dst = CacheWriter::new(&file, path, 0, cache)?;
+            let copied = std::io::copy(&mut reader, &mut progress.wrap_write(&mut dst));
+            let (stored, flushed) = dst.finish();
+            copied?;
+            flushed?;
+            stored
+        }
+    };
+    progress.finish();
+
+    ensure!(
+        written == range.len(),
+        "Only extracted {written} bytes instead of {}.",
+        range.len()
+    );
+
+    Ok(ExitCode::from(0))
+}
+
+fn mount(args: &CommandInvocation<MountCommand>) -> Result<ExitCode> {
+    use crate::reader::Uncovered;
+
+    let MountCommand {
+        ref group,
+        ref source,
+        ref name,
+        zeros,
+        allow_other,
+        ref mountpoint,
+    } = args.command;
+
+    let idx = args.use_index()?;
+    let reader = IndexReader::for_source(&idx, source, group)?.with_uncovered(match zeros {
+        true => Uncovered::Zeros,
+        false => Uncovered::Error,
+    });
+
+    let gaps = reader.gaps(index::Slice {
+        start: 0,
+        end: reader.len(),
+    });
+    if !gaps.is_empty() {
+        log::warn!(
+            "The fragments in group `{group}` do not cover all of `{source}`; \
+            reading the {} uncovered range(s) will fail.",
+            gaps.len()
+        );
+    }
+
+    fuse::serve(
+        reader,
+        std::path::Path::new(mountpoint),
+        fuse::MountOptions {
+            name: name.to_owned(),
+            allow_other,
+        },
+    )?;
+
+    Ok(ExitCode::from(0))
+}
+
+fn serve_nbd(args: &CommandInvocation<ServeNbdCommand>) -> Result<ExitCode> {
+    use crate::reader::Uncovered;
+
+    let ServeNbdCommand {
+        ref group,
+        ref socket,
+        ref tcp,
+        ref source,
+        ref name,
+        ref overlay,
+        zeros,
+    } = args.command;
+
+    let idx = args.use_index()?;
+    let reader = IndexReader::for_source(&idx, source, group)?.with_uncovered(match zeros {
+        true => Uncovered::Zeros,
+        false => Uncovered::Error,
+    });
+    let overlay = overlay
+        .as_ref()
+        .map(|path| nbd::Overlay::open(path, reader.len()))
+        .transpose()?;
+
+    let listen = match (socket, tcp) {
+        (Some(path), _) => nbd::Listen::Unix(path.into()),
+        (None, Some(addr)) => nbd::Listen::Tcp(addr.to_owned()),
+        (None, None) => unreachable!("Enforced by clap"),
+    };
+
+    nbd::serve(
+        nbd::Export {
+            name: name.to_owned(),
+            reader,
+            overlay,
+        },
+        listen,
+    )?;
+
+    Ok(ExitCode::from(0))
+}
+
+fn serve_http(args: &CommandInvocation<ServeHttpCommand>) -> Result<ExitCode> {
+    let ServeHttpCommand {
+        ref group,
+        ref source,
+        ref listen,
+    } = args.command;
+
+    let index = args.use_index()?;
+    // Fail early rather than on the first request
+    IndexReader::for_source(&index, source, group)?;
+
+    http::serve(
+        http::Server {
+            index,
+            group: group.to_owned(),
+            source: source.to_owned(),
+        },
+        listen,
+    )?;
+
+    Ok(ExitCode::from(0))
+}
+
+fn inspect_fragment(args: &CommandInvocation<InspectFragmentCommand>) -> Result<ExitCode> {
+    let InspectFragmentCommand {
+        ref path,
+        ref extract_index,
+    } = args.command;
+
+    let Some(container) = container::read_header_from(path)? else {
+        log::warn!("`{path}` is a plain fragment without a header.");
+        return Ok(ExitCode::from(3));
+    };
+
+    print!("{}", toml::to_string(&container.header)?);
+    println!("# Data offset: {}", container.data_offset);
+
+    match (extract_index, container.index) {
+        (Some(out), Some(index)) => {
+            ensure!(
+                try_read_to_string(out)?.is_none(),
+                "Refusing to overwrite existing file `{out}`!"
+            );
+            let format = format::Format::for_writing(out, args.format)?;
+            fs::write(out, format.serialize(&index)?)?;
+            log::info!("Wrote embedded index to `{out}`.");
+        }
+        (Some(_), None) => bail!("`{path}` does not contain a copy of the index."),
+        (None, Some(index)) => println!(
+            "# Contains a copy of the index with {} fragments.",
+            index.fragments.len()
+        ),
+        (None, None) => {}
+    }
+
+    Ok(ExitCode::from(0))
+}
+
+fn rebuild_index(args: &CommandInvocation<RebuildIndexCommand>) -> Result<(ExitCode, Index)> {
+    let RebuildIndexCommand {
+        ref paths,
+        ref skeleton,
+        ref uuid,
+        no_hash,
+        force,
+    } = args.command;
+
+    ensure!(
+        args.index.is_none() || force,
+        "Refusing to overwrite existing index! Use --force to replace it."
+    );
+
+    let skeleton = match skeleton {
+        Some(path) => {
+            Some(Index::from_file(path)?.with_context(|| format!("No such file `{path}`"))?)
+        }
+        None => args.index.clone(),
+    };
+
+    let rebuilt = rebuild::rebuild(paths, skeleton, uuid.as_deref(), !no_hash)?;
+    log::info!(
+        "Recovered {} fragments.",
+        rebuilt.index.fragments.len() - rebuilt.index.sources().count()
+    );
+
+    for conflict in rebuilt.conflicts.iter() {
+        log::warn!("{conflict}");
+    }
+    let status = match rebuilt.conflicts.is_empty() {
+        true => 0,
+        false => 3,
+    };
+
+    Ok((ExitCode::from(status), rebuilt.index))
+}
+
+fn sync_index(args: &CommandInvocation<SyncIndexCommand>) -> Result<(ExitCode, Index)> {
+    let SyncIndexCommand { ref replicas } = args.command;
+    let replicas = replicas
+        .iter()
+        .map(absolute_path)
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut copies = vec![];
+    let mut foreign = vec![];
+    let primary = match args.index.clone() {
+        Some(idx) => idx,
+        // Without an index, the newest of the given replicas becomes the index
+        None => {
+            let copies = replicas
+                .iter()
+                .filter_map(|path| replica::load(path).transpose())
+                .collect::<Result<Vec<_>>>()?;
+            replica::merge_newest(copies).with_context(|| {
+                format!(
+                    "Index file `{}` is missing and no replica could be loaded!",
+                    args.index_file
+                )
+            })?
+        }
+    };
+
+    let paths = primary.replicas.iter().chain(replicas.iter());
+    for path in paths {
+        match replica::load(path) {
+            Ok(Some(copy)) if replica::is_consistent(&primary, &copy) => {
+                log::info!("Merging replica `{path}` at revision {}.", copy.revision);
+                copies.push(copy);
+            }
+            Ok(Some(_)) => {
+                log::warn!("Replica `{path}` belongs to a different index; leaving it alone.");
+                foreign.push(path.to_owned());
+            }
+            Ok(None) => log::info!("Replica `{path}` does not exist (yet)."),
+            Err(e) => log::warn!("Skipping replica `{path}`: {e:?}"),
+        }
+    }
+
+    copies.push(primary);
+    let mut merged = replica::merge_newest(copies).unwrap();
+    for path in replicas {
+        if !merged.replicas.contains(&path) {
+            merged.replicas.push(path);
+        }
+    }
+    merged.replicas.retain(|path| !foreign.contains(path));
+    log::info!(
+        "Index is at revision {} with {} fragments and {} replicas.",
+        merged.revision,
+        merged.fragments.len(),
+        merged.replicas.len()
+    );
+
+    let status = match foreign.is_empty() {
+        true => 0,
+        false => 3,
+    };
+    Ok((ExitCode::from(status), merged))
+}
+
+fn load_index_arg(path: &str) -> Result<Index> {
+    Index::from_file(path)?.with_context(|| format!("Index file `{path}` is missing!"))
+}
+
+fn diff_index(args: &CommandInvocation<DiffIndexCommand>) -> Result<ExitCode> {
+    let DiffIndexCommand { ref a, ref b } = args.command;
+    let (a, b) = (load_index_arg(a)?, load_index_arg(b)?);
+
+    if let Err(e) = diff::check_same_main(&a, &b) {
+        log::warn!("{e}");
+    }
+
+    let entries = diff::diff(&a, &b);
+    print!("{}", diff::render(&entries));
+
+    let status = match entries.is_empty() {
+        true => 0,
+        false => 3,
+    };
+    Ok(ExitCode::from(status))
+}
+
+fn ask_side(frag: &index::Fragment, changes: &diff::Changes) -> Result<diff::Side> {
+    use std::io::BufRead;
+
+    eprintln!(
+        "Conflicting records of fragment {}: {}",
+        diff::fragment_key(frag),
+        changes.describe()
+    );
+    loop {
+        eprint!("Keep the record from index [a] or [b], or [q]uit? ");
+        std::io::stderr().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().lock().read_line(&mut answer)? == 0 {
+            bail!("Merge aborted.");
+        }
+        match answer.trim() {
+            "a" | "A" => return Ok(diff::Side::A),
+            "b" | "B" => return Ok(diff::Side::B),
+            "q" | "Q" => bail!("Merge aborted."),
+            _ => continue,
+        }
+    }
+}
+
+fn merge_index(args: &CommandInvocation<MergeIndexCommand>) -> Result<ExitCode> {
+    let MergeIndexCommand {
+        ref a,
+        ref b,
+        ref out,
+        prefer,
+        interactive,
+        force,
+    } = args.command;
+    let (a, b) = (load_index_arg(a)?, load_index_arg(b)?);
+
+    diff::check_same_main(&a, &b)?;
+    if let (Some(ua), Some(ub)) = (a.meta.uuid(), b.meta.uuid()) {
+        if ua != ub {
+            ensure!(
+                force,
+                "The indices have different UUIDs ({ua} and {ub}) and may be unrelated. \
+                Use --force to merge them anyway."
+            );
+            log::warn!("Merging indices with different UUIDs; the merged index keeps {ua}.");
+        }
+    }
+
+    let mut conflicts = vec![];
+    let merged = diff::merge(&a, &b, |fa, _, changes| match (prefer, interactive) {
+        (Some(side), _) => Ok(side),
+        (None, true) => ask_side(fa, changes),
+        (None, false) => {
+            conflicts.push(format!(
+                "{}: {}",
+                diff::fragment_key(fa),
+                changes.describe()
+            ));
+            Ok(diff::Side::A)
+        }
+    })?;
+
+    if !conflicts.is_empty() {
+        for conflict in conflicts.iter() {
+            log::error!("Conflict in fragment {conflict}");
+        }
+        bail!(
+            "Refusing to merge {} conflicting fragment(s); \
+            resolve them with --prefer or --interactive.",
+            conflicts.len()
+        );
+    }
+
+    log::info!(
+        "Merged index has {} fragments; writing it to `{out}`.",
+        merged.fragments.len()
+    );
+    save_index(out, merged, args.format)?;
+    Ok(ExitCode::from(0))
+}
+
+fn check(args: &CommandInvocation<CheckCommand>) -> Result<ExitCode> {
+    let CheckCommand { files, hash } = args.command;
+    let idx = args.use_index()?;
+
+    let problems = check::check_index(&idx);
+    for problem in problems.iter() {
+        log::error!("{problem}");
+    }
+    let mut ok = problems.is_empty();
+
+    if files || hash {
+        let statuses = check::check_files(&idx, hash);
+        print!("{}", check::render_files(&statuses));
+        let failed = statuses.iter().filter(|s| s.problem.is_some()).count();
+        if failed > 0 {
+            log::error!(
+                "{failed} of {} fragment files have problems.",
+                statuses.len()
+            );
+            ok = false;
+        }
+    }
+
+    match ok {
+        true => {
+            log::info!("Index `{}` is consistent.", args.index_file);
+            Ok(ExitCode::from(0))
+        }
+        false => Ok(ExitCode::from(3)),
+    }
+}
+
+fn fragment(args: &CommandInvocation<FragmentCommand>) -> Result<ExitCode> {
+    use FragmentAction as A;
+
+    let before = args.use_index()?;
+    let mut idx = before.clone();
+    let mut delete = None;
+
+    let done = match &args.command.action {
+        A::Add(cmd) => {
+            let name = edit::add(
+                &mut idx,
+                &cmd.path,
+                cmd.source.as_deref(),
+                cmd.offset,
+                cmd.len,
+                &cmd.group,
+                cmd.name.as_deref(),
+                !cmd.no_hash,
+            )?;
+            format!("Added fragment `{name}`.")
+        }
+        A::Rm(cmd) => {
+            let frag = edit::remove(&mut idx, &cmd.fragment, cmd.force)?;
+            if cmd.delete_file {
+                let Some(path) = frag.plain_file().map(str::to_owned) else {
+                    bail!(
+                        "The data of fragment `{}` is not a plain file and cannot be deleted with it.",
+                        cmd.fragment
+                    );
+                };
+                let replica = replica::replica_path(&path);
+                idx.replicas.retain(|r| *r != replica);
+                delete = Some((path, replica));
+            }
+            format!("Removed fragment `{}`.", cmd.fragment)
+        }
+        A::Rename(cmd) => {
+            edit::rename(&mut idx, &cmd.old, &cmd.new)?;
+            format!("Renamed fragment `{}` to `{}`.", cmd.old, cmd.new)
+        }
+        A::Group(FragmentGroupAction::Add(cmd)) => {
+            edit::add_group(&mut idx, &cmd.fragment, &cmd.group)?;
+            format!(
+                "Added fragment `{}` to group `{}`.",
+                cmd.fragment, cmd.group
+            )
+        }
+        A::Group(FragmentGroupAction::Rm(cmd)) => {
+            edit::remove_group(&mut idx, &cmd.fragment, &cmd.group, cmd.force)?;
+            format!(
+                "Removed fragment `{}` from group `{}`.",
+                cmd.fragment, cmd.group
+            )
+        }
+        A::Comment(cmd) => {
+            edit::comment(&mut idx, &cmd.fragment, &cmd.comment)?;
+            format!("Added comment to fragment `{}`.", cmd.fragment)
+        }
+    };
+
+    edit::validate(&before, &idx)?;
+    save_index(&args.index_file, idx, args.format)?;
+    log::info!("{done}");
+
+    // Only delete data once the index no longer refers to it
+    if let Some((path, replica)) = delete {
+        fs::remove_file(&path).with_context(|| format!("Could not delete `{path}`"))?;
+        log::info!("Deleted `{path}`.");
+        match fs::remove_file(&replica) {
+            Ok(()) => log::info!("Deleted index replica `{replica}`."),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Could not delete index replica `{replica}`: {e}"),
+        }
+    }
+
+    Ok(ExitCode::from(0))
+}
+
+fn import_split(args: &CommandInvocation<ImportSplitCommand>) -> Result<(ExitCode, Index)> {
+    let ImportSplitCommand {
+        ref parts,
+        main_size,
+        ref group,
+        no_hash,
+    } = args.command;
+
+    let parts = import::collect_parts(parts)?;
+    for part in parts.iter() {
+        log::info!("Importing `{part}`.");
+    }
+
+    let imported = import::import(args.index.clone(), &parts, main_size, group, !no_hash)?;
+    if let Some(before) = args.index.as_ref() {
+        edit::validate(before, &imported.index)?;
+    }
+
+    match imported.missing {
+        0 => Ok((ExitCode::from(0), imported.index)),
+        missing => {
+            log::warn!("The last {missing} bytes of main are not covered by any part.");
+            Ok((ExitCode::from(3), imported.index))
+        }
+    }
+}
+
+fn export_script(args: &CommandInvocation<ExportScriptCommand>) -> Result<ExitCode> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let ExportScriptCommand {
+        ref group,
+        ref source,
+        ref out,
+    } = args.command;
+    let idx = args.use_index()?;
+    let script = script::generate(&idx, source, group)?;
+
+    match out.as_str() {
+        "-" => std::io::stdout().write_all(script.as_bytes())?,
+        path => {
+            fs::write(path, &script)?;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+            log::info!("Wrote restore script to `{path}`.");
+        }
+    }
+
+    Ok(ExitCode::from(0))
+}
+
+fn convert_index(args: &CommandInvocation<ConvertIndexCommand>) -> Result<ExitCode> {
+    let ConvertIndexCommand { ref input, ref out } = args.command;
+
+    let (idx, from) =
+        format::read(input)?.with_context(|| format!("Index file `{input}` is missing!"))?;
+    let to = args
+        .format
+        .or_else(|| format::Format::from_path(out))
+        .with_context(|| {
+            format!("Cannot tell the format of `{out}` from its extension; specify --format.")
+        })?;
+
+    let data = to.serialize(&idx)?;
+    // Make sure nothing was lost on the way
+    let back = to.deserialize(&data)?;
+    ensure!(
+        serde_json::to_value(&idx)? == serde_json::to_value(&back)?,
+        "Converting the index to {to:?} would lose information."
+    );
+
+    write_atomic(out, data)?;
+    log::info!("Converted `{input}` from {from:?} to {to:?} in `{out}`.");
+    Ok(ExitCode::from(0))
+}
+
+fn migrate_index(args: &CommandInvocation<MigrateIndexCommand>) -> Result<ExitCode> {
+    let MigrateIndexCommand { dry_run } = args.command;
+    let path = &args.index_file;
+
+    let data = fs::read(path).with_context(|| format!("Index file `{path}` is missing!"))?;
+    let format = format::Format::detect(&data);
+    let version = format.version(&data)?;
+    let (mut idx, steps) = format.deserialize_migrating(&data)?;
+    if steps.is_empty() {
+        log::info!(
+            "The index already uses the current schema version {}.",
+            index::SchemaVersion::CURRENT.0
+        );
+        return Ok(ExitCode::from(0));
+    }
+
+    println!(
+        "Upgrading the index from schema version {} to {}:",
+        version.0,
+        index::SchemaVersion::CURRENT.0
+    );
+    for step in steps.iter() {
+        println!("\t{step}");
+    }
+
+    if dry_run {
+        if format != format::Format::Cbor {
+            idx.revision += 1;
+            let new = format.serialize_over(&idx, path)?;
+            println!();
+            print!(
+                "{}",
+                migrate::line_diff(&String::from_utf8_lossy(&data), &String::from_utf8(new)?)?
+            );
+        }
+        return Ok(ExitCode::from(0));
+    }
+
+    save_index(path, idx, Some(format))?;
+    log::info!("Upgraded `{path}`.");
+    Ok(ExitCode::from(0))
+}
+
+fn generations(args: &CommandInvocation<GenerationsCommand>) -> Result<ExitCode> {
+    let idx = args.use_index()?;
+    if idx.generations.is_empty() {
+        log::info!(
+            "The index records no generations yet; they are recorded once a backup completes."
+        );
+    }
+    print!("{}", generation::render(&idx));
+    Ok(ExitCode::from(0))
+}
+
+fn prune(args: &CommandInvocation<PruneCommand>) -> Result<ExitCode> {
+    let PruneCommand {
+        keep_last,
+        keep_monthly,
+        delete_files,
+        dry_run,
+    } = args.command;
+
+    let mut idx = args.use_index()?;
+    let pruned = generation::prune(&mut idx, keep_last, keep_monthly)?;
+    for gen in pruned.generations.iter() {
+        println!("Dropping generation {} from {}", gen.no, gen.time);
+    }
+    for frag in pruned.fragments.iter() {
+        println!("Removing fragment {}", diff::describe(frag));
+    }
+    if pruned.generations.is_empty() {
+        log::info!("All generations are kept; nothing to prune.");
+        return Ok(ExitCode::from(0));
+    }
+
+    // Files may hold several fragments; only those no remaining fragment uses can go
+    let mut delete = vec![];
+    if delete_files {
+        for frag in pruned.fragments.iter() {
+            // Chunks may be shared with other fragments, so they stay in the store
+            let Some(path) = frag.plain_file() else {
+                continue;
+            };
+            let used = idx.fragments.iter().any(|f| f.plain_file() == Some(path));
+            if !used && !delete.iter().any(|p| p == path) {
+                delete.push(path.to_owned());
+            }
+        }
+        let replicas = delete
+            .iter()
+            .map(|p| replica::replica_path(p))
+            .collect::<Vec<_>>();
+        idx.replicas.retain(|r| !replicas.contains(r));
+    }
+
+    if dry_run {
+        for path in delete.iter() {
+            println!("Would delete `{path}`");
+        }
+        return Ok(ExitCode::from(0));
+    }
+
+    save_index(&args.index_file, idx, args.format)?;
+    log::info!(
+        "Dropped {} generation(s) and {} fragment(s).",
+        pruned.generations.len(),
+        pruned.fragments.len()
+    );
+
+    // Only delete data once the index no longer refers to it
+    for path in delete {
+        match fs::remove_file(&path) {
+            Ok(()) => log::info!("Deleted `{path}`."),
+            Err(e) => log::warn!("Could not delete `{path}`: {e}"),
+        }
+        let replica = replica::replica_path(&path);
+        match fs::remove_file(&replica) {
+            Ok(()) => log::info!("Deleted index replica `{replica}`."),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::warn!("Could not delete index replica `{replica}`: {e}"),
+        }
+    }
+
+    Ok(ExitCode::from(0))
+}
+
+/// Persist the index, bumping its revision and refreshing all reachable replicas
+fn save_index(path: &str, mut index: Index, format: Option<format::Format>) -> Result<()> {
+    index.revision += 1;
+    let format = format::Format::for_writing(path, format)?;
+    write_atomic(path, format.serialize_over(&index, path)?)?;
+    // Replicas are always TOML, so they can be read without splitfile
+    replica::write_all(&index, &toml::to_string(&index)?);
+    Ok(())
+}
+
+/// Commands that return an updated index, which is then written to `--index`
+fn command_writes_index(command: &Command) -> bool {
+    use Command as C;
+    matches!(
+        command,
+        C::Create(_)
+            | C::WriteBackup(_)
+            | C::RebuildIndex(_)
+            | C::SyncIndex(_)
+            | C::ImportSplit(_)
+            | C::Refresh(_)
+    )
+}
+
+fn main() -> Result<ExitCode> {
+    pretty_env_logger::formatted_builder()
+        .filter_level(log::LevelFilter::Info)
+        .parse_default_env()
+        .init();
+
+    let cli = CliArgs::parse();
+
+    // TODO: Use open and keep file locked
+    let index_file = cli.index.clone().unwrap_or_default();
+    let index = match index_file.is_empty() {
+        true => None,
+        false => Index::from_file(&index_file)?,
+    };
+    let index_path = || {
+        cli.index
+            .as_deref()
+            .context("This command requires an index; specify one with --index.")
+    };
+    if command_writes_index(&cli.command) {
+        index_path()?;
+    }
+
+    let (status, index) = {
+        use Command as C;
+        match cli.command {
+            C::Create(command) => create(&CommandInvocation {
+                index_file,
+                index,
+                format: cli.format,
+                command,
+            })?,
+            C::WriteBackup(command) => write_backup(&CommandInvocation {
+                index_file,
+                index,
+                format: cli.format,
+                command,
+            })?,
+            C::RebuildIndex(command) => rebuild_index(&CommandInvocation {
+                index_file,
+                index,
+                format: cli.format,
+                command,
+            })?,
+            C::SyncIndex(command) => sync_index(&CommandInvocation {
+                index_file,
+                index,
+                format: cli.format,
+                command,
+            })?,
+            C::ImportSplit(command) => import_split(&CommandInvocation {
+                index_file,
+                index,
+                format: cli.format,
+                command,
+            })?,
+            C::Refresh(command) => refresh(&CommandInvocation {
+                index_file,
+                index,
+                format: cli.format,
+                command,
+            })?,
+            C::DiffIndex(command) => {
+                let status = diff_index(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::MergeIndex(command) => {
+                let status = merge_index(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::Fragment(command) => {
+                let status = fragment(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::ExportScript(command) => {
+                let status = export_script(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::Generations(command) => {
+                let status = generations(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::Prune(command) => {
+                let status = prune(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::MigrateIndex(command) => {
+                let status = migrate_index(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::ConvertIndex(command) => {
+                let status = convert_index(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::Check(command) => {
+                let status = check(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::RestoreFromFragment(command) => {
+                // TODO: Dirty!
+                let status = restore_from_fragment(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
                 return Ok(status);
             }
             C::ValidateHash(command) => {
@@ -550,6 +2948,52 @@ fn main() -> Result<ExitCode> {
                 let status = validate_hash(&CommandInvocation {
                     index_file,
                     index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::Extract(command) => {
+                let status = extract(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::Mount(command) => {
+                let status = mount(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::ServeNbd(command) => {
+                let status = serve_nbd(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::ServeHttp(command) => {
+                let status = serve_http(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
+                    command,
+                })?;
+                return Ok(status);
+            }
+            C::InspectFragment(command) => {
+                let status = inspect_fragment(&CommandInvocation {
+                    index_file,
+                    index,
+                    format: cli.format,
                     command,
                 })?;
                 return Ok(status);
@@ -557,7 +3001,43 @@ fn main() -> Result<ExitCode> {
         }
     };
 
-    fs::write(&cli.index, toml::to_string(&index)?)?;
+    save_index(index_path()?, index, cli.format)?;
 
     Ok(status)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> Result<(Option<u64>, Option<u64>)> {
+        let range: RangeArg = s.parse()?;
+        Ok((range.start, range.end))
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(range("..").unwrap(), (None, None));
+        assert_eq!(range("1K..").unwrap(), (Some(1024), None));
+        assert_eq!(range("..1M").unwrap(), (None, Some(1 << 20)));
+        assert_eq!(range("10..20").unwrap(), (Some(10), Some(20)));
+        assert!(range("10").is_err());
+        assert!(range("a..b").is_err());
+
+        let resolve = |s: &str, len| s.parse::<RangeArg>().unwrap().resolve(len);
+        assert_eq!(
+            resolve("..", 100).unwrap(),
+            index::Slice { start: 0, end: 100 }
+        );
+        assert_eq!(
+            resolve("10..", 100).unwrap(),
+            index::Slice {
+                start: 10,
+                end: 100
+            }
+        );
+        assert!(resolve("20..10", 100).is_err());
+        assert!(resolve("..101", 100).is_err());
+        assert!(resolve("100..", 100).unwrap().start == 100);
+    }
+}
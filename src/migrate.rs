@@ -0,0 +1,190 @@
+//! Upgrade indices written with older layouts
+//!
+//! Every index records the [SchemaVersion] of its layout; indices predating
+//! the version field are [SchemaVersion::LEGACY]. Older indices are upgraded
+//! step by step on their untyped representation before being deserialized,
+//! so each step only has to know the layout it starts from. Indices of a
+//! newer version are refused rather than read with fields silently dropped,
+//! so the version is only raised for changes older readers would misread;
+//! fields they can safely ignore do not need a new version.
+
+use anyhow::{bail, ensure, Result};
+
+use crate::index::SchemaVersion;
+
+struct Migration {
+    /// Version the migration upgrades from, to the next version
+    from: SchemaVersion,
+    description: &'static str,
+    apply: fn(&mut toml::Table) -> Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: SchemaVersion::LEGACY,
+        description: "Record the schema version in the index",
+        // The layout itself is unchanged; the version is stamped by [migrate]
+        apply: |_| Ok(()),
+    },
+    Migration {
+        from: SchemaVersion(2),
+        description: "Allow several sources per index; fragments without a source hold main",
+        // Older indices only have `main`, which is what a missing source refers to
+        apply: |_| Ok(()),
+    },
+    Migration {
+        from: SchemaVersion(3),
+        description: "Record the state of sources and mark outdated fragments as stale",
+        // Sources without a recorded state are not checked for changes
+        apply: |_| Ok(()),
+    },
+    Migration {
+        from: SchemaVersion(4),
+        description: "Let fragments of newer generations override older overlapping ones",
+        // Fragments without a generation are all of generation 0 and must not overlap
+        apply: |_| Ok(()),
+    },
+    Migration {
+        from: SchemaVersion(5),
+        description: "Allow fragments kept as chunks in a content addressed store",
+        // All existing fragments are plain files
+        apply: |_| Ok(()),
+    },
+];
+
+/// Refuse to read indices written by a newer version of splitfile
+pub fn ensure_supported(version: SchemaVersion) -> Result<()> {
+    if version > SchemaVersion::CURRENT {
+        bail!(
+            "The index uses schema version {} but this version of splitfile only \
+            understands up to version {}; refusing to open it so no data is lost. \
+            Please upgrade splitfile.",
+            version.0,
+            SchemaVersion::CURRENT.0
+        );
+    }
+    Ok(())
+}
+
+/// Upgrade `raw` from `version` to the current version; returns the applied steps
+pub fn migrate(raw: &mut toml::Table, version: SchemaVersion) -> Result<Vec<&'static str>> {
+    ensure_supported(version)?;
+
+    let mut version = version;
+    let mut steps = vec![];
+    while version < SchemaVersion::CURRENT {
+        let step = MIGRATIONS.iter().find(|m| m.from == version);
+        let Some(step) = step else {
+            bail!(
+                "Do not know how to upgrade an index from schema version {}.",
+                version.0
+            );
+        };
+        (step.apply)(raw)?;
+        steps.push(step.description);
+        version = SchemaVersion(version.0 + 1);
+    }
+
+    raw.insert("version".to_owned(), toml::Value::Integer(version.0.into()));
+    Ok(steps)
+}
+
+/// Line based diff of two texts in the style of `diff -u`, with two lines of context
+pub fn line_diff(old: &str, new: &str) -> Result<String> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+    ensure!(
+        old.len().saturating_mul(new.len()) <= 1 << 26,
+        "The index is too large to show a diff."
+    );
+
+    // Longest common subsequence, computed from the back
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = match old[i] == new[j] {
+                true => lcs[i + 1][j + 1] + 1,
+                false => lcs[i + 1][j].max(lcs[i][j + 1]),
+            };
+        }
+    }
+
+    let mut ops = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            ops.push((' ', old[i]));
+            i += 1;
+            j += 1;
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] > lcs[i + 1][j]) {
+            ops.push(('+', new[j]));
+            j += 1;
+        } else {
+            ops.push(('-', old[i]));
+            i += 1;
+        }
+    }
+
+    // Only show changes with a little context around them
+    const CONTEXT: usize = 2;
+    let changed = ops.iter().map(|(op, _)| *op != ' ').collect::<Vec<_>>();
+    let shown = (0..ops.len())
+        .map(|n| {
+            let from = n.saturating_sub(CONTEXT);
+            let to = (n + CONTEXT + 1).min(ops.len());
+            changed[from..to].iter().any(|c| *c)
+        })
+        .collect::<Vec<_>>();
+
+    let mut out = String::new();
+    for (n, (op, line)) in ops.iter().enumerate() {
+        if !shown[n] {
+            continue;
+        }
+        if n > 0 && !shown[n - 1] {
+            out.push_str("…\n");
+        }
+        out.push_str(&format!("{op}{line}\n"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_version_can_be_upgraded() {
+        for from in SchemaVersion::LEGACY.0..=SchemaVersion::CURRENT.0 {
+            let mut raw = toml::Table::new();
+            let steps = migrate(&mut raw, SchemaVersion(from)).unwrap();
+            assert_eq!(steps.len() as u32, SchemaVersion::CURRENT.0 - from);
+            assert_eq!(
+                raw["version"].as_integer(),
+                Some(SchemaVersion::CURRENT.0.into())
+            );
+        }
+
+        let newer = SchemaVersion(SchemaVersion::CURRENT.0 + 1);
+        assert!(ensure_supported(newer).is_err());
+        assert!(migrate(&mut toml::Table::new(), newer).is_err());
+    }
+
+    #[test]
+    fn diffs() {
+        assert_eq!(line_diff("a\nb\n", "a\nb\n").unwrap(), "");
+        assert_eq!(line_diff("", "a\n").unwrap(), "+a\n");
+        assert_eq!(
+            line_diff("a\nb\nc\n", "a\nx\nc\n").unwrap(),
+            " a\n-b\n+x\n c\n"
+        );
+
+        // Distant changes are shown separately, with unchanged lines elided
+        let old = (1..=10).map(|n| format!("{n}\n")).collect::<String>();
+        let new = old.replace("2\n", "two\n").replace("9\n", "nine\n");
+        assert_eq!(
+            line_diff(&old, &new).unwrap(),
+            " 1\n-2\n+two\n 3\n 4\n…\n 7\n 8\n-9\n+nine\n 10\n"
+        );
+    }
+}
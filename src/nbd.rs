@@ -0,0 +1,540 @@
+//! Export main as a block device over the NBD protocol
+//!
+//! Implements the fixed newstyle handshake and simple replies, which every
+//! current client supports. Clients are served one at a time.
+
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpListener;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use anyhow::{bail, Context, Result};
+
+use crate::index::Offset;
+use crate::reader::IndexReader;
+
+const NBDMAGIC: u64 = 0x4e42444d41474943;
+const IHAVEOPT: u64 = 0x49484156454f5054;
+const REPLY_MAGIC: u64 = 0x0003e889045565a9;
+const REQUEST_MAGIC: u32 = 0x25609513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x67446698;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+const FLAG_C_NO_ZEROES: u32 = 1 << 1;
+
+const FLAG_HAS_FLAGS: u16 = 1 << 0;
+const FLAG_READ_ONLY: u16 = 1 << 1;
+const FLAG_SEND_FLUSH: u16 = 1 << 2;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) + 1;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) + 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+
+const EPERM: u32 = 1;
+const EIO: u32 = 5;
+const EINVAL: u32 = 22;
+const ENOSPC: u32 = 28;
+
+/// Largest request we are willing to buffer
+const MAX_REQUEST: u32 = 32 << 20;
+
+/// Granularity at which the overlay tracks written data
+const OVERLAY_BLOCK: u64 = 4096;
+
+/// Where to listen for clients
+#[derive(Clone, Debug)]
+pub enum Listen {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+/// Copy-on-write storage for writes to an otherwise read-only export
+///
+/// Written blocks are stored at their offset in a sparse file; a sidecar
+/// bitmap file next to it records which blocks the overlay holds.
+pub struct Overlay {
+    file: fs::File,
+    map_path: PathBuf,
+    map: Vec<u8>,
+    dirty: bool,
+}
+
+/// The device presented to clients
+pub struct Export {
+    pub name: String,
+    pub reader: IndexReader,
+    pub overlay: Option<Overlay>,
+}
+
+impl Overlay {
+    pub fn open<P: AsRef<Path>>(path: P, len: Offset) -> Result<Self> {
+        let path = path.as_ref();
+        let mut map_path = path.as_os_str().to_owned();
+        map_path.push(".map");
+        let map_path = PathBuf::from(map_path);
+
+        // Check the map before touching the overlay, which may be some other file by mistake
+        let map_len = len.div_ceil(OVERLAY_BLOCK).div_ceil(8) as usize;
+        let mut map = match fs::read(&map_path) {
+            Ok(map) => map,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+            Err(e) => return Err(e).context("Could not read overlay map"),
+        };
+        if !map.is_empty() && map.len() != map_len {
+            bail!(
+                "Overlay map `{}` does not match the size of main.",
+                map_path.display()
+            );
+        }
+        if map.is_empty() && fs::metadata(path).is_ok_and(|meta| meta.len() > 0) {
+            bail!(
+                "`{}` is not empty but has no overlay map `{}`; refusing to use it as an overlay.",
+                path.display(),
+                map_path.display()
+            );
+        }
+        let fresh = map.is_empty();
+        map.resize(map_len, 0);
+
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Could not open overlay `{}`", path.display()))?;
+        file.set_len(len)?;
+        if fresh {
+            fs::write(&map_path, &map).context("Could not create overlay map")?;
+        }
+
+        Ok(Self {
+            file,
+            map_path,
+            map,
+            dirty: false,
+        })
+    }
+
+    fn has(&self, block: u64) -> bool {
+        self.map[(block / 8) as usize] & (1 << (block % 8)) != 0
+    }
+
+    fn mark(&mut self, block: u64) {
+        self.map[(block / 8) as usize] |= 1 << (block % 8);
+        self.dirty = true;
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.file.sync_data()?;
+        if self.dirty {
+            fs::write(&self.map_path, &self.map)?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+impl Export {
+    fn size(&self) -> Offset {
+        self.reader.len()
+    }
+
+    fn read_base(&mut self, off: Offset, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.seek(SeekFrom::Start(off))?;
+        self.reader.read_exact(buf)
+    }
+
+    fn read_at(&mut self, off: Offset, buf: &mut [u8]) -> io::Result<()> {
+        if self.overlay.is_none() {
+            return self.read_base(off, buf);
+        }
+
+        // Split the request into runs of blocks that are all in the overlay or all in the base
+        let end = off + buf.len() as u64;
+        let in_overlay = |export: &Self, pos: Offset| {
+            export
+                .overlay
+                .as_ref()
+                .is_some_and(|o| o.has(pos / OVERLAY_BLOCK))
+        };
+        let mut pos = off;
+        while pos < end {
+            let from_overlay = in_overlay(self, pos);
+            let mut run_end = pos;
+            while run_end < end && in_overlay(self, run_end) == from_overlay {
+                run_end = (run_end / OVERLAY_BLOCK + 1) * OVERLAY_BLOCK;
+            }
+            let run_end = run_end.min(end);
+
+            let chunk = &mut buf[(pos - off) as usize..(run_end - off) as usize];
+            match &self.overlay {
+                Some(overlay) if from_overlay => overlay.file.read_exact_at(chunk, pos)?,
+                _ => self.read_base(pos, chunk)?,
+            }
+            pos = run_end;
+        }
+        Ok(())
+    }
+
+    fn write_at(&mut self, off: Offset, data: &[u8]) -> io::Result<()> {
+        let end = off + data.len() as u64;
+        let first = off / OVERLAY_BLOCK;
+        let last = (end - 1) / OVERLAY_BLOCK;
+
+        // Partially written blocks must be completed with the base data first
+        for block in [first, last] {
+            let start = block * OVERLAY_BLOCK;
+            let stop = (start + OVERLAY_BLOCK).min(self.size());
+            let partial = off > start || end < stop;
+            let present = self.overlay.as_ref().unwrap().has(block);
+            if partial && !present {
+                let mut buf = vec![0; (stop - start) as usize];
+                self.read_base(start, &mut buf)?;
+                let overlay = self.overlay.as_mut().unwrap();
+                overlay.file.write_all_at(&buf, start)?;
+                overlay.mark(block);
+            }
+        }
+
+        let overlay = self.overlay.as_mut().unwrap();
+        overlay.file.write_all_at(data, off)?;
+        for block in first..=last {
+            overlay.mark(block);
+        }
+        Ok(())
+    }
+
+    fn transmission_flags(&self) -> u16 {
+        match self.overlay {
+            Some(_) => FLAG_HAS_FLAGS | FLAG_SEND_FLUSH,
+            None => FLAG_HAS_FLAGS | FLAG_READ_ONLY,
+        }
+    }
+}
+
+fn read_u16<R: Read>(r: &mut R) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32<R: Read>(r: &mut R) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64<R: Read>(r: &mut R) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn reply_opt<W: Write>(w: &mut W, opt: u32, typ: u32, data: &[u8]) -> io::Result<()> {
+    let mut msg = Vec::with_capacity(20 + data.len());
+    msg.extend_from_slice(&REPLY_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&opt.to_be_bytes());
+    msg.extend_from_slice(&typ.to_be_bytes());
+    msg.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    msg.extend_from_slice(data);
+    w.write_all(&msg)
+}
+
+fn reply_simple<W: Write>(w: &mut W, error: u32, handle: u64, data: &[u8]) -> io::Result<()> {
+    let mut msg = Vec::with_capacity(16 + data.len());
+    msg.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+    msg.extend_from_slice(&error.to_be_bytes());
+    msg.extend_from_slice(&handle.to_be_bytes());
+    msg.extend_from_slice(data);
+    w.write_all(&msg)
+}
+
+/// Negotiate options; returns whether the client wants to enter transmission
+fn handshake<S: Read + Write>(conn: &mut S, export: &Export) -> Result<bool> {
+    let mut hello = Vec::with_capacity(18);
+    hello.extend_from_slice(&NBDMAGIC.to_be_bytes());
+    hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+    hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+    conn.write_all(&hello)?;
+
+    let client_flags = read_u32(conn)?;
+    let no_zeroes = client_flags & FLAG_C_NO_ZEROES != 0;
+
+    loop {
+        let magic = read_u64(conn)?;
+        if magic != IHAVEOPT {
+            bail!("Client sent bad option magic {magic:#x}");
+        }
+        let opt = read_u32(conn)?;
+        let len = read_u32(conn)?;
+        if len > 4096 {
+            bail!("Client sent oversized option ({len} bytes)");
+        }
+        let mut data = vec![0; len as usize];
+        conn.read_exact(&mut data)?;
+
+        match opt {
+            OPT_EXPORT_NAME => {
+                let mut reply = Vec::with_capacity(134);
+                reply.extend_from_slice(&export.size().to_be_bytes());
+                reply.extend_from_slice(&export.transmission_flags().to_be_bytes());
+                if !no_zeroes {
+                    reply.extend_from_slice(&[0; 124]);
+                }
+                conn.write_all(&reply)?;
+                return Ok(true);
+            }
+            OPT_ABORT => {
+                reply_opt(conn, opt, REP_ACK, &[])?;
+                return Ok(false);
+            }
+            OPT_LIST => {
+                let mut server = Vec::new();
+                server.extend_from_slice(&(export.name.len() as u32).to_be_bytes());
+                server.extend_from_slice(export.name.as_bytes());
+                reply_opt(conn, opt, REP_SERVER, &server)?;
+                reply_opt(conn, opt, REP_ACK, &[])?;
+            }
+            OPT_INFO | OPT_GO => {
+                let name_len = data
+                    .get(..4)
+                    .map(|b| u32::from_be_bytes(b.try_into().unwrap()) as usize)
+                    .unwrap_or(usize::MAX);
+                let name = data.get(4..4 + name_len);
+                match name {
+                    // The empty name selects the default export
+                    Some(name) if name.is_empty() || name == export.name.as_bytes() => {}
+                    _ => {
+                        reply_opt(conn, opt, REP_ERR_UNKNOWN, b"No such export")?;
+                        continue;
+                    }
+                }
+
+                let mut info = Vec::new();
+                info.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+                info.extend_from_slice(&export.size().to_be_bytes());
+                info.extend_from_slice(&export.transmission_flags().to_be_bytes());
+                reply_opt(conn, opt, REP_INFO, &info)?;
+
+                let mut info = Vec::new();
+                info.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+                info.extend_from_slice(&1u32.to_be_bytes());
+                info.extend_from_slice(&4096u32.to_be_bytes());
+                info.extend_from_slice(&MAX_REQUEST.to_be_bytes());
+                reply_opt(conn, opt, REP_INFO, &info)?;
+
+                reply_opt(conn, opt, REP_ACK, &[])?;
+                if opt == OPT_GO {
+                    return Ok(true);
+                }
+            }
+            _ => reply_opt(conn, opt, REP_ERR_UNSUP, &[])?,
+        }
+    }
+}
+
+fn transmission<S: Read + Write>(conn: &mut S, export: &mut Export) -> Result<()> {
+    loop {
+        let magic = read_u32(conn)?;
+        if magic != REQUEST_MAGIC {
+            bail!("Client sent bad request magic {magic:#x}");
+        }
+        let _flags = read_u16(conn)?;
+        let typ = read_u16(conn)?;
+        let handle = read_u64(conn)?;
+        let off = read_u64(conn)?;
+        let len = read_u32(conn)?;
+
+        let in_bounds = off
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= export.size());
+
+        match typ {
+            CMD_READ => {
+                if !in_bounds || len > MAX_REQUEST {
+                    reply_simple(conn, EINVAL, handle, &[])?;
+                    continue;
+                }
+                let mut buf = vec![0; len as usize];
+                match export.read_at(off, &mut buf) {
+                    Ok(()) => reply_simple(conn, 0, handle, &buf)?,
+                    Err(e) => {
+                        log::error!("Read of {len} bytes at offset {off} failed: {e}");
+                        reply_simple(conn, EIO, handle, &[])?;
+                    }
+                }
+            }
+            CMD_WRITE => {
+                if len > MAX_REQUEST {
+                    bail!("Client sent oversized write ({len} bytes)");
+                }
+                let mut buf = vec![0; len as usize];
+                conn.read_exact(&mut buf)?;
+                let err = match (&export.overlay, in_bounds) {
+                    (None, _) => EPERM,
+                    (_, false) => ENOSPC,
+                    _ if buf.is_empty() => 0,
+                    _ => match export.write_at(off, &buf) {
+                        Ok(()) => 0,
+                        Err(e) => {
+                            log::error!("Write of {len} bytes at offset {off} failed: {e}");
+                            EIO
+                        }
+                    },
+                };
+                reply_simple(conn, err, handle, &[])?;
+            }
+            CMD_FLUSH => {
+                let err = match export.overlay.as_mut().map(Overlay::sync) {
+                    Some(Err(e)) => {
+                        log::error!("Flushing the overlay failed: {e:?}");
+                        EIO
+                    }
+                    _ => 0,
+                };
+                reply_simple(conn, err, handle, &[])?;
+            }
+            CMD_DISC => return Ok(()),
+            _ => reply_simple(conn, EINVAL, handle, &[])?,
+        }
+    }
+}
+
+fn serve_conn<S: Read + Write>(mut conn: S, export: &mut Export) -> Result<()> {
+    if handshake(&mut conn, export)? {
+        transmission(&mut conn, export)?;
+    }
+    Ok(())
+}
+
+fn finish_conn(export: &mut Export, res: Result<()>) {
+    if let Err(e) = res {
+        log::warn!("Client connection ended with an error: {e:?}");
+    }
+    if let Some(Err(e)) = export.overlay.as_mut().map(Overlay::sync) {
+        log::error!("Flushing the overlay failed: {e:?}");
+    }
+    log::info!("Client disconnected.");
+}
+
+/// Socket to remove when the process is terminated by a signal
+static SOCKET_PATH: OnceLock<CString> = OnceLock::new();
+
+extern "C" fn remove_socket(signal: libc::c_int) {
+    // SAFETY: Only async-signal-safe functions are called; the default action terminates us
+    unsafe {
+        if let Some(path) = SOCKET_PATH.get() {
+            libc::unlink(path.as_ptr());
+        }
+        libc::signal(signal, libc::SIG_DFL);
+        libc::raise(signal);
+    }
+}
+
+/// Removes the unix socket once the server stops, whether it returns or gets terminated
+struct SocketGuard(PathBuf);
+
+impl SocketGuard {
+    fn new(path: &Path) -> Result<Self> {
+        let path_c = CString::new(path.as_os_str().as_bytes())?;
+        if SOCKET_PATH.set(path_c).is_ok() {
+            let handler = remove_socket as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            // SAFETY: The handler only touches the path, which is never changed again
+            unsafe {
+                libc::signal(libc::SIGINT, handler);
+                libc::signal(libc::SIGTERM, handler);
+            }
+        }
+        Ok(Self(path.to_owned()))
+    }
+}
+
+impl Drop for SocketGuard {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Remove the socket a crashed server left behind, but nothing else
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !meta.file_type().is_socket() {
+        bail!(
+            "`{}` exists and is not a socket; refusing to replace it.",
+            path.display()
+        );
+    }
+    match UnixStream::connect(path) {
+        Ok(_) => bail!("Another server is listening on `{}`.", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+            log::info!("Removing stale socket `{}`.", path.display());
+            fs::remove_file(path)
+                .with_context(|| format!("Could not remove stale socket `{}`", path.display()))
+        }
+        Err(e) => Err(e).with_context(|| format!("Could not check socket `{}`", path.display())),
+    }
+}
+
+/// Serve the export to clients until the process is terminated
+pub fn serve(mut export: Export, listen: Listen) -> Result<()> {
+    match listen {
+        Listen::Unix(path) => {
+            remove_stale_socket(&path)?;
+            let listener = UnixListener::bind(&path)
+                .with_context(|| format!("Could not listen on `{}`", path.display()))?;
+            let _guard = SocketGuard::new(&path)?;
+            log::info!(
+                "Serving `{}` on unix socket `{}`.",
+                export.name,
+                path.display()
+            );
+            for conn in listener.incoming() {
+                log::info!("Client connected.");
+                let res = conn
+                    .map_err(Into::into)
+                    .and_then(|conn| serve_conn(conn, &mut export));
+                finish_conn(&mut export, res);
+            }
+        }
+        Listen::Tcp(addr) => {
+            let listener = TcpListener::bind(&addr)
+                .with_context(|| format!("Could not listen on `{addr}`"))?;
+            log::info!("Serving `{}` on `{}`.", export.name, listener.local_addr()?);
+            for conn in listener.incoming() {
+                log::info!("Client connected.");
+                let res = conn.map_err(Into::into).and_then(|conn| {
+                    conn.set_nodelay(true)?;
+                    serve_conn(conn, &mut export)
+                });
+                finish_conn(&mut export, res);
+            }
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,276 @@
+//! Rewrite a TOML index while keeping the layout of the existing file
+//!
+//! The new content is produced by serde as usual and then merged into the
+//! document parsed from the old file: values that did not change keep their
+//! formatting, comments and position; changed values are replaced in place;
+//! removed keys are dropped and new ones appended. Fragments are matched by
+//! their names, so comments stay with their fragment when others are added
+//! or removed.
+
+use anyhow::{Context, Result};
+use toml_edit::{ArrayOfTables, Document, InlineTable, Item, Table, Value};
+
+/// Whether two values are equal, disregarding formatting
+fn same_value(a: &Value, b: &Value) -> bool {
+    let parse = |v: &Value| toml::from_str::<toml::Table>(&format!("v = {v}")).ok();
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn merge_value(old: &mut Value, new: Value) {
+    if same_value(old, &new) {
+        return;
+    }
+    match (old, new) {
+        (Value::InlineTable(old), Value::InlineTable(new)) => merge_inline_table(old, new),
+        (old, mut new) => {
+            *new.decor_mut() = old.decor().clone();
+            *old = new;
+        }
+    }
+}
+
+fn merge_inline_table(old: &mut InlineTable, new: InlineTable) {
+    old.retain(|key, _| new.contains_key(key));
+    for (key, value) in new.into_iter() {
+        match old.get_mut(&key) {
+            Some(old) => merge_value(old, value),
+            None => {
+                old.insert(&key, value);
+            }
+        }
+    }
+}
+
+/// Position marking tables taken over from the new document; see [place_new_tables]
+const NEW_TABLE: usize = usize::MAX;
+
+fn mark_new(item: &mut Item) {
+    match item {
+        Item::Table(table) => mark_new_table(table),
+        Item::ArrayOfTables(tables) => tables.iter_mut().for_each(mark_new_table),
+        _ => {}
+    }
+}
+
+fn mark_new_table(table: &mut Table) {
+    table.set_position(NEW_TABLE);
+    for (_, item) in table.iter_mut() {
+        mark_new(item);
+    }
+}
+
+/// Move tables taken over from the new document right behind the table preceding them
+///
+/// Tables are written in the order of their positions, and those of new
+/// tables refer to the new document, which would scatter them among the
+/// old tables. Tables are visited in the order they are written in.
+fn place_new_tables(table: &mut Table, last: &mut usize) {
+    match table.position() {
+        Some(NEW_TABLE) => table.set_position(*last),
+        Some(pos) if !table.is_dotted() => *last = pos,
+        _ => {}
+    }
+    for (_, item) in table.iter_mut() {
+        match item {
+            Item::Table(table) => place_new_tables(table, last),
+            Item::ArrayOfTables(tables) => {
+                for table in tables.iter_mut() {
+                    place_new_tables(table, last);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn merge_table(old: &mut Table, new: Table) {
+    old.retain(|key, _| new.contains_key(key));
+    for (key, mut item) in new.into_iter() {
+        match old.get_mut(&key) {
+            Some(old) => merge_item(old, item),
+            None => {
+                mark_new(&mut item);
+                old.insert(&key, item);
+            }
+        }
+    }
+}
+
+/// Identity of a table in an array of tables; its names if it has any
+fn identity(table: &Table) -> Option<String> {
+    let name = table.get("name")?.as_value()?;
+    let mut name = name.clone();
+    name.decor_mut().clear();
+    Some(name.to_string())
+}
+
+fn merge_array_of_tables(old: &mut ArrayOfTables, new: ArrayOfTables) {
+    let mut olds = old.iter().cloned().map(Some).collect::<Vec<_>>();
+    let mut merged = ArrayOfTables::new();
+
+    for (no, mut table) in new.into_iter().enumerate() {
+        let id = identity(&table);
+        let matching = match id {
+            Some(_) => olds
+                .iter()
+                .position(|o| o.as_ref().is_some_and(|o| identity(o) == id)),
+            None => olds.get(no).and_then(|o| o.as_ref()).map(|_| no),
+        };
+        match matching.and_then(|pos| olds[pos].take()) {
+            Some(mut old) => {
+                merge_table(&mut old, table);
+                merged.push(old);
+            }
+            None => {
+                mark_new_table(&mut table);
+                merged.push(table);
+            }
+        }
+    }
+
+    *old = merged;
+}
+
+fn merge_item(old: &mut Item, new: Item) {
+    match (old, new) {
+        (Item::Value(old), Item::Value(new)) => merge_value(old, new),
+        (Item::Table(old), Item::Table(new)) => merge_table(old, new),
+        (Item::ArrayOfTables(old), Item::ArrayOfTables(new)) => merge_array_of_tables(old, new),
+        // Keep tables written inline by hand inline
+        (Item::Value(Value::InlineTable(old)), Item::Table(new)) => {
+            merge_inline_table(old, new.into_inline_table())
+        }
+        (old, mut new) => {
+            mark_new(&mut new);
+            *old = new;
+        }
+    }
+}
+
+/// Merge `new` into the document `old`, keeping the formatting of `old` wherever possible
+pub fn update(old: &str, new: &str) -> Result<String> {
+    let mut doc = old
+        .parse::<Document>()
+        .context("Could not parse the existing index for updating")?;
+    let new = new.parse::<Document>()?;
+    merge_table(doc.as_table_mut(), new.as_table().clone());
+    place_new_tables(doc.as_table_mut(), &mut 0);
+    Ok(doc.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OLD: &str = r#"# My backup index
+version = 6
+comment = ["old"]   # describe me
+
+# The disk
+[[fragments]]
+name = ["main"]
+path = "/dev/sda"
+start = 0
+end = 100
+hashes = { Sha3_256 = "aaa" }
+
+# First half, on the red disk
+[[fragments]]
+name = ["a"]
+path = "/red/a"
+start = 0
+end = 50
+
+# Second half, on the blue disk
+[[fragments]]
+name = ["b"]
+path = "/blue/b"
+start = 50
+end = 100
+"#;
+
+    #[test]
+    fn unchanged_index_is_kept_verbatim() {
+        // The new content comes from serde and lacks comments and alignment
+        let serialized = toml::to_string(&toml::from_str::<toml::Table>(OLD).unwrap()).unwrap();
+        assert_eq!(update(OLD, &serialized).unwrap(), OLD);
+    }
+
+    #[test]
+    fn changes_keep_comments_with_their_fragment() {
+        let new = r#"
+version = 6
+comment = ["new"]
+
+[[fragments]]
+name = ["main"]
+path = "/dev/sda"
+start = 0
+end = 100
+hashes = { Sha3_256 = "bbb" }
+
+[[fragments]]
+name = ["b"]
+path = "/green/b"
+start = 50
+end = 100
+
+[[fragments]]
+name = ["c"]
+path = "/green/c"
+start = 0
+end = 50
+"#;
+        let updated = update(OLD, new).unwrap();
+        assert_eq!(
+            updated,
+            r#"# My backup index
+version = 6
+comment = ["new"]   # describe me
+
+# The disk
+[[fragments]]
+name = ["main"]
+path = "/dev/sda"
+start = 0
+end = 100
+hashes = { Sha3_256 = "bbb" }
+
+# Second half, on the blue disk
+[[fragments]]
+name = ["b"]
+path = "/green/b"
+start = 50
+end = 100
+
+[[fragments]]
+name = ["c"]
+path = "/green/c"
+start = 0
+end = 50
+"#
+        );
+        assert_eq!(
+            toml::from_str::<toml::Table>(&updated).unwrap(),
+            toml::from_str::<toml::Table>(new).unwrap()
+        );
+    }
+
+    #[test]
+    fn new_tables_follow_their_predecessor() {
+        let old = "[a]\nx = 1 # keep\n\n[c]\nz = 3\n";
+        let new = "[a]\nx = 1\n\n[a.b]\ny = 2\n\n[c]\nz = 3\n";
+        assert_eq!(
+            update(old, new).unwrap(),
+            "[a]\nx = 1 # keep\n\n[a.b]\ny = 2\n\n[c]\nz = 3\n"
+        );
+    }
+
+    #[test]
+    fn invalid_old_index() {
+        assert!(update("[[broken", "x = 1").is_err());
+    }
+}
@@ -0,0 +1,653 @@
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Seek, SeekFrom};
+
+use anyhow::{bail, Context, Result};
+use sha3::Digest;
+
+use crate::cache::{self, CacheMode};
+use crate::chunks::ChunkReader;
+use crate::copy::encode_hash;
+use crate::diff::fragment_key;
+use crate::index::{
+    ChunkStore, Fragment, Generation, HashIdentifier, Index, LocationData, Offset, Slice,
+};
+use crate::util::{read_nointr, ReadSeek};
+
+/// What an [IndexReader] should do when asked for data that no fragment covers
+///
+/// This includes data missing from fragment files that are shorter than
+/// their geometry says.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Uncovered {
+    /// Fail the read with an error
+    #[default]
+    Error,
+    /// Pretend the uncovered range is filled with zeros
+    Zeros,
+}
+
+/// A fragment as seen by the reader: Which range of main it holds and where
+#[derive(Clone, Debug)]
+struct Extent {
+    geometry: Slice,
+    holes: Vec<Slice>,
+    path: String,
+    /// Offset of the data within the file
+    offset: Offset,
+    hash: Option<String>,
+    generation: u64,
+    /// Set if the data is kept in a chunk store rather than the file at `path`
+    chunks: Option<ChunkStore>,
+}
+
+struct OpenExtent {
+    no: usize,
+    file: Box<dyn ReadSeek>,
+    /// Position of `file` expressed as an offset into main
+    pos: Offset,
+    /// Running hash of the extent and the offset into main up to which it was hashed
+    hasher: Option<(sha3::Sha3_256, Offset)>,
+    /// The file turned out shorter than the geometry, which was reported already
+    short: bool,
+}
+
+/// A piece of main together with the place it is stored
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub range: Slice,
+    /// File holding the data and the offset of `range.start` in that file; `None` if uncovered
+    pub source: Option<(String, Offset)>,
+}
+
+enum Location {
+    Covered { no: usize, until: Offset },
+    Uncovered { until: Offset },
+}
+
+/// Random access to the main file, reassembled from a set of fragments
+///
+/// Offsets are offsets into main; each read is mapped through the fragment
+/// geometry to the file holding the data. When an entire fragment is read
+/// sequentially, its data is checked against the hash stored in the index.
+pub struct IndexReader {
+    extents: Vec<Extent>,
+    len: Offset,
+    pos: Offset,
+    uncovered: Uncovered,
+    verify: bool,
+    cache: CacheMode,
+    open: Option<OpenExtent>,
+}
+
+impl Extent {
+    fn from_fragment(frag: &Fragment) -> Self {
+        let (path, chunks) = match &frag.location.data {
+            LocationData::ChunkStore(chunks) => (
+                format!("{}/manifests/{}", chunks.store, chunks.manifest),
+                Some(chunks.clone()),
+            ),
+            _ => (frag.filepath().to_owned(), None),
+        };
+        Self {
+            geometry: frag.geometry,
+            holes: frag.holes.clone(),
+            path,
+            offset: frag.data_offset(),
+            hash: frag.hashes.get(&HashIdentifier::Sha3_256).cloned(),
+            generation: frag.generation,
+            chunks,
+        }
+    }
+}
+
+impl OpenExtent {
+    fn open(no: usize, ext: &Extent, verify: bool, cache: CacheMode) -> IoResult<Self> {
+        let mut file: Box<dyn ReadSeek> = match &ext.chunks {
+            Some(chunks) => Box::new(ChunkReader::new(chunks).map_err(|e| {
+                IoError::new(
+                    ErrorKind::NotFound,
+                    format!("Could not open fragment `{}`: {e:#}", ext.path),
+                )
+            })?),
+            None => cache::open_read(&ext.path, cache).map_err(|e| {
+                IoError::new(
+                    e.kind(),
+                    format!("Could not open fragment `{}`: {e}", ext.path),
+                )
+            })?,
+        };
+        if ext.offset != 0 {
+            file.seek(SeekFrom::Start(ext.offset))?;
+        }
+        let hasher = (verify && ext.hash.is_some() && ext.holes.is_empty())
+            .then(|| (sha3::Sha3_256::default(), ext.geometry.start));
+        Ok(Self {
+            no,
+            file,
+            pos: ext.geometry.start,
+            hasher,
+            short: false,
+        })
+    }
+}
+
+/// The source named `name`, or an error listing the sources to choose from
+fn find_source<'a>(idx: &'a Index, name: &str) -> Result<&'a Fragment> {
+    let source = idx.get_fragment_by_name(name).with_context(|| {
+        format!(
+            "The index has no source `{name}`; choose one of its sources with --source: {}",
+            idx.sources()
+                .filter_map(|source| source.meta.name.first())
+                .map(|name| format!("`{name}`"))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    })?;
+    Ok(source.get(idx))
+}
+
+impl IndexReader {
+    /// Reader over the fragments of a group holding data of the source `source`
+    pub fn for_source(idx: &Index, source: &str, group: &str) -> Result<Self> {
+        let source = find_source(idx, source)?;
+        let mut reader = Self::from_fragments(
+            idx.fragments
+                .iter()
+                .filter(|frag| frag.in_group(group) && frag.carries(source) && !frag.stale),
+        );
+        reader.len = source.geometry.end;
+        Ok(reader)
+    }
+
+    /// Reader over the state of the source `source` in generation `gen`
+    ///
+    /// Made up of the fragments of `group` up to that generation, including
+    /// those that became stale later on.
+    pub fn for_generation(
+        idx: &Index,
+        source: &str,
+        group: &str,
+        gen: &Generation,
+    ) -> Result<Self> {
+        if !gen.groups.iter().any(|g| g == group) {
+            bail!(
+                "Generation {} was not backed up to group `{group}`, only to: {}.",
+                gen.no,
+                gen.groups.join(", ")
+            );
+        }
+        let source = find_source(idx, source)?;
+        let key = fragment_key(source);
+        let Some(state) = gen.sources.get(&key) else {
+            bail!("Generation {} does not include source `{key}`.", gen.no);
+        };
+        let mut reader = Self::from_fragments(idx.fragments.iter().filter(|frag| {
+            frag.in_group(group) && frag.carries(source) && frag.generation <= gen.no
+        }));
+        reader.len = state.len;
+        Ok(reader)
+    }
+
+    /// Reader over an arbitrary set of fragments; the length is the end of the last fragment
+    pub fn from_fragments<'a, I>(fragments: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Fragment>,
+    {
+        let mut extents = fragments
+            .into_iter()
+            .map(Extent::from_fragment)
+            .collect::<Vec<_>>();
+        extents.sort_by_key(|ext| (ext.geometry.start, ext.geometry.end));
+        let len = extents
+            .iter()
+            .map(|ext| ext.geometry.end)
+            .max()
+            .unwrap_or(0);
+
+        Self {
+            extents,
+            len,
+            pos: 0,
+            uncovered: Uncovered::Error,
+            verify: true,
+            cache: CacheMode::Buffered,
+            open: None,
+        }
+    }
+
+    pub fn with_uncovered(mut self, uncovered: Uncovered) -> Self {
+        self.uncovered = uncovered;
+        self
+    }
+
+    pub fn with_verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// How fragment files are read with respect to the page cache
+    pub fn with_cache(mut self, cache: CacheMode) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Length of the main file
+    pub fn len(&self) -> Offset {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Parts of `range` that are not covered by any fragment
+    pub fn gaps(&self, range: Slice) -> Vec<Slice> {
+        let mut gaps = vec![];
+        let mut pos = range.start;
+        while pos < range.end {
+            match self.locate(pos) {
+                Location::Covered { until, .. } => pos = until.min(range.end),
+                Location::Uncovered { until } => {
+                    let end = until.min(range.end);
+                    gaps.push(Slice { start: pos, end });
+                    pos = end;
+                }
+            }
+        }
+        gaps
+    }
+
+    /// How `range` maps onto the fragment files, in order
+    pub fn segments(&self, range: Slice) -> Vec<Segment> {
+        let mut segments = vec![];
+        let mut pos = range.start;
+        while pos < range.end {
+            let (end, source) = match self.locate(pos) {
+                Location::Covered { no, until } => {
+                    let ext = &self.extents[no];
+                    let offset = ext.offset + pos - ext.geometry.start;
+                    (until, Some((ext.path.clone(), offset)))
+                }
+                Location::Uncovered { until } => (until, None),
+            };
+            let end = end.min(range.end);
+            segments.push(Segment {
+                range: Slice { start: pos, end },
+                source,
+            });
+            pos = end;
+        }
+        segments
+    }
+
+    fn holds(ext: &Extent, pos: Offset) -> bool {
+        ext.geometry.start <= pos
+            && pos < ext.geometry.end
+            && !ext.holes.iter().any(|h| h.start <= pos && pos < h.end)
+    }
+
+    /// Offset after `pos` at which `ext` starts holding data
+    fn next_start(ext: &Extent, pos: Offset) -> Option<Offset> {
+        let in_geometry = ext.geometry.start <= pos && pos < ext.geometry.end;
+        let start = (ext.geometry.start > pos).then_some(ext.geometry.start);
+        let hole_end = ext
+            .holes
+            .iter()
+            .filter(|h| in_geometry && h.start <= pos && pos < h.end)
+            .map(|h| h.end)
+            .min();
+        [start, hole_end].into_iter().flatten().min()
+    }
+
+    fn locate(&self, pos: Offset) -> Location {
+        let open = self.open.as_ref().map(|o| o.no);
+
+        // Newer generations take precedence; among equals, prefer the extent we already have
+        // open to avoid needless reopening
+        let found = (0..self.extents.len())
+            .filter(|no| Self::holds(&self.extents[*no], pos))
+            .max_by_key(|no| {
+                let ext = &self.extents[*no];
+                (ext.generation, Some(*no) == open, std::cmp::Reverse(*no))
+            });
+        if let Some(no) = found {
+            let ext = &self.extents[no];
+            let until = ext
+                .holes
+                .iter()
+                .filter(|h| h.start > pos)
+                .map(|h| h.start)
+                .chain(
+                    self.extents
+                        .iter()
+                        .filter(|other| other.generation > ext.generation)
+                        .filter_map(|other| Self::next_start(other, pos)),
+                )
+                .fold(ext.geometry.end, Offset::min);
+            return Location::Covered { no, until };
+        }
+
+        // Not covered; find where the next piece of data starts
+        let until = self
+            .extents
+            .iter()
+            .filter_map(|ext| Self::next_start(ext, pos))
+            .fold(self.len, Offset::min);
+        Location::Uncovered { until }
+    }
+
+    fn read_extent(&mut self, no: usize, until: Offset, buf: &mut [u8]) -> IoResult<usize> {
+        let pos = self.pos;
+        let ext = &self.extents[no];
+        if self.open.as_ref().map(|o| o.no) != Some(no) {
+            self.open = Some(OpenExtent::open(no, ext, self.verify, self.cache)?);
+        }
+        let open = self.open.as_mut().unwrap();
+
+        if open.pos != pos {
+            open.file
+                .seek(SeekFrom::Start(ext.offset + pos - ext.geometry.start))?;
+            open.pos = pos;
+        }
+
+        let want = buf.len().min((until - pos) as usize);
+        let buf = &mut buf[..want];
+        let mut got = read_nointr(&mut open.file, buf)?;
+        if got == 0 {
+            let problem = format!(
+                "Fragment `{}` ends before offset {pos} even though its geometry is {:?}",
+                ext.path, ext.geometry
+            );
+            if self.uncovered == Uncovered::Error {
+                return Err(IoError::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("{problem}."),
+                ));
+            }
+            if !open.short {
+                log::warn!(
+                    "{problem}; reading {pos}..{} as zeros instead.",
+                    ext.geometry.end
+                );
+                open.short = true;
+            }
+            buf.fill(0);
+            got = want;
+        }
+        open.pos += got as u64;
+
+        // Only sequential reads from the start of the fragment can be verified
+        open.hasher = match open.hasher.take() {
+            Some((mut hasher, hashed)) if hashed == pos => {
+                hasher.update(&buf[..got]);
+                Some((hasher, hashed + got as u64))
+            }
+            _ => None,
+        };
+
+        if let Some((_, hashed)) = open.hasher {
+            if hashed == ext.geometry.end {
+                let (hasher, _) = open.hasher.take().unwrap();
+                let hash = encode_hash(hasher.finalize());
+                let ref_hash = ext.hash.as_ref().unwrap();
+                if hash != *ref_hash {
+                    return Err(IoError::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Mismatch between hash and reference for fragment `{}`: \
+                            ref={ref_hash:?}, hash={hash:?}",
+                            ext.path
+                        ),
+                    ));
+                }
+                log::debug!("Verified hash of fragment `{}`.", ext.path);
+            }
+        }
+
+        Ok(got)
+    }
+}
+
+impl Read for IndexReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let got = match self.locate(self.pos) {
+            Location::Covered { no, until } => self.read_extent(no, until, buf)?,
+            Location::Uncovered { until } => match self.uncovered {
+                Uncovered::Zeros => {
+                    let len = buf.len().min((until - self.pos) as usize);
+                    buf[..len].fill(0);
+                    len
+                }
+                Uncovered::Error => {
+                    return Err(IoError::new(
+                        ErrorKind::UnexpectedEof,
+                        format!(
+                            "Range {}..{until} is not covered by any fragment.",
+                            self.pos
+                        ),
+                    ))
+                }
+            },
+        };
+
+        self.pos += got as u64;
+        Ok(got)
+    }
+}
+
+impl Seek for IndexReader {
+    fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
+        let new = match pos {
+            SeekFrom::Start(dst) => Some(dst),
+            SeekFrom::Current(dst) => self.pos.checked_add_signed(dst),
+            SeekFrom::End(dst) => self.len.checked_add_signed(dst),
+        };
+
+        self.pos = new.ok_or_else(|| {
+            IoError::new(
+                ErrorKind::InvalidInput,
+                "Invalid seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::*;
+    use crate::blocks::hash_block;
+    use crate::util::scratch_dir;
+
+    fn index(toml: &str) -> Index {
+        let mut idx: Index = toml::from_str(toml).unwrap();
+        idx.normalize();
+        idx
+    }
+
+    /// A fragment of group `backup` stored in `dir/name`
+    fn fragment(dir: &Path, name: &str, range: (u64, u64), extra: &str) -> String {
+        format!(
+            r#"
+            [[fragments]]
+            name = ["{name}"]
+            type = "File"
+            path = "{dir}/{name}"
+            groups = ["backup"]
+            start = {start}
+            end = {end}
+            {extra}
+            "#,
+            dir = dir.display(),
+            start = range.0,
+            end = range.1,
+        )
+    }
+
+    fn main_source(dir: &Path, len: u64) -> String {
+        format!(
+            r#"
+            [[fragments]]
+            name = ["main"]
+            type = "File"
+            path = "{dir}/main"
+            groups = ["main"]
+            start = 0
+            end = {len}
+            "#,
+            dir = dir.display(),
+        )
+    }
+
+    fn read_all(reader: &mut IndexReader) -> IoResult<Vec<u8>> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    fn backup_reader(idx: &Index) -> IndexReader {
+        IndexReader::from_fragments(idx.fragments.iter().filter(|f| f.in_group("backup")))
+    }
+
+    #[test]
+    fn reads_across_fragments_and_verifies_hashes() {
+        let dir = scratch_dir("reader-verify");
+        let data = (0..40u8).collect::<Vec<_>>();
+        fs::write(dir.join("a"), &data[..15]).unwrap();
+        fs::write(dir.join("b"), &data[15..]).unwrap();
+        let hash = |d: &[u8]| format!("hashes = {{ Sha3_256 = \"{}\" }}", hash_block(d));
+        let idx = index(&format!(
+            "{}{}",
+            fragment(&dir, "a", (0, 15), &hash(&data[..15])),
+            fragment(&dir, "b", (15, 40), &hash(&data[15..])),
+        ));
+
+        let mut reader = backup_reader(&idx);
+        assert_eq!(reader.len(), 40);
+        assert_eq!(read_all(&mut reader).unwrap(), data);
+
+        // Seeking into the middle reads the right data, but cannot verify the first fragment
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        assert_eq!(read_all(&mut reader).unwrap(), &data[10..]);
+
+        fs::write(dir.join("b"), vec![0u8; 25]).unwrap();
+        let err = read_all(&mut backup_reader(&idx)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let mut reader = backup_reader(&idx).with_verify(false);
+        assert_eq!(read_all(&mut reader).unwrap().len(), 40);
+    }
+
+    #[test]
+    fn uncovered_ranges_and_holes() {
+        let dir = scratch_dir("reader-gaps");
+        fs::write(dir.join("a"), [1u8; 10]).unwrap();
+        fs::write(dir.join("b"), [2u8; 10]).unwrap();
+        let idx = index(&format!(
+            "{}{}",
+            fragment(&dir, "a", (0, 10), "holes = [{ start = 4, end = 6 }]"),
+            fragment(&dir, "b", (20, 30), ""),
+        ));
+
+        let reader = backup_reader(&idx);
+        assert_eq!(
+            reader.gaps(Slice { start: 0, end: 30 }),
+            [Slice { start: 4, end: 6 }, Slice { start: 10, end: 20 }]
+        );
+        assert!(reader.gaps(Slice { start: 20, end: 30 }).is_empty());
+        let segments = reader.segments(Slice { start: 2, end: 25 });
+        let ranges = segments
+            .iter()
+            .map(|s| (s.range.start, s.range.end, s.source.as_ref().map(|s| s.1)))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ranges,
+            [
+                (2, 4, Some(2)),
+                (4, 6, None),
+                (6, 10, Some(6)),
+                (10, 20, None),
+                (20, 25, Some(0)),
+            ]
+        );
+
+        let err = read_all(&mut backup_reader(&idx)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let mut reader = backup_reader(&idx).with_uncovered(Uncovered::Zeros);
+        let mut expected = [[1u8; 10], [0; 10], [2; 10]].concat();
+        expected[4..6].fill(0);
+        assert_eq!(read_all(&mut reader).unwrap(), expected);
+    }
+
+    #[test]
+    fn short_fragment_files() {
+        let dir = scratch_dir("reader-short");
+        fs::write(dir.join("a"), [1u8; 6]).unwrap();
+        fs::write(dir.join("b"), [2u8; 10]).unwrap();
+        let idx = index(&format!(
+            "{}{}",
+            fragment(&dir, "a", (0, 10), ""),
+            fragment(&dir, "b", (10, 20), ""),
+        ));
+
+        let err = read_all(&mut backup_reader(&idx)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        let mut reader = backup_reader(&idx).with_uncovered(Uncovered::Zeros);
+        let expected = [&[1u8; 6][..], &[0; 4], &[2; 10]].concat();
+        assert_eq!(read_all(&mut reader).unwrap(), expected);
+    }
+
+    #[test]
+    fn newer_generations_take_precedence() {
+        let dir = scratch_dir("reader-generations");
+        fs::write(dir.join("base"), [1u8; 30]).unwrap();
+        fs::write(dir.join("delta"), [2u8; 10]).unwrap();
+        fs::write(dir.join("tail"), [3u8; 10]).unwrap();
+        let idx = index(&format!(
+            r#"
+            {}{}{}{}
+            [[generations]]
+            no = 1
+            time = "2024-01-01T00:00:00Z"
+            groups = ["backup"]
+            sources = {{ main = {{ len = 30 }} }}
+
+            [[generations]]
+            no = 2
+            time = "2024-01-02T00:00:00Z"
+            groups = ["backup"]
+            sources = {{ main = {{ len = 40 }} }}
+            "#,
+            main_source(&dir, 40),
+            fragment(&dir, "base", (0, 30), "generation = 1"),
+            fragment(&dir, "delta", (10, 20), "generation = 2"),
+            fragment(&dir, "tail", (30, 40), "generation = 2"),
+        ));
+
+        let latest = [[1u8; 10], [2; 10], [1; 10], [3; 10]].concat();
+        let mut reader = IndexReader::for_source(&idx, "main", "backup").unwrap();
+        assert_eq!(read_all(&mut reader).unwrap(), latest);
+
+        let mut reader =
+            IndexReader::for_generation(&idx, "main", "backup", &idx.generations[0]).unwrap();
+        assert_eq!(reader.len(), 30);
+        assert_eq!(read_all(&mut reader).unwrap(), [1u8; 30]);
+
+        let mut reader =
+            IndexReader::for_generation(&idx, "main", "backup", &idx.generations[1]).unwrap();
+        assert_eq!(read_all(&mut reader).unwrap(), latest);
+
+        assert!(IndexReader::for_generation(&idx, "main", "other", &idx.generations[1]).is_err());
+        let err = IndexReader::for_source(&idx, "missing", "backup")
+            .err()
+            .unwrap();
+        assert!(format!("{err:#}").contains("`main`"));
+    }
+}
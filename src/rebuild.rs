@@ -0,0 +1,563 @@
+//! Recover an index by scanning media for fragment files
+//!
+//! Self-describing fragments are recognized by their container header. Plain
+//! fragments can only be recognized with the help of a skeleton: an older
+//! copy of the index that still knows the length and hash of each fragment.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::chunks;
+use crate::container;
+use crate::copy::hash_data;
+use crate::diff::describe_location;
+use crate::index::{File, Fragment, HashIdentifier, Index, LocationData, Meta, Offset, Slice};
+use crate::replica;
+use crate::util::pretty_path;
+
+/// Outcome of a rebuild
+pub struct Rebuilt {
+    pub index: Index,
+    /// Human readable descriptions of conflicting or unusable files
+    pub conflicts: Vec<String>,
+}
+
+struct Candidate {
+    path: String,
+    len: Offset,
+    container: Option<container::Container>,
+    /// Set for index replicas, which are never fragments themselves
+    replica: Option<Index>,
+}
+
+fn file_len(path: &Path) -> Result<Offset> {
+    // Block devices report a size of zero through metadata, seeking works for both
+    let mut file = fs::File::open(path)?;
+    Ok(file.seek(SeekFrom::End(0))?)
+}
+
+fn scan(path: &Path, out: &mut Vec<Candidate>) -> Result<()> {
+    let meta =
+        fs::metadata(path).with_context(|| format!("Could not access `{}`", path.display()))?;
+
+    if meta.is_dir() && chunks::Store::is_store(path) {
+        log::info!(
+            "Not scanning chunk store `{}`; its fragments are taken from the skeleton.",
+            path.display()
+        );
+        return Ok(());
+    }
+    if meta.is_dir() {
+        let mut entries = fs::read_dir(path)?.collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort_by_key(|e| e.file_name());
+        for entry in entries {
+            if let Err(e) = scan(&entry.path(), out) {
+                log::warn!("Skipping `{}`: {e:?}", entry.path().display());
+            }
+        }
+        return Ok(());
+    }
+
+    let path = pretty_path(fs::canonicalize(path)?);
+    if replica::is_replica_path(&path) {
+        match replica::load(&path) {
+            Ok(replica) => out.push(Candidate {
+                len: 0,
+                path,
+                container: None,
+                replica,
+            }),
+            Err(e) => log::warn!("Ignoring unreadable index replica: {e:?}"),
+        }
+        return Ok(());
+    }
+
+    let container = container::read_header_from(&path)
+        .inspect_err(|e| log::warn!("Ignoring unreadable fragment header in `{path}`: {e:?}"))
+        .ok()
+        .flatten();
+    out.push(Candidate {
+        len: file_len(Path::new(&path))?,
+        path,
+        container,
+        replica: None,
+    });
+    Ok(())
+}
+
+fn relocate(mut frag: Fragment, path: &str) -> Fragment {
+    frag.location.data = File {
+        device: None,
+        path: path.to_owned(),
+    }
+    .as_location_data();
+    frag
+}
+
+/// Combine the given skeleton with all index copies found on the media; the newest copy wins
+fn choose_skeleton(
+    skeleton: Option<Index>,
+    uuid: Option<&str>,
+    candidates: &[Candidate],
+) -> Result<(Option<Index>, Option<String>)> {
+    let copies = candidates
+        .iter()
+        .filter_map(|c| c.container.as_ref().and_then(|c| c.index.as_ref()))
+        .chain(candidates.iter().filter_map(|c| c.replica.as_ref()));
+
+    let mut uuids = candidates
+        .iter()
+        .filter_map(|c| c.container.as_ref())
+        .map(|c| c.header.index.as_str())
+        .chain(
+            copies
+                .clone()
+                .filter_map(|idx| idx.meta.uuid().map(String::as_str)),
+        )
+        .collect::<Vec<_>>();
+    uuids.sort();
+    uuids.dedup();
+
+    let skeleton_uuid = skeleton.as_ref().and_then(|s| s.meta.uuid());
+    let uuid = match (uuid.or(skeleton_uuid.map(String::as_str)), uuids.as_slice()) {
+        (Some(uuid), _) => uuid.to_owned(),
+        (None, []) => return Ok((skeleton, None)),
+        (None, [uuid]) => uuid.to_string(),
+        (None, uuids) => bail!(
+            "Found fragments of several indices ({}); select one with --uuid.",
+            uuids.join(", ")
+        ),
+    };
+
+    let copies = copies
+        .filter(|idx| idx.meta.uuid() == Some(&uuid))
+        .cloned()
+        .chain(skeleton)
+        .collect::<Vec<_>>();
+    Ok((replica::merge_newest(copies), Some(uuid)))
+}
+
+pub fn rebuild<P: AsRef<Path>>(
+    paths: &[P],
+    skeleton: Option<Index>,
+    uuid: Option<&str>,
+    with_hash: bool,
+) -> Result<Rebuilt> {
+    let mut candidates = vec![];
+    for path in paths {
+        scan(path.as_ref(), &mut candidates)?;
+    }
+    log::info!("Scanned {} files.", candidates.len());
+
+    let (skeleton, uuid) = choose_skeleton(skeleton, uuid, &candidates)?;
+
+    let sources = skeleton
+        .iter()
+        .flat_map(|s| s.sources().cloned())
+        .collect::<Vec<_>>();
+    let source_paths = sources
+        .iter()
+        .filter_map(|s| match &s.location.data {
+            LocationData::File(File { path, .. }) => Some(path.clone()),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    // Only needed to recreate main when there is no skeleton
+    let mut main_len = None;
+
+    let mut conflicts = vec![];
+    let mut found: Vec<Fragment> = vec![];
+    let mut seen = HashSet::new();
+
+    // Self-describing fragments first; they are unambiguous
+    for cand in candidates.iter() {
+        let Some(cont) = &cand.container else {
+            continue;
+        };
+        let header = &cont.header;
+        if uuid.as_ref().is_some_and(|uuid| *uuid != header.index) {
+            log::info!(
+                "Ignoring `{}`, which belongs to index {}.",
+                cand.path,
+                header.index
+            );
+            continue;
+        }
+        let expected = match sources.iter().find(|s| header.fragment.carries(s)) {
+            Some(source) => Some(source.geometry.end),
+            None if !sources.is_empty() || header.fragment.source.is_some() => {
+                conflicts.push(format!(
+                    "`{}` holds data of the unknown source `{}`.",
+                    cand.path,
+                    header.fragment.source_name()
+                ));
+                continue;
+            }
+            None => main_len,
+        };
+        match expected {
+            Some(len) if len != header.main_len => {
+                conflicts.push(format!(
+                    "`{}` claims a main length of {} instead of {len}.",
+                    cand.path, header.main_len
+                ));
+                continue;
+            }
+            Some(_) => {}
+            None => main_len = Some(header.main_len),
+        }
+        seen.insert(cand.path.clone());
+        found.push(relocate(header.fragment.clone(), &cand.path));
+    }
+
+    // Plain fragments are matched by size and hash against the skeleton
+    let expected = skeleton
+        .iter()
+        .flat_map(|s| s.fragments.iter())
+        .filter(|frag| !frag.in_group("main"))
+        .filter(|frag| !found.iter().any(|f| f.meta.name == frag.meta.name))
+        .collect::<Vec<_>>();
+    let mut hashes: HashMap<(String, Offset, Offset), String> = HashMap::new();
+    // Files holding several fragments and the path they had in the skeleton
+    let mut packed: HashMap<String, String> = HashMap::new();
+    for frag in expected {
+        // Chunk stores are not scanned; their fragments are kept as long as all chunks are there
+        if let LocationData::ChunkStore(chunks) = &frag.location.data {
+            match chunks::Store::open(&chunks.store).and_then(|s| s.stored_len(&chunks.manifest)) {
+                Ok(len) if len == frag.geometry.len() => found.push(frag.clone()),
+                Ok(len) => conflicts.push(format!(
+                    "Fragment {:?} in chunk store `{}` holds {len} bytes instead of {}.",
+                    frag.meta.name,
+                    chunks.store,
+                    frag.geometry.len()
+                )),
+                Err(e) => conflicts.push(format!(
+                    "Fragment {:?} in chunk store `{}` cannot be read: {e:#}",
+                    frag.meta.name, chunks.store
+                )),
+            }
+            continue;
+        }
+
+        // Fragments packed into one file are found by the length of the whole file
+        let (file_len, range) = match frag.location.slice {
+            Some(slice) => {
+                let file_len = skeleton
+                    .iter()
+                    .flat_map(|s| s.fragments.iter())
+                    .filter(|f| f.plain_file().is_some() && f.plain_file() == frag.plain_file())
+                    .filter_map(|f| f.location.slice.map(|s| s.end))
+                    .max()
+                    .unwrap_or(slice.end);
+                (file_len, slice)
+            }
+            None => {
+                let len = frag.geometry.len();
+                (len, Slice { start: 0, end: len })
+            }
+        };
+        let sliced = frag.location.slice.is_some();
+
+        let matching = candidates
+            .iter()
+            .filter(|c| c.container.is_none() && c.replica.is_none())
+            .filter(|c| !seen.contains(&c.path))
+            .filter(|c| match packed.get(&c.path) {
+                Some(path) => sliced && Some(path.as_str()) == frag.plain_file(),
+                None => true,
+            })
+            .filter(|c| !source_paths.contains(&c.path))
+            .filter(|c| c.len == file_len)
+            .collect::<Vec<_>>();
+
+        let ref_hash = frag.hashes.get(&HashIdentifier::Sha3_256);
+        let mut hit = None;
+        match (with_hash, ref_hash) {
+            (true, Some(ref_hash)) => {
+                for cand in matching {
+                    let key = (cand.path.clone(), range.start, range.end);
+                    let hash = match hashes.get(&key) {
+                        Some(hash) => hash.clone(),
+                        None => {
+                            log::info!("Hashing `{}`…", cand.path);
+                            let mut file = fs::File::open(&cand.path)?;
+                            file.seek(SeekFrom::Start(range.start))?;
+                            let hash = hash_data(file.take(range.len()))?;
+                            hashes.insert(key, hash.clone());
+                            hash
+                        }
+                    };
+                    if hash == *ref_hash {
+                        hit = Some(cand);
+                        break;
+                    }
+                }
+            }
+            // Without a hash, a matching size is all we have to go on, unless several files match
+            _ => {
+                let known = matching.iter().find(|c| packed.contains_key(&c.path));
+                match (known, matching.as_slice()) {
+                    (Some(cand), _) | (None, [cand]) => hit = Some(*cand),
+                    (None, []) => {}
+                    (None, several) => conflicts.push(format!(
+                        "Fragment {:?} could be any of {} by size, which is all there is to go on \
+                        without its hash; it is not recovered.",
+                        frag.meta.name,
+                        several
+                            .iter()
+                            .map(|c| format!("`{}`", c.path))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )),
+                }
+            }
+        }
+
+        if let Some(cand) = hit {
+            let frag = match sliced {
+                true => {
+                    let path = frag.plain_file().unwrap_or_default().to_owned();
+                    packed.insert(cand.path.clone(), path);
+                    relocate(frag.clone(), &cand.path)
+                }
+                false => {
+                    seen.insert(cand.path.clone());
+                    let mut frag = relocate(frag.clone(), &cand.path);
+                    frag.location.slice = None;
+                    frag
+                }
+            };
+            found.push(frag);
+        }
+    }
+
+    // Report duplicates and files claiming the same range of main
+    found.sort_by_key(|f| (f.geometry.start, f.geometry.end));
+    let mut fragments: Vec<Fragment> = vec![];
+    for frag in found {
+        if let Some(dup) = fragments.iter().find(|f| f.meta.name == frag.meta.name) {
+            conflicts.push(format!(
+                "`{}` and `{}` both hold fragment {:?}; keeping the former.",
+                describe_location(&dup.location.data),
+                describe_location(&frag.location.data),
+                frag.meta.name
+            ));
+            continue;
+        }
+        for other in fragments.iter() {
+            // Newer generations take precedence over the ranges they overlap
+            let shared_group = frag.groups.iter().any(|g| other.in_group(g))
+                && frag.source_name() == other.source_name()
+                && frag.generation == other.generation
+                && !frag.stale
+                && !other.stale;
+            let overlap = frag.geometry.start < other.geometry.end
+                && other.geometry.start < frag.geometry.end;
+            if shared_group && overlap {
+                conflicts.push(format!(
+                    "`{}` ({}..{}) and `{}` ({}..{}) claim the same range of main in the same group.",
+                    describe_location(&other.location.data),
+                    other.geometry.start,
+                    other.geometry.end,
+                    describe_location(&frag.location.data),
+                    frag.geometry.start,
+                    frag.geometry.end
+                ));
+            }
+        }
+        fragments.push(frag);
+    }
+
+    let sources = match (sources.is_empty(), main_len) {
+        (false, _) => sources,
+        (true, Some(main_len)) => vec![Fragment {
+            meta: Meta {
+                name: vec!["main".to_owned(), crate::util::uuidgen()],
+                comment: vec![
+                    "Location of the main file is unknown; recovered by rebuild-index".to_owned(),
+                ],
+            },
+            groups: vec!["main".to_owned()],
+            stale: false,
+            generation: 0,
+            source: None,
+            location: File::default().as_location(),
+            hashes: HashMap::new(),
+            geometry: Slice {
+                start: 0,
+                end: main_len,
+            },
+            holes: vec![],
+            stat: None,
+            blocks: None,
+            extra: Default::default(),
+        }],
+        (true, None) => {
+            bail!("Found no fragments and no skeleton; cannot determine the main file.")
+        }
+    };
+
+    let (mut meta, revision, mut replicas, generations) = skeleton
+        .map(|s| (s.meta, s.revision, s.replicas, s.generations))
+        .unwrap_or_default();
+    for cand in candidates.iter().filter(|c| c.replica.is_some()) {
+        let consistent = cand.replica.as_ref().unwrap().meta.uuid() == uuid.as_ref();
+        if consistent && !replicas.contains(&cand.path) {
+            replicas.push(cand.path.clone());
+        }
+    }
+    if let Some(uuid) = uuid {
+        if !meta.is_named(&uuid) {
+            meta.name.push(uuid);
+        }
+    }
+    meta.comment.push(format!(
+        "Rebuilt by scanning: {}",
+        paths
+            .iter()
+            .map(|p| p.as_ref().display().to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    ));
+
+    let mut index = Index {
+        version: Default::default(),
+        meta,
+        revision,
+        replicas,
+        fragments: sources,
+        generations,
+        extra: Default::default(),
+    };
+    index.fragments.extend(fragments);
+
+    Ok(Rebuilt { index, conflicts })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::hash_block;
+    use crate::util::scratch_dir;
+
+    fn index(toml: &str) -> Index {
+        let mut idx: Index = toml::from_str(toml).unwrap();
+        idx.normalize();
+        idx
+    }
+
+    #[test]
+    fn packed_group_next_to_chunk_store() {
+        let dir = scratch_dir("rebuild-packed-chunks");
+        let media = dir.join("media");
+        fs::create_dir(&media).unwrap();
+        let data = (0..30u8).collect::<Vec<_>>();
+        fs::write(media.join("packed"), &data).unwrap();
+
+        let skeleton = index(&format!(
+            r#"
+            [[fragments]]
+            name = ["main"]
+            type = "File"
+            path = "{dir}/main"
+            groups = ["main"]
+            start = 0
+            end = 30
+
+            [[fragments]]
+            name = ["first"]
+            type = "File"
+            path = "/gone/packed"
+            slice = {{ start = 0, end = 10 }}
+            groups = ["backup"]
+            start = 0
+            end = 10
+            hashes = {{ Sha3_256 = "{first}" }}
+
+            [[fragments]]
+            name = ["second"]
+            type = "File"
+            path = "/gone/packed"
+            slice = {{ start = 10, end = 30 }}
+            groups = ["backup"]
+            start = 10
+            end = 30
+            hashes = {{ Sha3_256 = "{second}" }}
+
+            [[fragments]]
+            name = ["chunked"]
+            type = "ChunkStore"
+            store = "{dir}/store"
+            manifest = "missing"
+            groups = ["store"]
+            start = 0
+            end = 30
+            "#,
+            dir = dir.display(),
+            first = hash_block(&data[..10]),
+            second = hash_block(&data[10..]),
+        ));
+
+        let rebuilt = rebuild(&[&media], Some(skeleton), None, true).unwrap();
+        let packed = pretty_path(fs::canonicalize(media.join("packed")).unwrap());
+        for name in ["first", "second"] {
+            let frag = rebuilt.index.get_fragment_by_name(name).unwrap();
+            assert_eq!(frag.get(&rebuilt.index).plain_file(), Some(packed.as_str()));
+        }
+        // The store does not exist, which is reported rather than guessed around
+        assert_eq!(rebuilt.conflicts.len(), 1);
+        assert!(rebuilt.conflicts[0].contains("chunk store"));
+    }
+
+    #[test]
+    fn incremental_delta_is_no_conflict() {
+        let dir = scratch_dir("rebuild-incremental");
+        let base = (0..30u8).collect::<Vec<_>>();
+        let delta = vec![0xffu8; 10];
+        fs::write(dir.join("base"), &base).unwrap();
+        fs::write(dir.join("delta"), &delta).unwrap();
+        let old = vec![7u8; 30];
+        fs::write(dir.join("old"), &old).unwrap();
+
+        let fragment = |name: &str, data: &[u8], geometry: (u64, u64), extra: &str| {
+            format!(
+                r#"
+                [[fragments]]
+                name = ["{name}"]
+                type = "File"
+                path = "/gone/{name}"
+                groups = ["backup"]
+                start = {}
+                end = {}
+                hashes = {{ Sha3_256 = "{}" }}
+                {extra}
+                "#,
+                geometry.0,
+                geometry.1,
+                hash_block(data)
+            )
+        };
+        let skeleton = index(&format!(
+            r#"
+            [[fragments]]
+            name = ["main"]
+            type = "File"
+            path = "/elsewhere/main"
+            groups = ["main"]
+            start = 0
+            end = 30
+            {}{}{}"#,
+            fragment("base", &base, (0, 30), "generation = 1"),
+            fragment("delta", &delta, (10, 20), "generation = 2"),
+            fragment("old", &old, (0, 30), "stale = true"),
+        ));
+
+        let rebuilt = rebuild(&[&dir], Some(skeleton), None, true).unwrap();
+        assert!(rebuilt.conflicts.is_empty(), "{:?}", rebuilt.conflicts);
+        assert_eq!(rebuilt.index.fragments.len(), 4);
+    }
+}
@@ -0,0 +1,76 @@
+//! Copies of the index kept next to the fragments on each backup medium
+//!
+//! Every copy carries the index revision, which is incremented whenever the
+//! index is saved. When copies disagree, the one with the highest revision
+//! wins; fragments only known to older copies are carried over.
+
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::index::Index;
+use crate::util::write_atomic;
+
+/// Suffix of index replicas, appended to the path of the fragment they accompany
+pub const SUFFIX: &str = ".index.toml";
+
+pub fn replica_path(fragment_path: &str) -> String {
+    format!("{fragment_path}{SUFFIX}")
+}
+
+pub fn is_replica_path(path: &str) -> bool {
+    path.ends_with(SUFFIX)
+}
+
+/// Load a replica; `Ok(None)` if it does not exist
+pub fn load(path: &str) -> Result<Option<Index>> {
+    Index::from_file(path)
+}
+
+/// Whether `copy` describes the same main file as `idx`
+pub fn is_consistent(idx: &Index, copy: &Index) -> bool {
+    match (idx.meta.uuid(), copy.meta.uuid()) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Combine several copies of an index; the newest copy wins
+pub fn merge_newest(mut copies: Vec<Index>) -> Option<Index> {
+    copies.sort_by_key(|idx| (idx.revision, idx.fragments.len()));
+    let mut newest = copies.pop()?;
+
+    for copy in copies.into_iter().rev() {
+        for frag in copy.fragments {
+            if !newest
+                .fragments
+                .iter()
+                .any(|f| f.meta.name == frag.meta.name)
+            {
+                newest.fragments.push(frag);
+            }
+        }
+        for replica in copy.replicas {
+            if !newest.replicas.contains(&replica) {
+                newest.replicas.push(replica);
+            }
+        }
+    }
+
+    Some(newest)
+}
+
+/// Write `serialized` to every replica whose medium is currently reachable
+pub fn write_all(idx: &Index, serialized: &str) {
+    for replica in idx.replicas.iter() {
+        let reachable = Path::new(replica).parent().is_some_and(|dir| dir.is_dir());
+        if !reachable {
+            log::info!("Index replica `{replica}` is not reachable; run sync-index once it is.");
+            continue;
+        }
+        match write_atomic(replica, serialized) {
+            Ok(()) => log::debug!("Updated index replica `{replica}`."),
+            Err(e) => log::warn!("Could not update index replica `{replica}`: {e:?}"),
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Generate a standalone POSIX shell script that restores a source from a group
+//!
+//! The script only needs `dd` to reassemble the source. Hashes are checked with
+//! `sha3sum` if it is available; the index records SHA3-256 hashes, which
+//! `sha256sum` cannot verify.
+
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{ensure, Result};
+use base64::Engine;
+
+use crate::diff::fragment_key;
+use crate::index::{Fragment, HashIdentifier, Index, LocationData, Offset, Slice};
+use crate::reader::IndexReader;
+
+/// Block size `dd` copies the bulk of every transfer in
+const BULK_BLOCK: Offset = 1 << 20;
+
+const PRELUDE: &str = r#"set -eu
+
+out=${1:-main.restored}
+
+# Print the SHA3-256 hash of stdin in hex, or `unavailable`
+sha3() {
+    if command -v sha3sum >/dev/null 2>&1; then
+        sha3sum -a 256 | cut -d ' ' -f 1
+    else
+        cat >/dev/null
+        echo unavailable
+    fi
+}
+
+# check WHAT EXPECTED: compare the hash of stdin with EXPECTED
+check() {
+    actual=$(sha3)
+    if [ "$actual" = unavailable ]; then
+        echo "WARNING: Cannot verify $1; install sha3sum." >&2
+    elif [ "$actual" != "$2" ]; then
+        echo "ERROR: $1 is corrupted: expected SHA3-256 $2 but got $actual." >&2
+        exit 1
+    else
+        echo "$1: hash ok"
+    fi
+}
+
+# wait_for FILE: prompt until FILE is readable
+wait_for() {
+    while [ ! -r "$1" ]; do
+        echo "Cannot read $1; insert and mount the medium, then press enter." >&2
+        read -r _ || exit 1
+    done
+}
+"#;
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn hex_hash(hash: &str) -> Option<String> {
+    let digest = base64::engine::general_purpose::URL_SAFE_NO_PAD
+        .decode(hash)
+        .ok()?;
+    Some(digest.iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Commands copying `count` bytes from `skip` in the input to `seek` in the output
+///
+/// The `dd` invocations share the file offsets: the first one only moves them,
+/// the others copy a short head up to the next [BULK_BLOCK] boundary of the
+/// input, the aligned bulk and the remaining tail. Without an output, the
+/// data goes to stdout.
+fn dd(input: &str, output: Option<&str>, skip: Offset, seek: Offset, count: Offset) -> String {
+    let mut steps = vec![];
+    if skip > 0 || seek > 0 {
+        let mut position = "dd bs=1".to_owned();
+        if skip > 0 {
+            position.push_str(&format!(" skip={skip}"));
+        }
+        if seek > 0 {
+            position.push_str(&format!(" seek={seek}"));
+        }
+        steps.push(format!("{position} count=0"));
+    }
+    let head = ((BULK_BLOCK - skip % BULK_BLOCK) % BULK_BLOCK).min(count);
+    let (bulk, tail) = ((count - head) / BULK_BLOCK, (count - head) % BULK_BLOCK);
+    if head > 0 {
+        steps.push(format!("dd bs={head} count=1"));
+    }
+    if bulk > 0 {
+        steps.push(format!("dd bs={BULK_BLOCK} count={bulk}"));
+    }
+    if tail > 0 {
+        steps.push(format!("dd bs={tail} count=1"));
+    }
+    if steps.is_empty() {
+        steps.push(":".to_owned());
+    }
+
+    let mut cmd = format!("{{ {}; }} <{input}", steps.join(" && "));
+    if let Some(output) = output {
+        cmd.push_str(&format!(" 1<>{output}"));
+    }
+    cmd.push_str(" 2>/dev/null");
+    cmd
+}
+
+fn sha3_hex(frag: &Fragment) -> Option<String> {
+    frag.hashes
+        .get(&HashIdentifier::Sha3_256)
+        .and_then(|h| hex_hash(h))
+}
+
+/// Render the restore script for the fragments of `group` holding data of `source`
+pub fn generate(idx: &Index, source: &str, group: &str) -> Result<String> {
+    let reader = IndexReader::for_source(idx, source, group)?;
+    let target = idx.get_fragment_by_name(source)?.get(idx);
+    let all = Slice {
+        start: 0,
+        end: reader.len(),
+    };
+
+    let gaps = reader.gaps(all);
+    ensure!(
+        gaps.is_empty(),
+        "The fragments in group `{group}` do not cover `{source}`; missing: {}",
+        gaps.iter()
+            .map(|g| format!("{}..{}", g.start, g.end))
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let frags = idx
+        .fragments
+        .iter()
+        .filter(|f| f.in_group(group) && f.carries(target) && !f.stale)
+        .collect::<Vec<_>>();
+    ensure!(
+        !frags
+            .iter()
+            .any(|f| matches!(f.location.data, LocationData::ChunkStore(_))),
+        "Group `{group}` has fragments in a chunk store, which cannot be restored with standard tools."
+    );
+    let segments = reader.segments(all);
+
+    let mut s = String::new();
+    let _ = writeln!(s, "#!/bin/sh");
+    let _ = writeln!(s, "# Restore script generated by splitfile");
+    let _ = writeln!(s, "#");
+    for line in idx.meta.name.iter() {
+        let _ = writeln!(s, "# Index: {line}");
+    }
+    for line in idx.meta.comment.iter() {
+        let _ = writeln!(s, "#   {line}");
+    }
+    let _ = writeln!(
+        s,
+        "# Reassembles `{source}` ({} bytes) from the {} fragment(s) of group `{group}`",
+        all.end,
+        frags.len()
+    );
+    let _ = writeln!(s, "# in {} step(s).", segments.len());
+    let _ = writeln!(s, "#");
+    let _ = writeln!(s, "# Usage: sh restore.sh [OUTPUT]");
+    let _ = writeln!(
+        s,
+        "#   OUTPUT is a file or block device of at least {} bytes; defaults to main.restored.",
+        all.end
+    );
+    let _ = writeln!(
+        s,
+        "#   Each step names the medium it needs; the script waits until it is available."
+    );
+    let _ = writeln!(s);
+    s.push_str(PRELUDE);
+
+    for (no, seg) in segments.iter().enumerate() {
+        // Coverage was checked above
+        let (path, offset) = seg.source.as_ref().unwrap();
+        let frag = frags
+            .iter()
+            .find(|f| {
+                f.filepath() == path
+                    && f.geometry.start <= seg.range.start
+                    && seg.range.start < f.geometry.end
+            })
+            .unwrap();
+        let name = fragment_key(frag);
+        let medium = Path::new(path)
+            .parent()
+            .map(|p| p.display().to_string())
+            .unwrap_or_default();
+
+        let _ = writeln!(s);
+        let _ = writeln!(
+            s,
+            "# Step {} of {}: bytes {}..{} of `{source}`",
+            no + 1,
+            segments.len(),
+            seg.range.start,
+            seg.range.end
+        );
+        let _ = writeln!(s, "#   Fragment {name}, stored in {path}");
+        for line in frag.meta.comment.iter() {
+            let _ = writeln!(s, "#   {line}");
+        }
+        let _ = writeln!(
+            s,
+            "echo {}",
+            quote(&format!(
+                "Step {} of {}: insert the medium mounted at {medium} holding {path}.",
+                no + 1,
+                segments.len()
+            ))
+        );
+        let _ = writeln!(s, "wait_for {}", quote(path));
+
+        // Only a fragment used as a whole can be checked against its own hash
+        let whole = seg.range == frag.geometry && frag.holes.is_empty();
+        if let (true, Some(hash)) = (whole, sha3_hex(frag)) {
+            let _ = writeln!(
+                s,
+                "{} | check {} {hash}",
+                dd(&quote(path), None, *offset, 0, seg.range.len()),
+                quote(&format!("fragment {name}"))
+            );
+        }
+        let _ = writeln!(
+            s,
+            "{}",
+            dd(
+                &quote(path),
+                Some("\"$out\""),
+                *offset,
+                seg.range.start,
+                seg.range.len()
+            )
+        );
+    }
+
+    let _ = writeln!(s);
+    match sha3_hex(target) {
+        Some(hash) => {
+            let _ = writeln!(
+                s,
+                "{} | check \"$out\" {hash}",
+                dd("\"$out\"", None, 0, 0, all.end)
+            );
+        }
+        None => {
+            let _ = writeln!(
+                s,
+                "echo {} >&2",
+                quote(&format!(
+                    "The index holds no hash of `{source}`; the result cannot be verified."
+                ))
+            );
+        }
+    }
+    let _ = writeln!(s, "echo \"Restored \"{}\" to $out.\"", quote(source));
+
+    Ok(s)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::process::Command;
+
+    use super::*;
+    use crate::util::scratch_dir;
+
+    #[test]
+    fn dd_splits_at_bulk_blocks() {
+        assert_eq!(dd("in", None, 0, 0, 0), "{ :; } <in 2>/dev/null");
+        assert_eq!(
+            dd("in", Some("out"), 0, 0, 100),
+            "{ dd bs=100 count=1; } <in 1<>out 2>/dev/null"
+        );
+        assert_eq!(
+            dd("in", None, 0, 0, 2 * BULK_BLOCK),
+            format!("{{ dd bs={BULK_BLOCK} count=2; }} <in 2>/dev/null")
+        );
+        assert_eq!(
+            dd("in", Some("out"), 10, 20, 2 * BULK_BLOCK),
+            format!(
+                "{{ dd bs=1 skip=10 seek=20 count=0 && dd bs={head} count=1 \
+                && dd bs={BULK_BLOCK} count=1 && dd bs=10 count=1; }} <in 1<>out 2>/dev/null",
+                head = BULK_BLOCK - 10
+            )
+        );
+        // A short copy within one block is all head
+        assert_eq!(
+            dd("in", None, BULK_BLOCK + 5, 0, 7),
+            format!(
+                "{{ dd bs=1 skip={} count=0 && dd bs=7 count=1; }} <in 2>/dev/null",
+                BULK_BLOCK + 5
+            )
+        );
+    }
+
+    #[test]
+    fn dd_copies_the_range() {
+        let dir = scratch_dir("script-dd");
+        let data = (0..3 * BULK_BLOCK + 123)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        fs::write(dir.join("in"), &data).unwrap();
+        fs::write(dir.join("out"), vec![0xffu8; 64]).unwrap();
+
+        let (skip, seek, count) = (1000, 16, 2 * BULK_BLOCK + 77);
+        let input = quote(dir.join("in").to_str().unwrap());
+        let output = quote(dir.join("out").to_str().unwrap());
+        let status = Command::new("sh")
+            .arg("-c")
+            .arg(dd(&input, Some(&output), skip, seek, count))
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let out = fs::read(dir.join("out")).unwrap();
+        assert_eq!(out.len() as u64, seek + count);
+        assert_eq!(out[..seek as usize], [0xff; 16]);
+        assert_eq!(
+            out[seek as usize..],
+            data[skip as usize..(skip + count) as usize]
+        );
+    }
+
+    #[test]
+    fn quoting_and_hashes() {
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(hex_hash("AAEC_w").as_deref(), Some("000102ff"));
+        assert_eq!(hex_hash("not base64!"), None);
+    }
+}
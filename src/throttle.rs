@@ -0,0 +1,228 @@
+//! Keeping backups from starving other users of the disks
+//!
+//! [Throttle] limits the rate data is read at with a token bucket; in
+//! adaptive mode it also backs off while reads of the source take longer
+//! than they used to, which is the sign of other processes competing for the
+//! disk. [IoNice] lowers the I/O priority of the whole process instead,
+//! which only schedulers honoring priorities, like BFQ, act on.
+
+use std::io::{Read, Result as IoResult};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, ensure, Context, Result};
+use indicatif::{BinaryBytes, ProgressBar, ProgressStyle};
+
+use crate::util::parse_size;
+
+/// Time worth of data that may pass at once after a pause
+const BURST: Duration = Duration::from_millis(100);
+
+/// Interval over which read latency is averaged in adaptive mode
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Reads taking this many times as long as the fastest window make the throttle back off
+const SLOWDOWN: f64 = 2.0;
+
+/// The rate never drops below this fraction of the limit
+const MIN_FRACTION: f64 = 1.0 / 16.0;
+
+/// Lowest rate accepted; below it a single read of a block sleeps for minutes
+pub const MIN_RATE: u64 = 64 << 10;
+
+/// Parse a rate in bytes per second from the command line
+pub fn parse_rate(s: &str) -> Result<u64> {
+    let rate = parse_size(s)?;
+    ensure!(
+        rate >= MIN_RATE,
+        "The rate must be at least {MIN_RATE} bytes per second."
+    );
+    Ok(rate)
+}
+
+/// Read latency of the current window and the lowest seen so far, in seconds per byte
+struct Latency {
+    start: Instant,
+    busy: Duration,
+    bytes: u64,
+    best: Option<f64>,
+}
+
+/// A token bucket limiting the rate of data passing through [Throttled] readers
+pub struct Throttle {
+    /// Configured limit in bytes per second
+    limit: f64,
+    /// Rate currently allowed; below `limit` while backing off
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+    latency: Option<Latency>,
+    progress: Option<ProgressBar>,
+}
+
+impl Throttle {
+    /// Allow `limit` bytes per second; with `adaptive`, less while the source slows down
+    pub fn new(limit: u64, adaptive: bool) -> Self {
+        let now = Instant::now();
+        Self {
+            limit: limit as f64,
+            rate: limit as f64,
+            tokens: 0.0,
+            last: now,
+            latency: adaptive.then_some(Latency {
+                start: now,
+                busy: Duration::ZERO,
+                bytes: 0,
+                best: None,
+            }),
+            progress: None,
+        }
+    }
+
+    /// Show the effective rate against the limit on `progress`
+    pub fn watch(&mut self, progress: &ProgressBar) {
+        let style = ProgressStyle::with_template(
+            "{msg} {wide_bar} {binary_bytes}/{binary_total_bytes} at {binary_bytes_per_sec} {prefix}",
+        )
+        .expect("the template is valid");
+        progress.set_style(style);
+        self.progress = Some(progress.clone());
+        self.show_rate();
+    }
+
+    fn show_rate(&self) {
+        let Some(progress) = &self.progress else {
+            return;
+        };
+        let limit = BinaryBytes(self.limit as u64);
+        progress.set_prefix(match self.rate < self.limit {
+            true => format!(
+                "(limit {limit}/s, backed off to {}/s)",
+                BinaryBytes(self.rate as u64)
+            ),
+            false => format!("(limit {limit}/s)"),
+        });
+    }
+
+    /// Account for `n` bytes that took `took` to read, sleeping as long as the rate demands
+    fn pass(&mut self, n: usize, took: Duration) {
+        self.adapt(n, took);
+
+        let now = Instant::now();
+        let refill = now.duration_since(self.last).as_secs_f64() * self.rate;
+        self.tokens = (self.tokens + refill).min(self.rate * BURST.as_secs_f64());
+        self.last = now;
+
+        self.tokens -= n as f64;
+        if self.tokens < 0.0 {
+            std::thread::sleep(Duration::from_secs_f64(-self.tokens / self.rate));
+        }
+    }
+
+    /// Back off while reads take longer than in the fastest window, recover otherwise
+    fn adapt(&mut self, n: usize, took: Duration) {
+        let Some(latency) = self.latency.as_mut() else {
+            return;
+        };
+        latency.busy += took;
+        latency.bytes += n as u64;
+        if latency.start.elapsed() < WINDOW || latency.bytes == 0 {
+            return;
+        }
+
+        let current = latency.busy.as_secs_f64() / latency.bytes as f64;
+        let best = *latency.best.get_or_insert(current);
+        latency.best = Some(best.min(current));
+        (latency.start, latency.busy, latency.bytes) = (Instant::now(), Duration::ZERO, 0);
+
+        let rate = match current > best * SLOWDOWN {
+            true => (self.rate / 2.0).max(self.limit * MIN_FRACTION),
+            false => (self.rate + self.limit / 8.0).min(self.limit),
+        };
+        if rate != self.rate {
+            log::debug!(
+                "Source reads take {:.1} times as long as at best, rate is now {}/s.",
+                current / best,
+                BinaryBytes(rate as u64)
+            );
+            self.rate = rate;
+            self.show_rate();
+        }
+    }
+}
+
+/// A reader passing its data through a [Throttle], if any
+pub struct Throttled<'a, R> {
+    inner: R,
+    throttle: Option<&'a mut Throttle>,
+}
+
+impl<'a, R: Read> Throttled<'a, R> {
+    pub fn new(inner: R, throttle: Option<&'a mut Throttle>) -> Self {
+        Self { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for Throttled<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        let Some(throttle) = self.throttle.as_mut() else {
+            return self.inner.read(buf);
+        };
+        let start = Instant::now();
+        let n = self.inner.read(buf)?;
+        throttle.pass(n, start.elapsed());
+        Ok(n)
+    }
+}
+
+/// I/O scheduling class and level of the process, as set by `ionice`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IoNice {
+    /// Only get disk time when no one else needs it
+    Idle,
+    /// The default class, with levels from 0 (highest) to 7 (lowest)
+    BestEffort(u8),
+}
+
+impl FromStr for IoNice {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        match (class, level) {
+            ("idle", None) => Ok(Self::Idle),
+            ("best-effort", None) => Ok(Self::BestEffort(4)),
+            ("best-effort", Some(level)) => match level.parse() {
+                Ok(level @ 0..=7) => Ok(Self::BestEffort(level)),
+                _ => bail!("The best-effort level must be between 0 and 7, not `{level}`."),
+            },
+            _ => bail!("Unknown I/O priority `{s}`; use `idle` or `best-effort:N`."),
+        }
+    }
+}
+
+impl IoNice {
+    /// Apply the priority to all I/O of this process with `ioprio_set`
+    pub fn apply(self) -> Result<()> {
+        // From linux/ioprio.h, which the libc crate does not cover
+        const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+        const IOPRIO_CLASS_SHIFT: u32 = 13;
+        let (class, level): (libc::c_long, libc::c_long) = match self {
+            Self::BestEffort(level) => (2, level.into()),
+            Self::Idle => (3, 0),
+        };
+        let prio = (class << IOPRIO_CLASS_SHIFT) | level;
+
+        // SAFETY: ioprio_set only takes integers; a `who` of 0 is the calling process
+        let res = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) };
+        if res != 0 {
+            return Err(std::io::Error::last_os_error())
+                .with_context(|| format!("Could not set the I/O priority to {self:?}"));
+        }
+        log::debug!("Set the I/O priority to {self:?}.");
+        Ok(())
+    }
+}
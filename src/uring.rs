@@ -0,0 +1,373 @@
+//! Copying through io_uring with several reads and writes in flight
+//!
+//! The portable copy loop issues one `read` and one `write` at a time, which
+//! leaves fast devices idle most of the time. [UringReader] keeps reading
+//! ahead into a set of buffers and [UringWriter] keeps writing them out in
+//! the background, so the copy loop in between only hashes. The buffers are
+//! registered with the kernel, which saves mapping them for every request.
+//! Both fail to construct if the kernel does not support io_uring, in which
+//! case callers fall back to plain files.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read, Result as IoResult, Write};
+use std::os::fd::AsRawFd;
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+/// Number of buffers, which is also the number of requests in flight
+const DEPTH: usize = 8;
+
+/// Size of every buffer
+const BUF_SIZE: usize = 1 << 20;
+
+/// A ring with [DEPTH] registered buffers of [BUF_SIZE] bytes
+struct Ring {
+    ring: IoUring,
+    bufs: Vec<Vec<u8>>,
+    /// Requests submitted but not completed yet
+    in_flight: usize,
+}
+
+impl Ring {
+    fn new() -> IoResult<Self> {
+        let ring = IoUring::new(DEPTH as u32)?;
+        let mut bufs = vec![vec![0; BUF_SIZE]; DEPTH];
+        let iovecs = bufs
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr().cast(),
+                iov_len: buf.len(),
+            })
+            .collect::<Vec<_>>();
+        // SAFETY: The buffers are never reallocated and outlive the ring, see [Ring::drop]
+        unsafe { ring.submitter().register_buffers(&iovecs)? };
+        Ok(Self {
+            ring,
+            bufs,
+            in_flight: 0,
+        })
+    }
+
+    /// Submit a read or write of `len` bytes at `at` in buffer `buf` from or to `offset` in `file`
+    fn submit(&mut self, write: bool, file: &File, buf: usize, at: usize, len: usize, offset: u64) {
+        let fd = types::Fd(file.as_raw_fd());
+        let ptr = self.bufs[buf][at..].as_mut_ptr();
+        let entry: squeue::Entry = match write {
+            true => opcode::WriteFixed::new(fd, ptr, len as u32, buf as u16)
+                .offset(offset)
+                .build(),
+            false => opcode::ReadFixed::new(fd, ptr, len as u32, buf as u16)
+                .offset(offset)
+                .build(),
+        };
+        // SAFETY: The range lies within a registered buffer that is not touched until the
+        // request completed; at most one request per buffer is in flight, so the queue
+        // holding [DEPTH] entries cannot be full
+        unsafe {
+            self.ring
+                .submission()
+                .push(&entry.user_data(buf as u64))
+                .expect("the submission queue holds one entry per buffer");
+        }
+        self.in_flight += 1;
+    }
+
+    /// Submit queued requests and wait for at least one to complete; returns buffer and result
+    fn complete(&mut self) -> IoResult<Vec<(usize, IoResult<usize>)>> {
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let done = self
+            .ring
+            .completion()
+            .map(|cqe| {
+                let res = match cqe.result() {
+                    res if res < 0 => Err(IoError::from_raw_os_error(-res)),
+                    res => Ok(res as usize),
+                };
+                (cqe.user_data() as usize, res)
+            })
+            .collect::<Vec<_>>();
+        self.in_flight -= done.len();
+        Ok(done)
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        // The kernel may still access the buffers of requests in flight
+        while self.in_flight > 0 {
+            if self.complete().is_err() {
+                // Leaking the buffers is better than handing them back while in use
+                std::mem::forget(std::mem::take(&mut self.bufs));
+                break;
+            }
+        }
+    }
+}
+
+/// A read ahead into one of the buffers
+struct Slot {
+    buf: usize,
+    offset: u64,
+    want: usize,
+    got: usize,
+    done: bool,
+    error: Option<IoError>,
+}
+
+/// Reads `start..end` of a file sequentially, keeping [DEPTH] reads in flight
+pub struct UringReader {
+    ring: Ring,
+    file: File,
+    free: Vec<usize>,
+    /// Reads in file order
+    slots: VecDeque<Slot>,
+    /// Bytes of the first slot handed out already
+    consumed: usize,
+    /// Offset of the next read to submit
+    next: u64,
+    end: u64,
+}
+
+impl UringReader {
+    pub fn new(file: File, start: u64, end: u64) -> IoResult<Self> {
+        Ok(Self {
+            ring: Ring::new()?,
+            file,
+            free: (0..DEPTH).rev().collect(),
+            slots: VecDeque::new(),
+            consumed: 0,
+            next: start,
+            end,
+        })
+    }
+
+    fn read_ahead(&mut self) {
+        while self.next < self.end {
+            let Some(buf) = self.free.pop() else {
+                break;
+            };
+            let want = (self.end - self.next).min(BUF_SIZE as u64) as usize;
+            self.ring.submit(false, &self.file, buf, 0, want, self.next);
+            self.slots.push_back(Slot {
+                buf,
+                offset: self.next,
+                want,
+                got: 0,
+                done: false,
+                error: None,
+            });
+            self.next += want as u64;
+        }
+    }
+
+    fn reap(&mut self) -> IoResult<()> {
+        for (buf, res) in self.ring.complete()? {
+            let slot = self.slots.iter_mut().find(|s| s.buf == buf).unwrap();
+            match res {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => (slot.error, slot.done) = (Some(e), true),
+                Ok(0) => {
+                    // The file ended early; nothing after this read is needed
+                    slot.done = true;
+                    self.end = self.end.min(slot.offset + slot.got as u64);
+                    self.next = self.next.min(self.end);
+                    continue;
+                }
+                Ok(n) => {
+                    slot.got += n;
+                    slot.done = slot.got == slot.want;
+                }
+            }
+            if !slot.done {
+                let (at, len, offset) = (slot.got, slot.want - slot.got, slot.offset);
+                self.ring
+                    .submit(false, &self.file, buf, at, len, offset + at as u64);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for UringReader {
+    fn read(&mut self, out: &mut [u8]) -> IoResult<usize> {
+        loop {
+            self.read_ahead();
+            let Some(slot) = self.slots.front_mut() else {
+                return Ok(0);
+            };
+            if !slot.done {
+                self.reap()?;
+                continue;
+            }
+            if let Some(e) = slot.error.take() {
+                return Err(e);
+            }
+            if self.consumed < slot.got {
+                let data = &self.ring.bufs[slot.buf][self.consumed..slot.got];
+                let n = out.len().min(data.len());
+                out[..n].copy_from_slice(&data[..n]);
+                self.consumed += n;
+                return Ok(n);
+            }
+            let slot = self.slots.pop_front().unwrap();
+            self.free.push(slot.buf);
+            self.consumed = 0;
+            if slot.got < slot.want {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+/// Writes to a file from `offset` on, keeping [DEPTH] writes in flight
+///
+/// Bytes accepted by [Write::write] are written in the background;
+/// [UringWriter::finish] waits for all of them and tells how many made it.
+pub struct UringWriter {
+    ring: Ring,
+    file: File,
+    free: Vec<usize>,
+    /// The buffer being filled and the number of bytes in it
+    filling: Option<(usize, usize)>,
+    /// Offset the next buffer is written to
+    pos: u64,
+    start: u64,
+    /// Writes in flight by buffer: offset, length and bytes written so far
+    writes: BTreeMap<usize, (u64, usize, usize)>,
+    /// Completed writes by offset: bytes asked for and bytes written
+    completed: BTreeMap<u64, (usize, usize)>,
+    /// Every byte before this offset was written
+    stored: u64,
+    /// A write failed; no more are submitted
+    failed: bool,
+    error: Option<IoError>,
+}
+
+impl UringWriter {
+    pub fn new(file: File, offset: u64) -> IoResult<Self> {
+        Ok(Self {
+            ring: Ring::new()?,
+            file,
+            free: (0..DEPTH).rev().collect(),
+            filling: None,
+            pos: offset,
+            start: offset,
+            writes: BTreeMap::new(),
+            completed: BTreeMap::new(),
+            stored: offset,
+            failed: false,
+            error: None,
+        })
+    }
+
+    fn submit(&mut self, buf: usize, len: usize) {
+        self.ring.submit(true, &self.file, buf, 0, len, self.pos);
+        self.writes.insert(buf, (self.pos, len, 0));
+        self.pos += len as u64;
+    }
+
+    fn reap(&mut self) -> IoResult<()> {
+        for (buf, res) in self.ring.complete()? {
+            let (offset, len, written) = self.writes.get_mut(&buf).unwrap();
+            match res {
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    self.failed = true;
+                    self.error.get_or_insert(e);
+                }
+                Ok(0) => {
+                    self.failed = true;
+                    self.error.get_or_insert(ErrorKind::WriteZero.into());
+                }
+                Ok(n) => *written += n,
+            }
+            let (offset, len, written) = (*offset, *len, *written);
+            if written < len && !self.failed {
+                let at = written;
+                self.ring
+                    .submit(true, &self.file, buf, at, len - at, offset + at as u64);
+                continue;
+            }
+            self.writes.remove(&buf);
+            self.free.push(buf);
+            self.completed.insert(offset, (len, written));
+        }
+
+        // Only data without gaps before it counts as stored
+        while let Some((len, written)) = self.completed.get(&self.stored).copied() {
+            self.completed.remove(&self.stored);
+            self.stored += written as u64;
+            if written < len {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn failure(&mut self) -> IoError {
+        self.error
+            .take()
+            .unwrap_or_else(|| IoError::other("An earlier write failed"))
+    }
+
+    /// Wait for all writes; returns the number of bytes written
+    pub fn finish(mut self) -> (u64, IoResult<()>) {
+        let mut res = Ok(());
+        if let Some((buf, len)) = self.filling.take() {
+            if !self.failed {
+                self.submit(buf, len);
+            }
+        }
+        while self.ring.in_flight > 0 {
+            if let Err(e) = self.reap() {
+                res = Err(e);
+                break;
+            }
+        }
+        if let Some(e) = self.error.take() {
+            res = Err(e);
+        }
+        (self.stored - self.start, res)
+    }
+}
+
+impl Write for UringWriter {
+    fn write(&mut self, data: &[u8]) -> IoResult<usize> {
+        if self.failed {
+            return Err(self.failure());
+        }
+        let (buf, filled) = match self.filling {
+            Some(filling) => filling,
+            None => {
+                while self.free.is_empty() {
+                    self.reap()?;
+                    if self.failed {
+                        return Err(self.failure());
+                    }
+                }
+                (self.free.pop().unwrap(), 0)
+            }
+        };
+        let n = data.len().min(BUF_SIZE - filled);
+        self.ring.bufs[buf][filled..filled + n].copy_from_slice(&data[..n]);
+        match filled + n {
+            BUF_SIZE => {
+                self.filling = None;
+                self.submit(buf, BUF_SIZE);
+            }
+            filled => self.filling = Some((buf, filled)),
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        Ok(())
+    }
+}
@@ -1,8 +1,7 @@
-use std::error::Error;
-use std::fmt::{Debug, Display};
+use std::fmt::Debug;
 use std::io::{Read, Result as IoResult, Seek, Write};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use std::{fs::read_to_string, path::Path};
 
 pub fn try_read_to_string<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
@@ -17,6 +16,35 @@ pub fn try_read_to_string<P: AsRef<Path>>(path: P) -> Result<Option<String>> {
     }
 }
 
+pub fn try_read<P: AsRef<Path>>(path: P) -> Result<Option<Vec<u8>>> {
+    loop {
+        use std::io::ErrorKind as E;
+        return match std::fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == E::NotFound => Ok(None),
+            Err(err) if err.kind() == E::Interrupted => continue,
+            Err(err) => Err(err)?,
+        };
+    }
+}
+
+/// Replace the file at `path` without ever leaving a partially written file behind
+pub fn write_atomic<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    let path = path.as_ref();
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".tmp-{}", std::process::id()));
+
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(contents.as_ref())?;
+    file.sync_data()?;
+    drop(file);
+
+    std::fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = std::fs::remove_file(&tmp);
+    })?;
+    Ok(())
+}
+
 pub fn read_nointr<R: Read>(mut src: R, buf: &mut [u8]) -> IoResult<usize> {
     loop {
         use std::io::ErrorKind as E;
@@ -60,6 +88,27 @@ where
     Ok(())
 }
 
+/// Parse a byte count with an optional binary suffix such as `4K`, `1MiB` or `2G`
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, suffix) = s.split_at(split);
+    let num: u64 = num
+        .parse()
+        .with_context(|| format!("Invalid size `{s}`: expected a number"))?;
+    let shift = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 0,
+        "K" | "KB" | "KIB" => 10,
+        "M" | "MB" | "MIB" => 20,
+        "G" | "GB" | "GIB" => 30,
+        "T" | "TB" | "TIB" => 40,
+        "P" | "PB" | "PIB" => 50,
+        _ => bail!("Invalid size `{s}`: unknown suffix `{suffix}`"),
+    };
+    num.checked_mul(1 << shift)
+        .with_context(|| format!("Size `{s}` is too large"))
+}
+
 pub fn pretty_path<P: AsRef<Path> + Debug>(path: P) -> String {
     format!("{:?}", path)
         .trim_start_matches('"')
@@ -67,6 +116,22 @@ pub fn pretty_path<P: AsRef<Path> + Debug>(path: P) -> String {
         .to_owned()
 }
 
+/// Canonical form of a path that need not exist yet, as long as its directory does
+pub fn absolute_path<P: AsRef<Path> + Debug>(path: P) -> Result<String> {
+    let path = path.as_ref();
+    if let Ok(canonical) = std::fs::canonicalize(path) {
+        return Ok(pretty_path(canonical));
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .with_context(|| format!("Invalid path {path:?}"))?;
+    Ok(pretty_path(std::fs::canonicalize(dir)?.join(name)))
+}
+
 pub struct NullBuffer;
 
 impl Write for NullBuffer {
@@ -79,96 +144,82 @@ impl Write for NullBuffer {
     }
 }
 
+/// Anything that can be read from at arbitrary offsets
+pub trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
 pub fn uuidgen() -> String {
     uuid::Uuid::new_v4().to_string()
 }
 
-pub struct TruncateReadStream<R: Read + Seek> {
-    inner: R,
-    limit: usize,
-    pos: usize,
-    extended: bool,
+/// The current time in RFC 3339 format, in UTC and with second precision
+pub fn utc_now() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    utc_timestamp(secs)
 }
 
-#[derive(Debug)]
-enum TruncateReadStreamError {
-    NegativeSeek,
-    SeekPastEnd,
+/// Format seconds since the epoch in RFC 3339 format
+pub fn utc_timestamp(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // Civil date from days since the epoch, after Howard Hinnant's `civil_from_days`
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
 }
 
-impl Display for TruncateReadStreamError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
-    }
+/// An empty directory for a test to put its files in
+#[cfg(test)]
+pub fn scratch_dir(name: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("splitfile-{}-{name}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
 }
 
-impl Error for TruncateReadStreamError {}
-
-impl<R: Read + Seek> TruncateReadStream<R> {
-    pub fn new(mut inner: R, limit: usize) -> Result<Self> {
-        let pos = inner.stream_position()? as usize;
-        let limit = limit + pos;
-        Ok(Self {
-            inner,
-            limit,
-            pos,
-            extended: false,
-        })
-    }
-}
-
-impl<R: Read + Seek> Read for TruncateReadStream<R> {
-    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
-        use std::cmp::min;
-
-        if self.pos == self.limit {
-            return Ok(0);
-        }
-
-        if self.extended {
-            self.inner.seek(std::io::SeekFrom::Start(self.pos as u64))?;
-            self.extended = false;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size(" 512 ").unwrap(), 512);
+        assert_eq!(parse_size("12B").unwrap(), 12);
+        assert_eq!(parse_size("4K").unwrap(), 4 << 10);
+        assert_eq!(parse_size("4kib").unwrap(), 4 << 10);
+        assert_eq!(parse_size("1MiB").unwrap(), 1 << 20);
+        assert_eq!(parse_size("3 GB").unwrap(), 3 << 30);
+        assert_eq!(parse_size("2T").unwrap(), 2 << 40);
+        assert_eq!(parse_size("1P").unwrap(), 1 << 50);
+
+        for invalid in ["", "K", "-1", "1.5M", "4X", "4 K B"] {
+            assert!(parse_size(invalid).is_err(), "{invalid}");
         }
-
-        let local_limit = min(buf.len(), self.limit - self.pos);
-        let buf = &mut buf[..local_limit];
-        let r = match self.inner.read(buf)? {
-            0 => {
-                buf.fill(0);
-                self.extended = true;
-                buf.len()
-            }
-            v => v,
-        };
-
-        self.pos += r;
-
-        Ok(r)
+        assert!(parse_size("16384P").is_err());
+        assert!(parse_size("99999999999999999999").is_err());
     }
-}
-
-impl<R: Read + Seek> Seek for TruncateReadStream<R> {
-    fn seek(&mut self, pos: std::io::SeekFrom) -> IoResult<u64> {
-        use std::io::ErrorKind;
-        use std::io::SeekFrom as S;
-        let new = match pos {
-            S::Start(dst) => dst as isize,
-            S::Current(dst) => self.pos as isize + dst as isize,
-            S::End(dst) => self.limit as isize + dst as isize,
-        };
-
-        if new < 0 {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidInput,
-                TruncateReadStreamError::NegativeSeek,
-            ));
-        } else if new as usize > self.limit {
-            return Err(std::io::Error::new(
-                ErrorKind::InvalidInput,
-                TruncateReadStreamError::SeekPastEnd,
-            ));
-        }
 
-        self.inner.seek(S::Start(new as u64))
+    #[test]
+    fn timestamps() {
+        assert_eq!(utc_timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(utc_timestamp(-1), "1969-12-31T23:59:59Z");
+        assert_eq!(utc_timestamp(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(utc_timestamp(1_700_000_000), "2023-11-14T22:13:20Z");
+        assert_eq!(utc_timestamp(4_107_542_399), "2100-02-28T23:59:59Z");
     }
 }